@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sendfile_cli::testing;
+
+// a stream of frames, as read from a socket
+fuzz_target!(|data: &[u8]| {
+    let _ = testing::read_frames(data);
+});
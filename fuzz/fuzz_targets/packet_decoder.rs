@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sendfile_cli::testing::{self, Encoding};
+
+// the action of a packet, its encoding and its data
+fuzz_target!(|data: &[u8]| {
+    if let [action, encoding, body @ ..] = data {
+        let encoding = match encoding % 2 {
+            0 => Encoding::Json,
+            _ => Encoding::Binary,
+        };
+        let _ = testing::decode_packet(*action, body, encoding);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sendfile_cli::testing::{Harness, Replay};
+
+// a whole session sent to a receiver writing to a temporary directory
+fuzz_target!(|data: &[u8]| {
+    let harness = Harness::new().expect("temporary directory");
+    let _ = harness.receive(Replay::new(data));
+});
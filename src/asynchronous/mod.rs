@@ -0,0 +1,15 @@
+//! tokio versions of the sender and the receiver, enabled by the `async` feature
+//!
+//! They speak the same protocol as the blocking ones and share their packet codec, so async and
+//! blocking peers can be mixed. Dropping a pending `send` or `accept` cancels the transfer; the
+//! receiver keeps the `.part` file so that the sender can resume it.
+
+#[cfg(feature = "quic")]
+mod quic;
+mod receiver;
+mod sender;
+mod streamer;
+mod tls;
+
+pub use receiver::AsyncReceiver;
+pub use sender::AsyncSender;
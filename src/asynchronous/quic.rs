@@ -0,0 +1,200 @@
+//! QUIC connections: the request and its answer go on a bidirectional control stream, then every
+//! file gets its own unidirectional stream so that a lost packet only stalls one file
+
+use super::streamer::FileStream;
+use super::tls;
+use crate::error::Cancelled;
+use crate::tls::KeyPair;
+use quinn::{
+    Connection, ConnectionError, Endpoint, Incoming, NewConnection, ReadError, RecvStream,
+    SendStream, ServerConfig, TransportConfig, VarInt, WriteError,
+};
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// application protocol negotiated by TLS
+const ALPN: &[u8] = b"sendfile";
+
+/// files a sender can have in flight at once
+const MAX_FILE_STREAMS: u32 = 16;
+
+/// code of the connection close once a session is over
+pub const CLOSE_DONE: u32 = 0;
+
+/// code of the connection close of a cancelled session, the reason goes with it
+const CLOSE_CANCELLED: u32 = 1;
+
+/// code a receiver stops the stream of a file it cancels with, no reason goes with it
+const STOP_CANCELLED: u32 = 1;
+
+/// what the sender of a file the receiver stopped is told
+pub const STOPPED_REASON: &str = "cancelled by the receiver";
+
+pub fn bind(addr: SocketAddr, keypair: &KeyPair) -> io::Result<(Endpoint, Incoming)> {
+    let mut crypto = tls::server_config(keypair)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut transport = TransportConfig::default();
+    transport.max_concurrent_uni_streams(VarInt::from_u32(MAX_FILE_STREAMS));
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport = Arc::new(transport);
+    Endpoint::server(config, addr)
+}
+
+/// connect to a receiver, only trusting the certificate with the given fingerprint if any
+pub async fn connect(addr: SocketAddr, fingerprint: Option<&str>) -> io::Result<NewConnection> {
+    let mut crypto = tls::client_config(fingerprint);
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let local = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let mut endpoint = Endpoint::client(SocketAddr::new(local, 0))?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+    let connecting = endpoint
+        .connect(addr, "localhost")
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(connecting.await?)
+}
+
+/// the peer went away before opening the stream the session waits for
+pub fn closed() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "connection closed by peer")
+}
+
+/// a stream of the connection, reading or writing it fails with `Cancelled` once the peer closed
+/// the connection with `CLOSE_CANCELLED`
+pub struct QuicStream {
+    opt_send: Option<SendStream>,
+    opt_recv: Option<RecvStream>,
+}
+
+impl QuicStream {
+    /// both directions of a bidirectional stream, for the control packets
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        QuicStream {
+            opt_send: Some(send),
+            opt_recv: Some(recv),
+        }
+    }
+
+    /// a unidirectional stream opened to send a file
+    pub fn sending(send: SendStream) -> Self {
+        QuicStream {
+            opt_send: Some(send),
+            opt_recv: None,
+        }
+    }
+
+    /// a unidirectional stream the peer opened to send a file
+    pub fn receiving(recv: RecvStream) -> Self {
+        QuicStream {
+            opt_send: None,
+            opt_recv: Some(recv),
+        }
+    }
+
+    /// end the sending side, completes once the peer got everything
+    pub async fn finish(&mut self) -> io::Result<()> {
+        match self.opt_send.as_mut() {
+            Some(send) => send.finish().await.map_err(|e| cancelled(e.into())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.opt_recv.as_mut() {
+            Some(recv) => Pin::new(recv).poll_read(cx, buf).map_err(cancelled),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.opt_send.as_mut() {
+            Some(send) => Pin::new(send).poll_write(cx, buf).map_err(cancelled),
+            None => Poll::Ready(Err(Error::from(ErrorKind::BrokenPipe))),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.opt_send.as_mut() {
+            Some(send) => Pin::new(send).poll_flush(cx).map_err(cancelled),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.opt_send.as_mut() {
+            Some(send) => Pin::new(send).poll_shutdown(cx).map_err(cancelled),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// the stream of a file is stopped when the receiver cancels it, the sender finds out once
+/// its next write fails
+impl FileStream for QuicStream {
+    fn stop(&mut self) -> bool {
+        if let Some(recv) = self.opt_recv.as_mut() {
+            // the stream may be over already
+            let _ = recv.stop(VarInt::from_u32(STOP_CANCELLED));
+        }
+        true
+    }
+
+    fn is_duplex(&self) -> bool {
+        false
+    }
+}
+
+/// whether the receiver stopped the stream of the file `err` comes from
+pub fn is_stopped(err: &io::Error) -> bool {
+    let opt_write_err = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<WriteError>());
+    matches!(
+        opt_write_err,
+        Some(WriteError::Stopped(code)) if *code == VarInt::from_u32(STOP_CANCELLED)
+    )
+}
+
+/// close the connection to cancel the session, the peer gets `reason`
+pub fn cancel(connection: &Connection, reason: &str) {
+    connection.close(VarInt::from_u32(CLOSE_CANCELLED), reason.as_bytes())
+}
+
+/// turn the error of a connection the peer closed with `CLOSE_CANCELLED` into `Cancelled`
+pub fn cancelled(err: io::Error) -> io::Error {
+    let opt_conn_err = err.get_ref().and_then(|inner| {
+        if let Some(ReadError::ConnectionLost(e)) = inner.downcast_ref::<ReadError>() {
+            Some(e)
+        } else if let Some(WriteError::ConnectionLost(e)) = inner.downcast_ref::<WriteError>() {
+            Some(e)
+        } else {
+            inner.downcast_ref::<ConnectionError>()
+        }
+    });
+    match opt_conn_err {
+        Some(ConnectionError::ApplicationClosed(close))
+            if close.error_code == VarInt::from_u32(CLOSE_CANCELLED) =>
+        {
+            Cancelled::error(&String::from_utf8_lossy(&close.reason), true)
+        }
+        _ => err,
+    }
+}
@@ -0,0 +1,670 @@
+#[cfg(feature = "quic")]
+use super::quic::{self, QuicStream};
+use super::streamer::{AsyncConnection, AsyncStreamer, FileStream};
+use super::tls;
+use crate::cancel::CancelHandle;
+use crate::discovery::{self, Announcer, Beacon};
+use crate::error::{Cancelled, Error, Integrity, Result};
+use crate::observer::{Event, Observer};
+use crate::packet::cancel::{CancelData, CancelScope};
+use crate::packet::file_info::FileInfo;
+use crate::packet::start_file::StartFileData;
+use crate::packet::Packet;
+use crate::receiver::{ReceiveReport, ReceiverBuilder};
+use crate::server::{self, AcceptPolicy};
+use crate::source::{self, ContentHash};
+use crate::sparse;
+use crate::transport::{Endpoint, PeerInfo};
+#[cfg(feature = "quic")]
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+#[cfg(feature = "quic")]
+use quinn::{Incoming, NewConnection, VarInt};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpListener;
+#[cfg(feature = "quic")]
+use tokio::sync::Mutex;
+use tokio_rustls::TlsAcceptor;
+
+impl ReceiverBuilder {
+    /// same as `build` for the async receiver, which does not support share and sync modes
+    pub async fn build_async(self) -> Result<AsyncReceiver> {
+        if self.opt_share.is_some() || self.opt_sync.is_some() {
+            return Err(Error::Config(String::from(
+                "share and sync are not supported by the async receiver",
+            )));
+        }
+        self.check_socket_options()?;
+        let (addr, tls) = match (&self.endpoint, &self.opt_listener) {
+            (Endpoint::Tls(addr), None) => (*addr, true),
+            (Endpoint::Tcp(addr), None) => (*addr, false),
+            #[cfg(feature = "quic")]
+            (Endpoint::Quic(addr), None) => (*addr, true),
+            _ => {
+                return Err(Error::Config(String::from(
+                    "the async receiver only listens on tls://, tcp:// and quic:// endpoints",
+                )))
+            }
+        };
+        let keypair = self.opt_keypair.unwrap_or_default();
+
+        let (listener, endpoint) = match self.endpoint {
+            #[cfg(feature = "quic")]
+            Endpoint::Quic(_) => {
+                let (endpoint, incoming) = quic::bind(addr, &keypair)?;
+                let local_addr = endpoint.local_addr()?;
+                (
+                    AsyncListener::Quic(Mutex::new(incoming)),
+                    Endpoint::Quic(local_addr),
+                )
+            }
+            _ => {
+                let listener = TcpListener::bind(addr).await?;
+                let local_addr = listener.local_addr()?;
+                let (endpoint, opt_acceptor) = if tls {
+                    (Endpoint::Tls(local_addr), Some(tls::acceptor(&keypair)?))
+                } else {
+                    warn!("listening on {} without encryption", local_addr);
+                    (Endpoint::Tcp(local_addr), None)
+                };
+                let listener = AsyncListener::Tcp {
+                    listener,
+                    opt_acceptor,
+                };
+                (listener, endpoint)
+            }
+        };
+        info!("starting server at: {}", endpoint);
+        let fingerprint = keypair.fingerprint();
+        info!("certificate fingerprint: {}", fingerprint);
+        let mut opt_announcer = None;
+        if let Some(name) = self.opt_announce {
+            let port = match endpoint {
+                Endpoint::Tls(addr) => addr.port(),
+                _ => {
+                    return Err(Error::Config(format!(
+                        "cannot announce {}, only TLS endpoints can",
+                        endpoint
+                    )))
+                }
+            };
+            let beacon = Beacon {
+                name,
+                port,
+                fingerprint: fingerprint.clone(),
+            };
+            opt_announcer = Some(discovery::announce(beacon)?);
+        }
+        Ok(AsyncReceiver {
+            listener,
+            endpoint,
+            fingerprint,
+            out_dir: self.out_dir,
+            to_stdout: self.to_stdout,
+            opt_policy: self.opt_policy,
+            opt_observer: self.opt_observer,
+            cancel: self.cancel,
+            opt_announcer,
+        })
+    }
+}
+
+/// sockets of the async receiver
+enum AsyncListener {
+    Tcp {
+        listener: TcpListener,
+        /// TLS, plaintext otherwise
+        opt_acceptor: Option<TlsAcceptor>,
+    },
+    #[cfg(feature = "quic")]
+    Quic(Mutex<Incoming>),
+}
+
+/// async counterpart of `Receiver`, built with `ReceiverBuilder::build_async`;
+/// `accept` can run from several tasks at once to serve senders concurrently
+pub struct AsyncReceiver {
+    listener: AsyncListener,
+    endpoint: Endpoint,
+    fingerprint: String,
+    out_dir: PathBuf,
+    to_stdout: bool,
+    opt_policy: Option<AcceptPolicy>,
+    opt_observer: Option<Arc<dyn Observer>>,
+    cancel: CancelHandle,
+    /// broadcasts the beacon of the receiver as long as it lives
+    opt_announcer: Option<Announcer>,
+}
+
+impl AsyncReceiver {
+    /// where senders connect to
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// SHA-256 fingerprint of the certificate, for senders to pin
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// stop broadcasting the beacon of `ReceiverBuilder::announce`, which also stops when the
+    /// receiver is dropped
+    pub fn stop_announcing(&mut self) {
+        self.opt_announcer.take();
+    }
+
+    /// wait for the next sender and serve its session
+    pub async fn accept(&self) -> Result<ReceiveReport> {
+        let (session, res) = match &self.listener {
+            AsyncListener::Tcp {
+                listener,
+                opt_acceptor,
+            } => {
+                let (str, peer) = listener.accept().await?;
+                self.check_cancelled()?;
+                info!("accepted new client at: {}", peer);
+                let str: Box<dyn AsyncConnection> = match opt_acceptor.as_ref() {
+                    Some(acceptor) => Box::new(acceptor.accept(str).await?),
+                    None => Box::new(str),
+                };
+                let mut session = Session::new(self, peer.to_string());
+                let res = session.run(AsyncStreamer::new(str)).await;
+                (session, res)
+            }
+            #[cfg(feature = "quic")]
+            AsyncListener::Quic(incoming) => {
+                let connecting = incoming
+                    .lock()
+                    .await
+                    .next()
+                    .await
+                    .ok_or_else(quic::closed)?;
+                let peer = connecting.remote_address();
+                let conn = connecting.await.map_err(io::Error::from)?;
+                self.check_cancelled()?;
+                info!("accepted new client at: {}", peer);
+                let mut session = Session::new(self, peer.to_string());
+                let res = session.run_quic(conn).await;
+                (session, res)
+            }
+        };
+
+        if let Err(e) = res {
+            warn!("session with {} failed: {}", session.peer.name, e);
+            return Err(e.into());
+        }
+        Ok(ReceiveReport {
+            peer: session.peer.name,
+            credentials: None,
+            accepted: session.accepted,
+            files: session.received,
+        })
+    }
+
+    /// no more sessions once cancelled
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel.session_reason() {
+            Some(reason) => Err(Error::Cancelled {
+                reason,
+                by_peer: false,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// one session with a sender, the steps follow `ServerStateMachine`
+struct Session<'a> {
+    receiver: &'a AsyncReceiver,
+    peer: PeerInfo,
+    accepted: bool,
+    /// the accepted request
+    files: Arc<Vec<FileInfo>>,
+    /// how much of each file ResumeAt offered to keep
+    offers: Arc<Vec<Option<u64>>>,
+    received: Vec<FileInfo>,
+}
+
+impl<'a> Session<'a> {
+    fn new(receiver: &'a AsyncReceiver, peer: String) -> Self {
+        Session {
+            receiver,
+            peer: PeerInfo::new(peer),
+            accepted: false,
+            files: Arc::new(Vec::new()),
+            offers: Arc::new(Vec::new()),
+            received: Vec::new(),
+        }
+    }
+
+    async fn run<S>(&mut self, mut str: AsyncStreamer<S>) -> io::Result<()>
+    where
+        S: FileStream,
+    {
+        if !self.answer(&mut str).await? {
+            return Ok(());
+        }
+
+        let r = self.receiver;
+        loop {
+            if let Some(reason) = r.cancel.session_reason() {
+                return Err(self
+                    .stopped(&mut str, Cancelled::error(&reason, false))
+                    .await);
+            }
+            let res = match str.read_packet().await {
+                Ok(Packet::StartFile(data)) => {
+                    match server::check_start_file(&self.files, &self.offers, &data) {
+                        Ok(_) => {
+                            let observer = r.opt_observer.as_ref();
+                            let cancel = &r.cancel;
+                            receive_file(&mut str, data, &r.out_dir, r.to_stdout, observer, cancel)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Ok(Packet::Finish) => {
+                    // from version 3 the sender waits for the answer
+                    if str.encoding().version() >= 3 {
+                        str.write_packet(Packet::Finish).await?;
+                    }
+                    self.notify(Event::Finished);
+                    return Ok(());
+                }
+                Ok(Packet::Cancel(data)) if data.scope == CancelScope::File => Ok(None),
+                Ok(Packet::Cancel(data)) => Err(Cancelled::error(&data.reason, true)),
+                Ok(_) => Err(unexpected()),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(opt_file) => self.received.extend(opt_file),
+                Err(e) => return Err(self.stopped(&mut str, e).await),
+            }
+        }
+    }
+
+    /// same steps over QUIC, the files come at once on their own streams
+    #[cfg(feature = "quic")]
+    async fn run_quic(&mut self, conn: NewConnection) -> io::Result<()> {
+        let NewConnection {
+            connection,
+            mut uni_streams,
+            mut bi_streams,
+            ..
+        } = conn;
+        let (send, recv) = bi_streams
+            .next()
+            .await
+            .ok_or_else(quic::closed)?
+            .map_err(|e| quic::cancelled(e.into()))?;
+        let mut control = AsyncStreamer::new(QuicStream::new(send, recv));
+        if !self.answer(&mut control).await? {
+            control.get_mut().finish().await?;
+            return Ok(());
+        }
+
+        let r = self.receiver;
+        let encoding = control.encoding();
+        let mut tasks = Vec::new();
+        let mut res = Ok(());
+        {
+            // the sender only sends Finish once its file streams are done, so they are all
+            // opened before Finish is read
+            let finish = control.read_packet();
+            tokio::pin!(finish);
+            loop {
+                tokio::select! {
+                    biased;
+                    Some(stream) = uni_streams.next() => {
+                        let stream = match stream {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                res = Err(quic::cancelled(e.into()));
+                                break;
+                            }
+                        };
+                        let stream = QuicStream::receiving(stream);
+                        let mut str = AsyncStreamer::with_encoding(stream, encoding);
+                        let out_dir = r.out_dir.clone();
+                        let to_stdout = r.to_stdout;
+                        let opt_observer = r.opt_observer.clone();
+                        let cancel = r.cancel.clone();
+                        let connection = connection.clone();
+                        let files = self.files.clone();
+                        let offers = self.offers.clone();
+                        tasks.push(tokio::spawn(async move {
+                            let res = match str.read_packet().await? {
+                                Packet::StartFile(data) => {
+                                    server::check_start_file(&files, &offers, &data)?;
+                                    receive_file(
+                                        &mut str,
+                                        data,
+                                        &out_dir,
+                                        to_stdout,
+                                        opt_observer.as_ref(),
+                                        &cancel,
+                                    )
+                                    .await
+                                }
+                                _ => Err(unexpected()),
+                            };
+                            // before the dropped stream tells the sender to stop it
+                            let opt_cancelled = res.as_ref().err().and_then(Cancelled::get);
+                            if let Some(c) = opt_cancelled.filter(|c| !c.by_peer) {
+                                quic::cancel(&connection, &c.reason);
+                            }
+                            res
+                        }));
+                    }
+                    packet = &mut finish => {
+                        match packet {
+                            Ok(Packet::Finish) => {}
+                            Ok(_) => res = Err(unexpected()),
+                            Err(e) => res = Err(e),
+                        }
+                        break;
+                    }
+                    reason = r.cancel.cancelled() => {
+                        quic::cancel(&connection, &reason);
+                        res = Err(Cancelled::error(&reason, false));
+                        break;
+                    }
+                }
+            }
+        }
+
+        for task in tasks {
+            match task.await.map_err(io::Error::other)? {
+                Ok(opt_file) => self.received.extend(opt_file),
+                Err(e) => res = res.and(Err(e)),
+            }
+        }
+        if let Err(e) = res {
+            self.notify_cancelled(&e);
+            return Err(e);
+        }
+        self.notify(Event::Finished);
+        control.write_packet(Packet::Finish).await?;
+        control.get_mut().finish().await?;
+        connection.close(VarInt::from_u32(quic::CLOSE_DONE), b"done");
+        Ok(())
+    }
+
+    /// read the request of the sender and answer it, false when there is nothing to receive
+    async fn answer<S>(&mut self, str: &mut AsyncStreamer<S>) -> io::Result<bool>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let r = self.receiver;
+        let (files, resume) = match str.read_request().await? {
+            Packet::Send(files) => (files, false),
+            Packet::Resume(files) => (files, true),
+            Packet::List(_) | Packet::Get(_) | Packet::Sync(_) => {
+                info!("share and sync are not supported by the async receiver");
+                str.write_packet(Packet::Reject).await?;
+                return Ok(false);
+            }
+            Packet::Finish => return Ok(false),
+            _ => return Err(unexpected()),
+        };
+        debug!("internal answer for request: {:?}", files);
+
+        // send accept of cancel
+        self.notify(Event::Requested {
+            files: files.clone(),
+        });
+        if !server::is_accepted(&self.peer, &files, r.to_stdout, r.opt_policy.as_ref()) {
+            self.notify(Event::Rejected);
+            str.write_packet(Packet::Reject).await?;
+            return Ok(false);
+        }
+        self.accepted = true;
+        self.files = Arc::new(files.clone());
+        self.notify(Event::Accepted);
+        let answer = if resume {
+            let kept = server::kept_sizes(&r.out_dir, &files, r.to_stdout);
+            self.offers = Arc::new(kept.clone());
+            Packet::ResumeAt(kept)
+        } else if str.encoding().version() >= 2 && !r.to_stdout {
+            // comparing the hashes of the files reads them
+            let (out_dir, requested) = (r.out_dir.clone(), self.files.clone());
+            let have =
+                tokio::task::spawn_blocking(move || server::identical_files(&out_dir, &requested))
+                    .await
+                    .map_err(io::Error::other)?;
+            for index in (0..have.len()).filter(|i| have[*i]) {
+                self.notify(Event::Skipped {
+                    index,
+                    file: files[index].clone(),
+                });
+            }
+            server::have_answer(have)
+        } else {
+            Packet::Accept
+        };
+        str.write_packet(answer).await?;
+        Ok(true)
+    }
+
+    /// tell the sender when the session was cancelled here or the data received does not
+    /// match what it announced, then hang up
+    async fn stopped<S>(&self, str: &mut AsyncStreamer<S>, e: io::Error) -> io::Error
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let opt_data = match Cancelled::get(&e) {
+            Some(c) if !c.by_peer => Some(CancelData::new(CancelScope::Session, &c.reason)),
+            None if Integrity::is(&e) => {
+                let scope = server::integrity_scope(str.encoding());
+                Some(CancelData::new(scope, &e.to_string()))
+            }
+            _ => None,
+        };
+        if let Some(data) = opt_data {
+            if let Err(e) = str.write_packet(Packet::Cancel(data)).await {
+                warn!("cannot tell the sender why the session stopped: {}", e);
+            }
+        }
+        self.notify_cancelled(&e);
+        e
+    }
+
+    fn notify_cancelled(&self, e: &io::Error) {
+        if let Some(c) = Cancelled::get(e) {
+            self.notify(Event::Cancelled {
+                index: None,
+                reason: c.reason.clone(),
+                by_peer: c.by_peer,
+            })
+        }
+    }
+
+    fn notify(&self, event: Event) {
+        notify(self.receiver.opt_observer.as_ref(), event)
+    }
+}
+
+/// write the content of the file started by `data` until EndFile, nothing when the file is
+/// cancelled; the partial output of a cancelled file is removed and the sender told about it
+async fn receive_file<R>(
+    str: &mut AsyncStreamer<R>,
+    data: StartFileData,
+    out_dir: &Path,
+    to_stdout: bool,
+    opt_observer: Option<&Arc<dyn Observer>>,
+    cancel: &CancelHandle,
+) -> io::Result<Option<FileInfo>>
+where
+    R: FileStream,
+{
+    debug!("start receiving file: {:?}", data);
+    let index = data.index;
+    let mut file = data.file_info;
+    notify(
+        opt_observer,
+        Event::FileStarted {
+            index,
+            file: file.clone(),
+        },
+    );
+
+    // the `.part` file behind the writer, where holes are left
+    let mut opt_part = None;
+    let (mut writer, opt_path): (BufWriter<Box<dyn AsyncWrite + Send + Unpin>>, _) = if to_stdout {
+        (BufWriter::new(Box::new(tokio::io::stdout())), None)
+    } else {
+        let path = source::safe_join(out_dir, &file.name)?;
+        let part = server::open_part(&path, &file, data.offset)?;
+        opt_part = Some(part.try_clone()?);
+        let part = tokio::fs::File::from_std(part);
+        (BufWriter::new(Box::new(part)), Some(path))
+    };
+    let mut hash = match opt_path.clone() {
+        // the part kept from an earlier session is read again to hash it
+        Some(path) => {
+            let offset = data.offset;
+            tokio::task::spawn_blocking(move || server::part_hash(&path, offset))
+                .await
+                .map_err(io::Error::other)??
+        }
+        None => ContentHash::new(),
+    };
+
+    let mut received_size = data.offset;
+    let mut discarding = false;
+    let res = async {
+        loop {
+            if let Some(reason) = cancel.session_reason() {
+                return Err(Cancelled::error(&reason, false));
+            }
+            if let Some(reason) = cancel.take_file_reason().filter(|_| !discarding) {
+                notify_file_cancelled(opt_observer, index, reason.clone(), false);
+                discarding = true;
+                // what the sender sent meanwhile is ignored until EndFile
+                match str.stop_file(index, &reason).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => warn!("cannot tell the sender about the cancelled file: {}", e),
+                }
+            }
+            match str.read_packet().await? {
+                Packet::FileData(_) | Packet::Hole(_) if discarding => {}
+                Packet::FileData(data) => {
+                    server::check_received(&file, received_size + data.len() as u64)?;
+                    writer.write_all(&data).await?;
+                    hash.update(&data);
+                    received_size += data.len() as u64;
+                    notify(
+                        opt_observer,
+                        Event::Progress {
+                            index,
+                            bytes: received_size,
+                        },
+                    );
+                }
+                Packet::Hole(hole) => {
+                    if hole.offset != received_size
+                        || hole.len > file.size.saturating_sub(received_size)
+                        || file.streamed
+                    {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "invalid hole"));
+                    }
+                    writer.flush().await?;
+                    match opt_part.as_ref() {
+                        Some(part) => {
+                            let (part, len) = (part.try_clone()?, hole.len);
+                            tokio::task::spawn_blocking(move || sparse::skip(&part, len))
+                                .await
+                                .map_err(io::Error::other)??
+                        }
+                        None => {
+                            for zeros in sparse::zeros(hole.len) {
+                                writer.write_all(zeros).await?;
+                            }
+                        }
+                    }
+                    hash.update_zeros(hole.len);
+                    received_size += hole.len;
+                    notify(
+                        opt_observer,
+                        Event::Progress {
+                            index,
+                            bytes: received_size,
+                        },
+                    );
+                }
+                Packet::EndFile => break,
+                Packet::Cancel(data) if data.scope == CancelScope::File => {
+                    notify_file_cancelled(opt_observer, index, data.reason, true);
+                    discarding = true;
+                    break;
+                }
+                Packet::Cancel(data) => return Err(Cancelled::error(&data.reason, true)),
+                _ => return Err(unexpected()),
+            }
+        }
+        writer.flush().await
+    }
+    .await;
+
+    drop(writer);
+    if let Err(e) = res {
+        if Cancelled::is(&e) || cancel.is_cancelled() {
+            opt_path.iter().for_each(|path| server::remove_part(path));
+        }
+        return Err(e);
+    }
+    if discarding {
+        opt_path.iter().for_each(|path| server::remove_part(path));
+        return Ok(None);
+    }
+    let hash = hash.finish();
+    if let Err(e) =
+        server::check_size(&file, received_size).and_then(|_| server::check_hash(&file, &hash))
+    {
+        opt_path.iter().for_each(|path| server::remove_part(path));
+        return Err(e);
+    }
+    if let Some(path) = opt_path {
+        server::commit_part(&path, file.mtime)?;
+    }
+    file.hash = Some(hash);
+    notify(
+        opt_observer,
+        Event::FileCompleted {
+            index,
+            file: file.clone(),
+        },
+    );
+    Ok(Some(file))
+}
+
+fn notify_file_cancelled(
+    opt_observer: Option<&Arc<dyn Observer>>,
+    index: usize,
+    reason: String,
+    by_peer: bool,
+) {
+    let index = Some(index);
+    notify(
+        opt_observer,
+        Event::Cancelled {
+            index,
+            reason,
+            by_peer,
+        },
+    )
+}
+
+fn notify(opt_observer: Option<&Arc<dyn Observer>>, event: Event) {
+    if let Some(observer) = opt_observer {
+        observer.on_event(&event)
+    }
+}
+
+fn unexpected() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "unexpected packet")
+}
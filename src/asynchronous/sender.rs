@@ -0,0 +1,574 @@
+#[cfg(feature = "quic")]
+use super::quic::{self, QuicStream};
+use super::streamer::{AsyncStreamer, FileStream};
+use super::tls;
+use crate::cancel::CancelHandle;
+use crate::client::{have_offsets, hung_up, resume_offsets, ClientOutcome};
+use crate::discovery;
+use crate::error::{Cancelled, Error, Result, Unversioned};
+use crate::observer::{Event, Observer};
+use crate::packet::cancel::{CancelData, CancelScope};
+use crate::packet::file_info::FileInfo;
+use crate::packet::start_file::StartFileData;
+use crate::packet::{Packet, FILE_DATA_LEN};
+use crate::sender::{is_retryable, SendReport, Sender, SenderBuilder, Target, DISCOVERY_TIMEOUT};
+use crate::source::{self, ContentHash, SourceFile};
+use crate::sparse::{Piece, SparseReader};
+use crate::transport::Endpoint;
+use log::{error, info, warn};
+#[cfg(feature = "quic")]
+use quinn::VarInt;
+use std::cmp::min;
+use std::io::{self, ErrorKind, SeekFrom};
+#[cfg(feature = "quic")]
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+
+impl SenderBuilder {
+    pub fn build_async(self) -> Result<AsyncSender> {
+        let inner = self.build()?;
+        if inner.opt_proxy.is_some() {
+            return Err(Error::Config(String::from(
+                "proxies are not supported by the async sender",
+            )));
+        }
+        Ok(AsyncSender { inner })
+    }
+}
+
+/// async counterpart of `Sender`, built with `SenderBuilder::build_async`
+pub struct AsyncSender {
+    inner: Sender,
+}
+
+impl AsyncSender {
+    pub async fn send(&self) -> Result<SendReport> {
+        let sender = &self.inner;
+        let (endpoint, opt_fingerprint) = match &sender.target {
+            Target::Endpoint(endpoint) => (endpoint.clone(), sender.opt_fingerprint.clone()),
+            Target::Peer(name) => {
+                let name = name.clone();
+                let peer = tokio::task::spawn_blocking(move || {
+                    discovery::resolve(&name, DISCOVERY_TIMEOUT)
+                })
+                .await
+                .map_err(io::Error::other)??;
+                info!("resolved '{}' to {}", peer.name, peer.addr);
+                (Endpoint::Tls(peer.addr), Some(peer.fingerprint))
+            }
+            Target::Transport(_) => {
+                return Err(Error::Config(String::from(
+                    "custom transports are not supported by the async sender",
+                )))
+            }
+        };
+        info!("sending files: {:?} to {}", sender.paths, endpoint);
+
+        // check all files are exists, and expand directories
+        let files = source::collect_args(&sender.paths, &sender.stdin_name, &sender.selection)?;
+        let infos = files
+            .iter()
+            .map(SourceFile::info)
+            .collect::<io::Result<Vec<FileInfo>>>()?;
+
+        let retry = &sender.retry;
+        let mut session = Session {
+            items: &files,
+            finished: vec![false; files.len()],
+            checksum: sender.checksum,
+            skipped: 0,
+            stdin_consumed: false,
+            legacy: false,
+            opt_observer: sender.opt_observer.clone(),
+            cancel: sender.cancel.clone(),
+        };
+        let mut backoff = retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            if let Some(reason) = sender.cancel.session_reason() {
+                return Err(Error::Cancelled {
+                    reason,
+                    by_peer: false,
+                });
+            }
+            let res = match &endpoint {
+                #[cfg(feature = "quic")]
+                Endpoint::Quic(addr) => {
+                    session
+                        .run_quic(*addr, opt_fingerprint.as_deref(), attempt > 1)
+                        .await
+                }
+                _ => match tls::connect(&endpoint, opt_fingerprint.as_deref()).await {
+                    Ok(str) => session.run(AsyncStreamer::new(str), attempt > 1).await,
+                    Err(e) => Err(e),
+                },
+            };
+
+            match res {
+                Ok(ClientOutcome::Finished) => {
+                    info!(
+                        "sent {} file(s) to {}, {} already there",
+                        files.len() - session.skipped,
+                        endpoint,
+                        session.skipped
+                    );
+                    return Ok(SendReport {
+                        peer: endpoint.to_string(),
+                        files: infos,
+                        skipped: session.skipped,
+                        attempts: attempt,
+                    });
+                }
+                Ok(ClientOutcome::Rejected) => return Err(Error::Rejected),
+                Err(e) if Unversioned::is(&e) && !session.legacy => {
+                    info!(
+                        "{} does not answer Hello, speaking protocol version 1",
+                        endpoint
+                    );
+                    session.legacy = true;
+                }
+                Err(e)
+                    if attempt >= retry.max_attempts
+                        || !is_retryable(&e)
+                        || session.stdin_consumed =>
+                {
+                    let done = session.finished.iter().filter(|f| **f).count();
+                    error!(
+                        "sending failed after {} attempt(s), {}/{} file(s) sent: {}",
+                        attempt,
+                        done,
+                        files.len(),
+                        e
+                    );
+                    return Err(Error::partial(e.into(), done, files.len()));
+                }
+                Err(e) => {
+                    warn!(
+                        "attempt {}/{} failed: {}, reconnecting in {:?}",
+                        attempt, retry.max_attempts, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = min(backoff * 2, retry.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// what survives the connections of a send, the steps follow `ClientStateMachine`
+struct Session<'a> {
+    items: &'a [SourceFile],
+    finished: Vec<bool>,
+    checksum: bool,
+    /// files the receiver already had
+    skipped: usize,
+    stdin_consumed: bool,
+    /// the receiver hung up on Hello, it is asked again in JSON
+    legacy: bool,
+    opt_observer: Option<Arc<dyn Observer>>,
+    cancel: CancelHandle,
+}
+
+impl Session<'_> {
+    async fn run<S>(&mut self, mut str: AsyncStreamer<S>, resume: bool) -> io::Result<ClientOutcome>
+    where
+        S: FileStream,
+    {
+        let (infos, offsets) = match self.request(&mut str, resume).await? {
+            Some(answer) => answer,
+            None => return Ok(ClientOutcome::Rejected),
+        };
+
+        let total = self.items.len();
+        for (index, offset) in offsets.into_iter().enumerate() {
+            if let Some(offset) = offset {
+                let item = &self.items[index];
+                self.stdin_consumed |= item.is_stdin();
+                let data = StartFileData::new(infos[index].clone(), index, total, offset);
+                match send_file(
+                    &mut str,
+                    item,
+                    data,
+                    self.opt_observer.as_ref(),
+                    &self.cancel,
+                )
+                .await
+                {
+                    Ok(sent) => self.finished[index] = sent,
+                    Err(e) => return Err(self.stopped(&mut str, e).await),
+                }
+            }
+        }
+
+        if let Err(e) = finish(&mut str).await {
+            return Err(self.stopped(&mut str, e).await);
+        }
+        self.notify(Event::Finished);
+        Ok(ClientOutcome::Finished)
+    }
+
+    /// same steps over QUIC, sending the files at once on their own streams
+    #[cfg(feature = "quic")]
+    async fn run_quic(
+        &mut self,
+        addr: SocketAddr,
+        opt_fingerprint: Option<&str>,
+        resume: bool,
+    ) -> io::Result<ClientOutcome> {
+        let connection = quic::connect(addr, opt_fingerprint).await?.connection;
+        let (send, recv) = connection
+            .open_bi()
+            .await
+            .map_err(|e| quic::cancelled(e.into()))?;
+        let mut control = AsyncStreamer::new(QuicStream::new(send, recv));
+        let (infos, offsets) = match self.request(&mut control, resume).await? {
+            Some(answer) => answer,
+            None => return Ok(ClientOutcome::Rejected),
+        };
+
+        let total = self.items.len();
+        let mut tasks = Vec::new();
+        let mut res = Ok(());
+        for (index, offset) in offsets.into_iter().enumerate() {
+            if let Some(offset) = offset {
+                if self.cancel.is_cancelled() {
+                    break;
+                }
+                let item = self.items[index].clone();
+                self.stdin_consumed |= item.is_stdin();
+                let data = StartFileData::new(infos[index].clone(), index, total, offset);
+                let opt_observer = self.opt_observer.clone();
+                let cancel = self.cancel.clone();
+                // waits while the receiver has as many files in flight as it allows
+                let send = match connection.open_uni().await {
+                    Ok(send) => send,
+                    Err(e) => {
+                        res = Err(quic::cancelled(e.into()));
+                        break;
+                    }
+                };
+                let mut str =
+                    AsyncStreamer::with_encoding(QuicStream::sending(send), control.encoding());
+                let connection = connection.clone();
+                tasks.push(tokio::spawn(async move {
+                    let observer = opt_observer.as_ref();
+                    let sent = match send_file(&mut str, &item, data, observer, &cancel).await {
+                        Ok(sent) => sent,
+                        Err(e) if quic::is_stopped(&e) => {
+                            let reason = quic::STOPPED_REASON.to_string();
+                            notify_file_cancelled(observer, index, reason);
+                            return Ok((index, false));
+                        }
+                        Err(e) => {
+                            // the other files stop at once
+                            if let Some(c) = Cancelled::get(&e).filter(|c| !c.by_peer) {
+                                quic::cancel(&connection, &c.reason);
+                            }
+                            return Err(e);
+                        }
+                    };
+                    str.get_mut().finish().await?;
+                    Ok::<_, io::Error>((index, sent))
+                }));
+            }
+        }
+
+        // keep the files that made it for a resume
+        for task in tasks {
+            match task.await.map_err(io::Error::other)? {
+                Ok((index, sent)) => self.finished[index] = sent,
+                Err(e) => res = res.and(Err(e)),
+            }
+        }
+        if let Some(reason) = self.cancel.session_reason() {
+            quic::cancel(&connection, &reason);
+            res = res.and(Err(Cancelled::error(&reason, false)));
+        }
+        if let Err(e) = res {
+            self.notify_cancelled(&e);
+            return Err(e);
+        }
+
+        // the receiver answers once every file is written
+        let res = match control.write_packet(Packet::Finish).await {
+            Ok(_) => control.read_packet().await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(Packet::Finish) => {}
+            Ok(_) => return Err(io::Error::new(ErrorKind::InvalidData, "unexpected packet")),
+            Err(e) => {
+                self.notify_cancelled(&e);
+                return Err(e);
+            }
+        }
+        connection.close(VarInt::from_u32(quic::CLOSE_DONE), b"done");
+        self.notify(Event::Finished);
+        Ok(ClientOutcome::Finished)
+    }
+
+    /// ask to send the files, and get which of them to send from which offset
+    async fn request<S>(
+        &mut self,
+        str: &mut AsyncStreamer<S>,
+        resume: bool,
+    ) -> io::Result<Option<(Vec<FileInfo>, Vec<Option<u64>>)>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let infos = self
+            .items
+            .iter()
+            .map(|item| item.request_info(self.checksum))
+            .collect::<io::Result<Vec<FileInfo>>>()?;
+        self.notify(Event::Requested {
+            files: infos.clone(),
+        });
+        let packet = if resume {
+            Packet::Resume(infos.clone())
+        } else {
+            Packet::Send(infos.clone())
+        };
+        if !self.legacy {
+            str.hello().await?;
+        }
+        str.write_packet(packet).await?;
+
+        // this sender sends whole files, the signatures offering a delta and the offer of
+        // deduplication are left aside
+        let mut answer = str.read_packet().await?;
+        while let Packet::Signature(_) | Packet::Dedup = answer {
+            answer = str.read_packet().await?;
+        }
+        let (offsets, have) = match answer {
+            Packet::Accept => (vec![Some(0); self.items.len()], Vec::new()),
+            Packet::ResumeAt(kept) => {
+                let offsets = resume_offsets(self.items, &kept, &self.finished)?;
+                (offsets, Vec::new())
+            }
+            Packet::Have(have) => (have_offsets(self.items, &have)?, have),
+            Packet::Reject => {
+                self.notify(Event::Rejected);
+                return Ok(None);
+            }
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        };
+        self.notify(Event::Accepted);
+        for index in (0..have.len()).filter(|i| have[*i]) {
+            self.finished[index] = true;
+            self.skipped += 1;
+            self.notify(Event::Skipped {
+                index,
+                file: infos[index].clone(),
+            });
+        }
+        Ok(Some((infos, offsets)))
+    }
+
+    /// tell the receiver when the session was cancelled here; a receiver cancelling the
+    /// session sends Cancel and hangs up, so a write failing because it hung up may have a
+    /// Cancel waiting to be read
+    async fn stopped<S>(&self, str: &mut AsyncStreamer<S>, e: io::Error) -> io::Error
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let e = if let Some(c) = Cancelled::get(&e).filter(|c| !c.by_peer) {
+            let data = CancelData::new(CancelScope::Session, &c.reason);
+            if let Err(e) = str.write_packet(Packet::Cancel(data)).await {
+                warn!("cannot tell the receiver about the cancellation: {}", e);
+            }
+            e
+        } else if Cancelled::is(&e) || !hung_up(&e) {
+            e
+        } else {
+            match str.read_packet().await {
+                Ok(Packet::Cancel(data)) if data.scope != CancelScope::File => data.peer_error(),
+                _ => e,
+            }
+        };
+        self.notify_cancelled(&e);
+        e
+    }
+
+    fn notify_cancelled(&self, e: &io::Error) {
+        if let Some(c) = Cancelled::get(e) {
+            self.notify(Event::Cancelled {
+                index: None,
+                reason: c.reason.clone(),
+                by_peer: c.by_peer,
+            })
+        }
+    }
+
+    fn notify(&self, event: Event) {
+        notify(self.opt_observer.as_ref(), event)
+    }
+}
+
+/// StartFile, the content from the offset of `data`, then EndFile or Cancel when the file is
+/// cancelled, in which case it returns false; a file the receiver cancels ends with EndFile
+async fn send_file<W>(
+    str: &mut AsyncStreamer<W>,
+    item: &SourceFile,
+    data: StartFileData,
+    opt_observer: Option<&Arc<dyn Observer>>,
+    cancel: &CancelHandle,
+) -> io::Result<bool>
+where
+    W: FileStream,
+{
+    let index = data.index;
+    let mut file = data.file_info.clone();
+    let mut reader = open_at(item, data.offset).await?;
+    // the part sent by an earlier session is read again to hash it
+    let (path, offset) = (item.path.clone(), data.offset);
+    let mut hash = tokio::task::spawn_blocking(move || ContentHash::resume(&path, offset))
+        .await
+        .map_err(io::Error::other)??;
+    // holes are only known to version 2
+    let mut opt_sparse = match str.encoding().version() >= 2 && !item.is_stdin() {
+        true => {
+            let path = item.path.clone();
+            tokio::task::spawn_blocking(move || SparseReader::open(&path, offset))
+                .await
+                .map_err(io::Error::other)??
+        }
+        false => None,
+    };
+    notify(
+        opt_observer,
+        Event::FileStarted {
+            index,
+            file: file.clone(),
+        },
+    );
+    let mut sent_size = data.offset;
+    str.write_packet(Packet::StartFile(data)).await?;
+
+    let mut buf = vec![0_u8; FILE_DATA_LEN];
+    loop {
+        if let Some(reason) = cancel.session_reason() {
+            return Err(Cancelled::error(&reason, false));
+        }
+        if let Some(reason) = cancel.take_file_reason() {
+            notify(
+                opt_observer,
+                Event::Cancelled {
+                    index: Some(index),
+                    reason: reason.clone(),
+                    by_peer: false,
+                },
+            );
+            let data = CancelData::new(CancelScope::File, &reason);
+            str.write_packet(Packet::Cancel(data)).await?;
+            return Ok(false);
+        }
+        match str.peer_cancel().await? {
+            Some(data) if data.scope != CancelScope::File => return Err(data.peer_error()),
+            // the receiver got an earlier file whole meanwhile
+            Some(data) if data.index.is_some_and(|i| i != index) => {}
+            Some(data) => {
+                notify_file_cancelled(opt_observer, index, data.reason);
+                str.write_packet(Packet::EndFile).await?;
+                return Ok(false);
+            }
+            None => {}
+        }
+        let packet = match opt_sparse.take() {
+            Some(mut sparse) => {
+                // looking for the holes and reading the data block
+                let (sparse, res) = tokio::task::spawn_blocking(move || {
+                    let res = sparse.next_piece();
+                    (sparse, res)
+                })
+                .await
+                .map_err(io::Error::other)?;
+                let piece = match res? {
+                    Some(Piece::Data(data)) => {
+                        hash.update(&data);
+                        Packet::FileData(data)
+                    }
+                    Some(Piece::Hole(hole)) => {
+                        hash.update_zeros(hole.len);
+                        Packet::Hole(hole)
+                    }
+                    None => break,
+                };
+                sent_size = sparse.position();
+                opt_sparse = Some(sparse);
+                piece
+            }
+            None => {
+                let len = reader.read(&mut buf).await?;
+                if len == 0 {
+                    break;
+                }
+                hash.update(&buf[..len]);
+                sent_size += len as u64;
+                Packet::FileData(buf[..len].to_vec())
+            }
+        };
+        str.write_packet(packet).await?;
+        notify(
+            opt_observer,
+            Event::Progress {
+                index,
+                bytes: sent_size,
+            },
+        );
+    }
+
+    str.write_packet(Packet::EndFile).await?;
+    file.hash = Some(hash.finish());
+    notify(opt_observer, Event::FileCompleted { index, file });
+    Ok(true)
+}
+
+/// Finish, answered from version 3 after the Cancel of a file the receiver got whole meanwhile
+async fn finish<S>(str: &mut AsyncStreamer<S>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    str.write_packet(Packet::Finish).await?;
+    while str.encoding().version() >= 3 {
+        match str.read_packet().await? {
+            Packet::Finish => break,
+            Packet::Cancel(data) if data.scope == CancelScope::File => {}
+            Packet::Cancel(data) => return Err(data.peer_error()),
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        }
+    }
+    Ok(())
+}
+
+fn notify_file_cancelled(opt_observer: Option<&Arc<dyn Observer>>, index: usize, reason: String) {
+    notify(
+        opt_observer,
+        Event::Cancelled {
+            index: Some(index),
+            reason,
+            by_peer: true,
+        },
+    )
+}
+
+fn notify(opt_observer: Option<&Arc<dyn Observer>>, event: Event) {
+    if let Some(observer) = opt_observer {
+        observer.on_event(&event)
+    }
+}
+
+/// async counterpart of `SourceFile::open_at`
+async fn open_at(item: &SourceFile, offset: u64) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+    match (item.is_stdin(), offset) {
+        (true, 0) => Ok(Box::new(tokio::io::stdin())),
+        (true, _) => Err(io::Error::new(ErrorKind::InvalidInput, "cannot seek stdin")),
+        (false, _) => {
+            let mut file = tokio::fs::File::open(&item.path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            Ok(Box::new(file))
+        }
+    }
+}
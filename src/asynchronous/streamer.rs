@@ -0,0 +1,202 @@
+use crate::packet::cancel::CancelData;
+use crate::packet::{Encoding, Packet, HEADER_LEN, MAX_MESSAGE_LEN, VERSIONS};
+use crate::streamer::unversioned;
+use std::future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// async counterpart of `Connection`
+pub trait AsyncConnection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncConnection for T {}
+
+/// async counterpart of `Streamer`, with the same framing; it only needs the halves of the
+/// stream it uses, such as the receiving side of a QUIC stream, so the encoding is negotiated
+/// explicitly with `hello` and `read_request`
+pub struct AsyncStreamer<S> {
+    str: S,
+    encoding: Encoding,
+    /// the first byte of the next packet, read by `pending`
+    opt_peeked: Option<u8>,
+}
+
+impl<S> AsyncStreamer<S> {
+    pub fn new(str: S) -> Self {
+        Self::with_encoding(str, Encoding::Json)
+    }
+
+    /// a stream of a session whose encoding is already negotiated
+    pub fn with_encoding(str: S, encoding: Encoding) -> Self {
+        AsyncStreamer {
+            str,
+            encoding,
+            opt_peeked: None,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.str
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncStreamer<S> {
+    /// [1 byte for action] + [2 bytes for len] + [additional data]
+    pub async fn write_packet(&mut self, packet: Packet) -> Result<usize> {
+        let vec = packet.into_bytes(self.encoding)?;
+        self.str.write_all(&vec).await?;
+        self.str.flush().await?;
+        Ok(vec.len())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncStreamer<S> {
+    /// [1 byte for action] + [2 bytes for len] + [additional data]
+    pub async fn read_packet(&mut self) -> Result<Packet> {
+        let (action, data) = self.read_message().await?;
+        Packet::from_data(action, &data, self.encoding)
+    }
+
+    /// action and data of a packet, made of the frames continuing the first one
+    async fn read_message(&mut self) -> Result<(u8, Vec<u8>)> {
+        let (action, mut data) = self.read_frame().await?;
+        let mut len = data.len();
+        while self.encoding.is_continued(len) {
+            let (next, part) = self.read_frame().await?;
+            if next != action {
+                return Err(Error::new(ErrorKind::InvalidData, "unexpected frame"));
+            }
+            if data.len() + part.len() > MAX_MESSAGE_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "packet too large"));
+            }
+            len = part.len();
+            data.extend_from_slice(&part);
+        }
+        Ok((action, data))
+    }
+
+    async fn read_frame(&mut self) -> Result<(u8, Vec<u8>)> {
+        let mut header = [0_u8; HEADER_LEN];
+        match self.opt_peeked.take() {
+            Some(byte) => {
+                header[0] = byte;
+                self.str.read_exact(&mut header[1..]).await?;
+            }
+            None => {
+                self.str.read_exact(&mut header).await?;
+            }
+        }
+        let (action, len) = Packet::parse_header(header);
+
+        let mut data_buf = vec![0_u8; len as usize];
+        self.str.read_exact(&mut data_buf).await?;
+        Ok((action, data_buf))
+    }
+
+    /// whether the peer wrote something not read yet or hung up, without waiting for it
+    async fn pending(&mut self) -> Result<bool> {
+        if self.opt_peeked.is_some() {
+            return Ok(true);
+        }
+        let mut byte = [0_u8; 1];
+        let str = &mut self.str;
+        let opt_read = future::poll_fn(|cx| {
+            let mut buf = ReadBuf::new(&mut byte);
+            Poll::Ready(match Pin::new(&mut *str).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => Ok(Some(buf.filled().len())),
+                Poll::Ready(Err(e)) => Err(e),
+                Poll::Pending => Ok(None),
+            })
+        })
+        .await?;
+        if opt_read == Some(1) {
+            self.opt_peeked = Some(byte[0]);
+        }
+        Ok(opt_read.is_some())
+    }
+}
+
+/// the stream a file goes on, where from version 3 the receiver tells the sender about the
+/// files it cancels
+pub(crate) trait FileStream: AsyncRead + AsyncWrite + Unpin {
+    /// stop the file coming on the stream, false when the stream goes on and the receiver
+    /// writes Cancel to it instead
+    fn stop(&mut self) -> bool {
+        false
+    }
+
+    /// whether the receiver writes to the stream the sender sends a file on
+    fn is_duplex(&self) -> bool {
+        true
+    }
+}
+
+impl FileStream for Box<dyn AsyncConnection> {}
+
+impl<S: FileStream> AsyncStreamer<S> {
+    /// tell the sender to stop the file at `index`, true when what it sent of the file still
+    /// comes up to EndFile
+    pub(crate) async fn stop_file(&mut self, index: usize, reason: &str) -> Result<bool> {
+        if self.encoding.version() < 3 {
+            return Ok(true);
+        }
+        if self.str.stop() {
+            return Ok(false);
+        }
+        let data = CancelData::file(index, reason);
+        self.write_packet(Packet::Cancel(data)).await?;
+        Ok(true)
+    }
+
+    /// the Cancel the receiver wrote since the stream was last read, if any
+    pub(crate) async fn peer_cancel(&mut self) -> Result<Option<CancelData>> {
+        if self.encoding.version() < 3 || !self.str.is_duplex() || !self.pending().await? {
+            return Ok(None);
+        }
+        match self.read_packet().await? {
+            Packet::Cancel(data) => Ok(Some(data)),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncStreamer<S> {
+    /// offer the versions of this side before the first request, the peer answers with the
+    /// one of the session; a peer of version 1 hangs up instead
+    pub async fn hello(&mut self) -> Result<()> {
+        self.encoding = Encoding::Json;
+        self.write_packet(Packet::Hello(VERSIONS.to_vec())).await?;
+        self.encoding = match self.read_packet().await.map_err(unversioned)? {
+            Packet::Hello(answer) => Encoding::from_answer(&answer)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected answer to Hello",
+                ))
+            }
+        };
+        Ok(())
+    }
+
+    /// the first packet of a session, after answering the Hello of a peer offering versions;
+    /// a peer starting without Hello speaks JSON
+    pub async fn read_request(&mut self) -> Result<Packet> {
+        self.encoding = Encoding::Json;
+        match self.read_packet().await? {
+            Packet::Hello(offered) => {
+                let encoding = Encoding::choose(&offered)?;
+                self.write_packet(Packet::Hello(vec![encoding.version()]))
+                    .await?;
+                self.encoding = encoding;
+                self.read_packet().await
+            }
+            packet => Ok(packet),
+        }
+    }
+}
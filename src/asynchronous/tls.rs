@@ -0,0 +1,97 @@
+use super::streamer::AsyncConnection;
+use crate::tls::{fingerprint_der, KeyPair};
+use crate::transport::Endpoint;
+use log::warn;
+use std::convert::TryFrom;
+use std::io::{self, Error, ErrorKind};
+use std::sync::Arc;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+pub fn acceptor(keypair: &KeyPair) -> io::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(keypair)?)))
+}
+
+/// TLS configuration of the receiver, presenting `keypair`
+pub fn server_config(keypair: &KeyPair) -> io::Result<ServerConfig> {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(keypair.signed_public_key().0)],
+            PrivateKey(keypair.get_private_key().0),
+        )
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// TLS configuration of the sender, only trusting the certificate with the given fingerprint
+/// if any
+pub fn client_config(fingerprint: Option<&str>) -> ClientConfig {
+    let verifier = danger::FingerprintVerification {
+        opt_fingerprint: fingerprint.map(String::from),
+    };
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+/// connect to a TLS or plaintext TCP endpoint, only trusting the certificate with the given
+/// fingerprint if any
+pub async fn connect(
+    endpoint: &Endpoint,
+    fingerprint: Option<&str>,
+) -> io::Result<Box<dyn AsyncConnection>> {
+    match endpoint {
+        Endpoint::Tls(addr) => Ok(Box::new(connect_tls(*addr, fingerprint).await?)),
+        Endpoint::TlsHost(host) => Ok(Box::new(connect_tls(host.as_str(), fingerprint).await?)),
+        Endpoint::Tcp(addr) => {
+            warn!("connecting to {} without encryption", addr);
+            Ok(Box::new(TcpStream::connect(addr).await?))
+        }
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("the async sender cannot connect to {}", endpoint),
+        )),
+    }
+}
+
+async fn connect_tls<A: ToSocketAddrs>(
+    addr: A,
+    fingerprint: Option<&str>,
+) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(Arc::new(client_config(fingerprint)));
+
+    let str = TcpStream::connect(addr).await?;
+    let dns_name = ServerName::try_from("localhost").unwrap();
+    connector.connect(dns_name, str).await
+}
+
+mod danger {
+    use super::{fingerprint_der, rustls};
+
+    /// pinned certificate when a fingerprint is given, no verification otherwise
+    pub struct FingerprintVerification {
+        pub opt_fingerprint: Option<String>,
+    }
+
+    impl rustls::client::ServerCertVerifier for FingerprintVerification {
+        fn verify_server_cert(
+            &self,
+            end_entity: &rustls::Certificate,
+            _intermediates: &[rustls::Certificate],
+            _server_name: &rustls::ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp: &[u8],
+            _now: std::time::SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+            match self.opt_fingerprint.as_ref() {
+                Some(fp) if fingerprint_der(&end_entity.0) != *fp => Err(rustls::Error::General(
+                    "certificate fingerprint does not match".to_string(),
+                )),
+                _ => Ok(rustls::client::ServerCertVerified::assertion()),
+            }
+        }
+    }
+}
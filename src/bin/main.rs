@@ -1,73 +1,862 @@
 use std::env;
 
-use getopts::{HasArg, Occur, Options};
-use sendfile_cli::driver::{client_send_files, ServerDriver};
+use getopts::{Matches, Options};
+use sendfile_cli::config::{self, Settings};
+use sendfile_cli::driver::{
+    client_discover, client_get, client_list, client_send_files, client_send_files_to, client_sync,
+    SendOptions, ServerDriver, DISCOVERY_TIMEOUT,
+};
+use sendfile_cli::history::{self, Direction, Record};
+use sendfile_cli::transport::Proxy;
+use sendfile_cli::{
+    CancelHandle, ConflictPolicy, Endpoint, Error, Event, KeyPair, Observer, Receiver, Result,
+    Selection,
+};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// time given to a cancelled transfer to tell the peer and clean up before exiting
+const CANCEL_GRACE: Duration = Duration::from_secs(2);
+
+/// least time between two progress events of the JSON output
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// exit codes, so that scripts can tell failures apart
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_REJECTED: i32 = 3;
+/// some files were transferred before the failure
+const EXIT_PARTIAL: i32 = 4;
+const EXIT_NETWORK: i32 = 5;
+/// the size or the hash of the data received does not match what was announced
+const EXIT_INTEGRITY: i32 = 6;
+const EXIT_CANCELLED_BY_PEER: i32 = 7;
+/// after Ctrl-C
+const EXIT_INTERRUPTED: i32 = 130;
 
 extern crate getopts;
 
-fn main() {
-    env_logger::init();
+struct Command {
+    name: &'static str,
+    about: &'static str,
+    /// arguments after the options, for the help
+    args: &'static str,
+    options: fn(&mut Options),
+    run: fn(&Cli) -> Result<()>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "send",
+        about: "send files or directories to a receiver",
+        args: "PATH...",
+        options: send_options,
+        run: send,
+    },
+    Command {
+        name: "receive",
+        about: "receive files, or share or sync a directory",
+        args: "[PORT|URL]",
+        options: receive_options,
+        run: receive,
+    },
+    Command {
+        name: "list",
+        about: "list the files shared by a receiver",
+        args: "ADDRESS [PATH]",
+        options: no_options,
+        run: list,
+    },
+    Command {
+        name: "get",
+        about: "download files or directories shared by a receiver",
+        args: "ADDRESS NAME...",
+        options: get_options,
+        run: get,
+    },
+    Command {
+        name: "sync",
+        about: "mirror a directory with a receiver",
+        args: "ADDRESS DIR",
+        options: sync_options,
+        run: sync,
+    },
+    Command {
+        name: "peers",
+        about: "list the receivers announced on the local network",
+        args: "",
+        options: peers_options,
+        run: peers,
+    },
+    Command {
+        name: "identity",
+        about: "create a TLS identity, or show the fingerprint of one",
+        args: "new|show [CERT KEY]",
+        options: no_options,
+        run: identity,
+    },
+    Command {
+        name: "history",
+        about: "list the past transfers",
+        args: "",
+        options: history_options,
+        run: history,
+    },
+];
 
+/// the parsed command line of a command
+struct Cli {
+    prog: String,
+    command: &'static Command,
+    opts: Options,
+    m: Matches,
+    settings: Settings,
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
-    let prog = args.first().unwrap();
+    let prog = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from("sendfile"));
 
-    let mut opts = Options::new();
-    opts.opt(
-        "s",
-        "server",
-        "start server with port (example: -s 8080)",
-        "PORT",
-        HasArg::Yes,
-        Occur::Optional,
-    );
-    opts.opt(
-        "c",
-        "client",
-        "connect to server",
-        "SERVER_ADDRESS (example: -c 127.0.0.1:8080)",
-        HasArg::Yes,
-        Occur::Optional,
-    );
-    opts.optmulti("f", "", "selected file (for client)", "FILE");
+    let name = match args.get(1) {
+        Some(name) => name.as_str(),
+        None => {
+            eprint!("{}", usage(&prog));
+            process::exit(EXIT_USAGE)
+        }
+    };
+    let opt_command = match name {
+        "-h" | "--help" | "help" => match args.get(2) {
+            Some(name) => Some(find_command(&prog, name)),
+            None => {
+                print!("{}", usage(&prog));
+                return;
+            }
+        },
+        _ => None,
+    };
+    if let Some(command) = opt_command {
+        print!(
+            "{}",
+            command_help(&prog, command, &command_options(command))
+        );
+        return;
+    }
+    let command = find_command(&prog, name);
 
-    // parse
-    let m = match opts.parse(&args[1..]) {
+    let opts = command_options(command);
+    let m = match opts.parse(&args[2..]) {
         Ok(m) => m,
         Err(e) => {
-            print_help(prog, &opts);
-            panic!("{}", e)
+            eprintln!("error: {}", e);
+            eprint!("{}", command_help(&prog, command, &opts));
+            process::exit(EXIT_USAGE)
         }
     };
+    if m.opt_present("h") {
+        print!("{}", command_help(&prog, command, &opts));
+        return;
+    }
+
+    let config_path = m.opt_str("config").map(PathBuf::from);
+    let settings = config::load(config_path.as_deref(), m.opt_str("profile").as_deref())
+        .unwrap_or_else(|e| exit_with(e));
+    init_logger(&settings);
+
+    let cli = Cli {
+        prog,
+        command,
+        opts,
+        m,
+        settings,
+    };
+    if let Err(e) = (command.run)(&cli) {
+        if cli.json() {
+            print_json(error_event(&e));
+        }
+        exit_with(e)
+    }
+}
+
+fn find_command(prog: &str, name: &str) -> &'static Command {
+    match COMMANDS.iter().find(|c| c.name == name) {
+        Some(command) => command,
+        None => {
+            eprintln!("error: unknown command: {}", name);
+            eprint!("{}", usage(prog));
+            process::exit(EXIT_USAGE)
+        }
+    }
+}
+
+/// options of the command, and the ones every command takes
+fn command_options(command: &Command) -> Options {
+    let mut opts = Options::new();
+    (command.options)(&mut opts);
+    opts.optopt(
+        "",
+        "config",
+        "read the settings from this file only, instead of /etc/sendfile/config.toml and ~/.config/sendfile/config.toml",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "profile",
+        "apply the settings of this profile of the configuration",
+        "NAME",
+    );
+    opts.optflag("h", "help", "print this help");
+    opts
+}
+
+fn send_options(opts: &mut Options) {
+    opts.optopt(
+        "c",
+        "connect",
+        "address of the receiver, with TLS unless a tcp://, quic:// or unix: URL is given",
+        "ADDRESS (example: -c 127.0.0.1:8080, -c unix:/tmp/sendfile.sock)",
+    );
+    opts.optopt(
+        "",
+        "to",
+        "send to the peer advertised with this name",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "name",
+        "name given to the standard input, sent with - as PATH (default: stdin)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "retries",
+        "reconnect up to N times when the connection fails (default: 4)",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "include",
+        "only send the files of the directories matching this glob, such as '*.rs'",
+        "GLOB",
+    );
+    opts.optmulti(
+        "",
+        "exclude",
+        "leave out the files and directories matching this glob, such as 'target/'",
+        "GLOB",
+    );
+    opts.optflag(
+        "",
+        "gitignore",
+        "leave out what the .gitignore and .ignore files ignore, and .git",
+    );
+    opts.optopt(
+        "",
+        "max-depth",
+        "only send the files at most N directories down, 1 for the files of a directory",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "min-size",
+        "leave out the files of the directories smaller than this",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-size",
+        "leave out the files of the directories larger than this",
+        "BYTES",
+    );
+    opts.optflag(
+        "",
+        "checksum",
+        "hash the files so that the receiver only skips the ones with the same content",
+    );
+    opts.optflag(
+        "",
+        "dedup",
+        "split the files into chunks and only send the ones a receiver with --dedup lacks",
+    );
+    opts.optflag(
+        "",
+        "zero-copy",
+        "on Linux, let the kernel copy the files to tcp:// and unix: sockets",
+    );
+    opts.optopt(
+        "",
+        "fingerprint",
+        "only trust the receiver presenting the certificate with this SHA-256 fingerprint, needed for relay:// URLs",
+        "SHA256",
+    );
+    opts.optopt(
+        "",
+        "proxy",
+        "connect through a socks5:// or http:// proxy (default: $SENDFILE_PROXY)",
+        "URL",
+    );
+    opts.optflag("", "json", "print the events of the transfer as JSON lines");
+}
+
+fn receive_options(opts: &mut Options) {
+    opts.optopt(
+        "b",
+        "bind",
+        "listen on this address when a port is given (default: 127.0.0.1, or 0.0.0.0 with --announce)",
+        "IP",
+    );
+    opts.optopt(
+        "o",
+        "output",
+        "directory the received files are written to (default: out)",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "stdout",
+        "write a single received file to the standard output and exit",
+    );
+    opts.optflag(
+        "",
+        "whole-file",
+        "receive changed files whole instead of a delta of the copies in the output directory",
+    );
+    opts.optflag(
+        "",
+        "dedup",
+        "keep an index of the chunks of the received files, to only receive the new ones",
+    );
+    opts.optflag(
+        "",
+        "once",
+        "serve a single session and exit, with the exit code of that session",
+    );
+    opts.optopt(
+        "",
+        "announce",
+        "advertise the receiver on the local network with this name",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "share",
+        "let clients list and download the files of this directory",
+        "DIR",
+    );
+    opts.optopt("", "sync", "let clients mirror this directory", "DIR");
+    opts.optopt(
+        "",
+        "socket-mode",
+        "permissions of the Unix domain socket file in octal (example: 660)",
+        "MODE",
+    );
+    opts.optmulti(
+        "",
+        "allow-uid",
+        "only accept Unix domain socket connections from processes of this user",
+        "UID",
+    );
+    opts.optflag("", "json", "print the events of the sessions as JSON lines");
+}
 
-    // print
-    let is_server = m.opt_present("s");
-    let is_client = m.opt_present("c");
-    match (is_server, is_client) {
-        (false, false) => {
-            print_help(prog, &opts);
-            panic!("Required -s for server or -c for client")
-        }
-        (true, _) => {
-            let port: u16 = m.opt_get("s").unwrap().unwrap();
-            let server = ServerDriver::create_server(port);
-            loop {
-                server.accept_conn()
+fn get_options(opts: &mut Options) {
+    opts.optopt(
+        "o",
+        "output",
+        "directory the downloaded files are written to (default: out)",
+        "DIR",
+    );
+}
+
+fn sync_options(opts: &mut Options) {
+    opts.optopt(
+        "",
+        "conflict",
+        "which version wins when a file differs on both sides: newer, local, remote or skip (default: newer)",
+        "POLICY",
+    );
+}
+
+fn peers_options(opts: &mut Options) {
+    opts.optopt(
+        "",
+        "timeout",
+        "listen for announces during this many seconds (default: 5)",
+        "SECS",
+    );
+}
+
+fn history_options(opts: &mut Options) {
+    opts.optopt("n", "last", "only list the last N transfers", "N");
+}
+
+fn no_options(_: &mut Options) {}
+
+fn send(cli: &Cli) -> Result<()> {
+    let paths: Vec<PathBuf> = cli.m.free.iter().map(PathBuf::from).collect();
+    if paths.is_empty() {
+        cli.usage_error("no file to send")
+    }
+    let mut send_opts = SendOptions::default();
+    if let Some(retries) = cli.opt_parse::<u32>("retries").or(cli.settings.retries) {
+        send_opts.retry.max_attempts = retries + 1;
+    }
+    if let Some(name) = cli.m.opt_str("name") {
+        send_opts.stdin_name = name;
+    }
+    send_opts.selection = cli.selection();
+    send_opts.checksum = cli.m.opt_present("checksum");
+    send_opts.dedup = cli.m.opt_present("dedup");
+    send_opts.zero_copy = cli.m.opt_present("zero-copy");
+    send_opts.opt_fingerprint = cli
+        .m
+        .opt_str("fingerprint")
+        .or_else(|| cli.settings.fingerprint.clone());
+    send_opts.opt_proxy = cli.proxy();
+    if send_opts.opt_proxy.is_some()
+        && send_opts.opt_fingerprint.is_none()
+        && !cli.m.opt_present("to")
+    {
+        eprintln!(
+            "warning: the receiver is not pinned with --fingerprint, the proxy could pass for it"
+        );
+    }
+    send_opts.cancel = interruptible();
+    if cli.json() {
+        send_opts.opt_observer = Some(Arc::new(json_events()));
+    }
+    let (res, target) = match (cli.m.opt_str("c"), cli.m.opt_str("to")) {
+        (Some(addr), None) => (client_send_files(paths, &addr, send_opts), addr),
+        (None, Some(name)) => (client_send_files_to(paths, &name, send_opts), name),
+        _ => cli.usage_error("give the receiver with either -c or --to"),
+    };
+    match &res {
+        Ok(report) => {
+            if cli.json() {
+                print_json(json!({
+                    "event": "summary",
+                    "peer": report.peer,
+                    "files": report.files.len(),
+                    "skipped": report.skipped,
+                    "bytes": report.files.iter().map(|f| f.size).sum::<u64>(),
+                    "attempts": report.attempts,
+                }));
             }
+            record(Record::new(
+                Direction::Sent,
+                &report.peer,
+                &report.files,
+                "ok",
+            ))
+        }
+        Err(e) => record(Record::new(Direction::Sent, &target, &[], &e.to_string())),
+    }
+    res.map(|_| ())
+}
+
+fn receive(cli: &Cli) -> Result<()> {
+    let m = &cli.m;
+    if cli.json() && m.opt_present("stdout") {
+        cli.usage_error("--json and --stdout both write to the standard output")
+    }
+    let announce = m.opt_str("announce");
+    let arg = match m.free.as_slice() {
+        [] => match cli.settings.port {
+            Some(port) => port.to_string(),
+            None => cli.usage_error("no port given, nor in the configuration"),
+        },
+        [arg] => arg.clone(),
+        _ => cli.usage_error("a single port or URL is expected"),
+    };
+    let endpoint: Endpoint = match arg.parse::<u16>() {
+        Ok(port) => {
+            let default_ip = match (cli.settings.bind, &announce) {
+                (Some(ip), _) => ip,
+                (None, Some(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                (None, None) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            };
+            let ip = cli.opt_parse("b").unwrap_or(default_ip);
+            Endpoint::Tls(SocketAddr::new(ip, port))
+        }
+        Err(_) => arg.parse().unwrap_or_else(|e: String| cli.usage_error(e)),
+    };
+
+    let builder = Receiver::builder()
+        .endpoint(endpoint)
+        .stdout(m.opt_present("stdout"))
+        .delta(!m.opt_present("whole-file"))
+        .cancel_handle(interruptible());
+    let mut builder = cli.settings.apply_receiver(builder)?;
+    if let Some(mode) = m.opt_str("socket-mode") {
+        let mode = u32::from_str_radix(&mode, 8)
+            .unwrap_or_else(|e| cli.usage_error(format!("invalid socket mode {:?}: {}", mode, e)));
+        builder = builder.socket_mode(mode);
+    }
+    if m.opt_present("dedup") {
+        // next to the history
+        let path = history::default_path()
+            .map(|p| p.with_file_name("chunks.jsonl"))
+            .ok_or_else(|| Error::Config(String::from("no data directory for the chunk index")))?;
+        builder = builder.chunk_index(path);
+    }
+    let uids: Vec<u32> = m
+        .opt_strs("allow-uid")
+        .iter()
+        .map(|uid| {
+            uid.parse()
+                .unwrap_or_else(|e| cli.usage_error(format!("invalid uid {:?}: {}", uid, e)))
+        })
+        .collect();
+    builder = builder.allow_uids(uids);
+    if let Some(dir) = m.opt_str("o") {
+        builder = builder.output_dir(dir);
+    }
+    if let Some(dir) = m.opt_str("share") {
+        builder = builder.share_dir(dir);
+    }
+    if let Some(dir) = m.opt_str("sync") {
+        builder = builder.sync_dir(dir);
+    }
+    if let Some(name) = announce {
+        builder = builder.announce(&name);
+    }
+    if cli.json() {
+        builder = builder.observer(json_events());
+    }
+
+    let server = ServerDriver::build(builder)?;
+    if m.opt_present("stdout") || m.opt_present("once") {
+        return receive_session(&server, cli.json());
+    }
+    loop {
+        match receive_session(&server, cli.json()) {
+            Err(e @ Error::Cancelled { by_peer: false, .. }) => return Err(e),
+            Err(e) if cli.json() => print_json(error_event(&e)),
+            _ => {}
+        }
+    }
+}
+
+/// serve one session, the files it received go to the history
+fn receive_session(server: &ServerDriver, json: bool) -> Result<()> {
+    let report = server.accept_conn()?;
+    if json {
+        print_json(json!({
+            "event": "summary",
+            "peer": report.peer,
+            "accepted": report.accepted,
+            "files": report.files.len(),
+            "bytes": report.files.iter().map(|f| f.size).sum::<u64>(),
+        }));
+    }
+    if report.accepted {
+        record(Record::new(
+            Direction::Received,
+            &report.peer,
+            &report.files,
+            "ok",
+        ));
+    }
+    Ok(())
+}
+
+fn list(cli: &Cli) -> Result<()> {
+    let (addr, path) = match cli.m.free.as_slice() {
+        [addr] => (addr, ""),
+        [addr, path] => (addr, path.as_str()),
+        _ => cli.usage_error("an address and an optional path are expected"),
+    };
+    client_list(addr, path)?
+        .iter()
+        .for_each(|f| println!("{}\t{}", f.size, f.name));
+    Ok(())
+}
+
+fn get(cli: &Cli) -> Result<()> {
+    let (addr, names) = match cli.m.free.split_first() {
+        Some((addr, names)) if !names.is_empty() => (addr, names),
+        _ => cli.usage_error("an address and the names to download are expected"),
+    };
+    let out_dir = cli
+        .m
+        .opt_str("o")
+        .map(PathBuf::from)
+        .or_else(|| cli.settings.output.clone())
+        .unwrap_or_else(|| PathBuf::from("out"));
+    client_get(addr, names, &out_dir)
+}
+
+fn sync(cli: &Cli) -> Result<()> {
+    let (addr, dir) = match cli.m.free.as_slice() {
+        [addr, dir] => (addr, PathBuf::from(dir)),
+        _ => cli.usage_error("an address and a directory are expected"),
+    };
+    let policy: ConflictPolicy = cli
+        .opt_parse("conflict")
+        .or(cli.settings.conflict)
+        .unwrap_or_default();
+    let summary = client_sync(addr, &dir, policy)?;
+    println!(
+        "sent {} file(s), received {} file(s)",
+        summary.sent, summary.received
+    );
+    Ok(())
+}
+
+fn peers(cli: &Cli) -> Result<()> {
+    let timeout = cli
+        .opt_parse("timeout")
+        .map(Duration::from_secs)
+        .unwrap_or(DISCOVERY_TIMEOUT);
+    client_discover(timeout)?
+        .iter()
+        .for_each(|p| println!("{}\t{}\t{}", p.name, p.addr, p.fingerprint));
+    Ok(())
+}
+
+fn identity(cli: &Cli) -> Result<()> {
+    let identity = &cli.settings.identity;
+    let (action, cert, key) = match (cli.m.free.as_slice(), &identity.cert, &identity.key) {
+        ([action, cert, key], _, _) => (action, PathBuf::from(cert), PathBuf::from(key)),
+        ([action], Some(cert), Some(key)) => (action, cert.clone(), key.clone()),
+        _ => cli.usage_error("give the certificate and key files, or set them in the config"),
+    };
+    let keypair = match action.as_str() {
+        "new" => {
+            let keypair = KeyPair::new();
+            keypair.save_pem_files(&cert, &key)?;
+            keypair
+        }
+        "show" => KeyPair::from_pem_files(&cert, &key)?,
+        _ => cli.usage_error(format!("unknown action: {}", action)),
+    };
+    println!("{}", keypair.fingerprint());
+    Ok(())
+}
+
+fn history(cli: &Cli) -> Result<()> {
+    if !cli.m.free.is_empty() {
+        cli.usage_error("no argument is expected")
+    }
+    let path = history::default_path()
+        .ok_or_else(|| Error::Config(String::from("no data directory for the history")))?;
+    let records = history::load(&path)?;
+    let last = cli.opt_parse("n").unwrap_or(records.len());
+    for r in &records[records.len().saturating_sub(last)..] {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            history::format_time(r.time),
+            r.direction,
+            r.peer,
+            r.files,
+            r.bytes,
+            r.outcome
+        );
+    }
+    Ok(())
+}
+
+impl Cli {
+    /// `--json` was given, only the commands running transfers take it
+    fn json(&self) -> bool {
+        self.m.opt_defined("json") && self.m.opt_present("json")
+    }
+    /// print the error and the help of the command, then exit
+    fn usage_error<M: Display>(&self, msg: M) -> ! {
+        eprintln!("error: {}", msg);
+        eprint!("{}", command_help(&self.prog, self.command, &self.opts));
+        process::exit(EXIT_USAGE)
+    }
+
+    /// proxy of `--proxy`, else of `SENDFILE_PROXY`, else of the configuration
+    fn proxy(&self) -> Option<Proxy> {
+        let url = self
+            .m
+            .opt_str("proxy")
+            .or_else(|| {
+                env::var("SENDFILE_PROXY")
+                    .ok()
+                    .filter(|url| !url.is_empty())
+            })
+            .or_else(|| self.settings.proxy.clone())?;
+        Some(
+            url.parse()
+                .unwrap_or_else(|e| self.usage_error(format!("--proxy: {}", e))),
+        )
+    }
+
+    /// files of the directories to send, from `--include`, `--exclude` and the filters
+    fn selection(&self) -> Selection {
+        let mut selection = Selection::default().gitignore(self.m.opt_present("gitignore"));
+        for glob in self.m.opt_strs("include") {
+            selection = selection.include(&glob);
+        }
+        for glob in self.m.opt_strs("exclude") {
+            selection = selection.exclude(&glob);
+        }
+        if let Some(depth) = self.opt_parse("max-depth") {
+            selection = selection.max_depth(depth);
         }
-        (_, true) => {
-            let paths: Vec<PathBuf> = m.opt_strs("f").iter().map(PathBuf::from).collect();
-            if paths.is_empty() {
-                print_help(prog, &opts);
-                panic!("Required -f for client")
+        if let Some(size) = self.opt_parse("min-size") {
+            selection = selection.min_size(size);
+        }
+        if let Some(size) = self.opt_parse("max-size") {
+            selection = selection.max_size(size);
+        }
+        selection
+    }
+
+    /// value of an option, a usage error when it cannot be parsed
+    fn opt_parse<T: FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.m
+            .opt_get(name)
+            .unwrap_or_else(|e| self.usage_error(format!("--{}: {}", name, e)))
+    }
+}
+
+/// prints every event as a JSON line, progress at most every `JSON_PROGRESS_INTERVAL`
+fn json_events() -> impl Observer {
+    let last_progress: Mutex<Option<Instant>> = Mutex::new(None);
+    move |event: &Event| {
+        if let Event::Progress { .. } = event {
+            let mut last = last_progress.lock().unwrap();
+            if last.is_some_and(|t| t.elapsed() < JSON_PROGRESS_INTERVAL) {
+                return;
             }
-            let addr: String = m.opt_get("c").unwrap().unwrap();
-            client_send_files(paths, addr);
+            *last = Some(Instant::now());
+        }
+        match serde_json::to_value(event) {
+            Ok(value) => print_json(value),
+            Err(e) => eprintln!("warning: cannot serialize {:?}: {}", event, e),
         }
     }
 }
 
-fn print_help(prog: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options]", prog);
-    print!("{}", opts.usage(&brief));
+fn error_event(e: &Error) -> Value {
+    json!({
+        "event": "error",
+        "message": e.to_string(),
+        "exit_code": exit_code(e),
+    })
+}
+
+/// one line, written at once so that events of concurrent transfers do not mix
+fn print_json(value: Value) {
+    println!("{}", value)
+}
+
+/// a cancel handle stopped by Ctrl-C
+fn interruptible() -> CancelHandle {
+    let cancel = CancelHandle::new();
+    handle_ctrlc(cancel.clone());
+    cancel
+}
+
+/// the first Ctrl-C cancels the transfer, the process exits once the peer is told or after a
+/// grace period; a second one exits at once
+fn handle_ctrlc(cancel: CancelHandle) {
+    let res = ctrlc::set_handler(move || {
+        if cancel.is_cancelled() {
+            process::exit(EXIT_INTERRUPTED)
+        }
+        cancel.cancel("interrupted");
+        thread::spawn(|| {
+            thread::sleep(CANCEL_GRACE);
+            process::exit(EXIT_INTERRUPTED)
+        });
+    });
+    if let Err(e) = res {
+        eprintln!("warning: cannot handle Ctrl-C: {}", e);
+    }
+}
+
+/// add a transfer to the history, a transfer does not fail because it cannot be recorded
+fn record(record: Record) {
+    if let Some(path) = history::default_path() {
+        if let Err(e) = history::append(&path, &record) {
+            eprintln!("warning: cannot record the transfer in {:?}: {}", path, e);
+        }
+    }
+}
+
+/// `RUST_LOG` wins over the log level of the configuration
+fn init_logger(settings: &Settings) {
+    let env = match &settings.log {
+        Some(level) => env_logger::Env::default().default_filter_or(level.as_str()),
+        None => env_logger::Env::default(),
+    };
+    env_logger::Builder::from_env(env).init();
+}
+
+fn exit_with(e: Error) -> ! {
+    eprintln!("error: {}", e);
+    process::exit(exit_code(&e))
+}
+
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Integrity(_) => EXIT_INTEGRITY,
+        Error::Io(e) if is_network(e.kind()) => EXIT_NETWORK,
+        Error::Io(_) => EXIT_ERROR,
+        Error::Rejected => EXIT_REJECTED,
+        Error::Config(_) => EXIT_USAGE,
+        Error::Cancelled { by_peer: true, .. } => EXIT_CANCELLED_BY_PEER,
+        Error::Cancelled { by_peer: false, .. } => EXIT_INTERRUPTED,
+        Error::Partial { source, .. } => match exit_code(source) {
+            EXIT_INTERRUPTED => EXIT_INTERRUPTED,
+            _ => EXIT_PARTIAL,
+        },
+    }
+}
+
+fn is_network(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::AddrInUse
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+fn usage(prog: &str) -> String {
+    let mut text = format!("Usage: {} COMMAND [options]\n\nCommands:\n", prog);
+    for command in COMMANDS {
+        text += &format!("    {:<10}{}\n", command.name, command.about);
+    }
+    text += &format!(
+        "\nRun '{} help COMMAND' for the options of a command.\n\n\
+         Exit codes: 0 success, 1 error, 2 usage or configuration error, 3 rejected by the \
+         receiver,\n4 partial transfer, 5 network error, 6 integrity failure, 7 cancelled by \
+         the peer, 130 interrupted\n",
+        prog
+    );
+    text
+}
+
+fn command_help(prog: &str, command: &Command, opts: &Options) -> String {
+    let brief = format!(
+        "Usage: {} {} [options] {}\n\n{}",
+        prog, command.name, command.args, command.about
+    );
+    opts.usage(&brief)
 }
@@ -0,0 +1,78 @@
+use getopts::Options;
+use log::info;
+use sendfile_cli::relay::Relay;
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process;
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let prog = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from("relay"));
+
+    let mut opts = Options::new();
+    opts.optopt(
+        "b",
+        "bind",
+        "listen on this address when a port is given (default: 0.0.0.0)",
+        "IP",
+    );
+    opts.optflag("h", "help", "print this help");
+    let m = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => usage_error(&prog, &opts, e),
+    };
+    if m.opt_present("h") {
+        print!("{}", help(&prog, &opts));
+        return;
+    }
+    let addr = match m.free.as_slice() {
+        [arg] => match arg.parse::<u16>() {
+            Ok(port) => {
+                let ip = match m.opt_str("b") {
+                    Some(ip) => ip.parse().unwrap_or_else(|_| {
+                        usage_error(&prog, &opts, format!("invalid IP address {:?}", ip))
+                    }),
+                    None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                };
+                SocketAddr::new(ip, port)
+            }
+            Err(_) => arg.parse().unwrap_or_else(|_| {
+                usage_error(&prog, &opts, format!("invalid address {:?}", arg))
+            }),
+        },
+        _ => usage_error(&prog, &opts, "a single port or address is expected"),
+    };
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let res = Relay::bind(addr).and_then(|relay| {
+        info!("relaying on {}", relay.local_addr()?);
+        relay.serve()
+    });
+    if let Err(e) = res {
+        eprintln!("error: {}", e);
+        process::exit(EXIT_ERROR)
+    }
+}
+
+fn usage_error<E: Display>(prog: &str, opts: &Options, e: E) -> ! {
+    eprintln!("error: {}", e);
+    eprint!("{}", help(prog, opts));
+    process::exit(EXIT_USAGE)
+}
+
+fn help(prog: &str, opts: &Options) -> String {
+    let brief = format!(
+        "Usage: {} [options] PORT|ADDRESS\n\n\
+         pair the senders and receivers coming with the same session code, such as\n\
+         'receive relay://ADDRESS/CODE' and 'send -c relay://ADDRESS/CODE'",
+        prog
+    );
+    opts.usage(&brief)
+}
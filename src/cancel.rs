@@ -0,0 +1,70 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "quic")]
+use std::time::Duration;
+
+/// how often a task waiting for a cancellation checks for it
+#[cfg(feature = "quic")]
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// stops transfers from another thread, for example on Ctrl-C: give a clone to a sender or a
+/// receiver builder, and call `cancel` or `cancel_file` from anywhere
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    state: Arc<Mutex<CancelState>>,
+}
+
+#[derive(Default)]
+struct CancelState {
+    opt_session: Option<String>,
+    opt_file: Option<String>,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// stop the session in progress and every later one, the peer is told `reason`
+    pub fn cancel(&self, reason: &str) {
+        self.state.lock().unwrap().opt_session = Some(reason.to_string())
+    }
+
+    /// stop the file being transferred, the session goes on with the next one
+    pub fn cancel_file(&self, reason: &str) {
+        self.state.lock().unwrap().opt_file = Some(reason.to_string())
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().opt_session.is_some()
+    }
+
+    /// why the session is cancelled, if it is
+    pub(crate) fn session_reason(&self) -> Option<String> {
+        self.state.lock().unwrap().opt_session.clone()
+    }
+
+    /// why the current file is cancelled, the request only applies to one file
+    pub(crate) fn take_file_reason(&self) -> Option<String> {
+        self.state.lock().unwrap().opt_file.take()
+    }
+
+    /// completes with the reason once the session is cancelled
+    #[cfg(feature = "quic")]
+    pub(crate) async fn cancelled(&self) -> String {
+        loop {
+            if let Some(reason) = self.session_reason() {
+                return reason;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await
+        }
+    }
+}
+
+impl fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
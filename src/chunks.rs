@@ -0,0 +1,380 @@
+//! content-defined chunks shared between files: the sender cuts its files where a rolling hash of
+//! the last bytes matches a pattern, so that a section found in several files is cut into the
+//! same chunks, and the receiver only asks for the chunks missing from its index
+
+use crate::error::Integrity;
+use crate::packet::chunks::{ChunkInfo, CHUNK_HASH_LEN};
+use crate::packet::FILE_DATA_LEN;
+use crate::source::ContentHash;
+use log::debug;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const MIN_CHUNK_LEN: usize = 16 * 1024;
+
+/// a cut after each byte with probability 2^-16, chunks are about 80 KiB on average
+const AVG_BITS: u32 = 16;
+
+const MAX_CHUNK_LEN: usize = 256 * 1024;
+
+/// the high bits of the hash, which depend on the last 64 bytes
+const MASK: u64 = ((1 << AVG_BITS) - 1) << (64 - AVG_BITS);
+
+/// random value of each byte for the gear hash, the same on every side
+const GEAR: [u64; 256] = gear_table();
+
+/// splitmix64 from a fixed seed
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0_u64; 256];
+    let mut state = 0x7365_6e64_6669_6c65_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// length of the chunk at the start of `buf`, which holds the rest of the content when shorter
+/// than the longest chunk
+fn cut(buf: &[u8]) -> usize {
+    if buf.len() <= MIN_CHUNK_LEN {
+        return buf.len();
+    }
+    let end = buf.len().min(MAX_CHUNK_LEN);
+    let mut h = 0_u64;
+    for (i, b) in buf[MIN_CHUNK_LEN..end].iter().enumerate() {
+        h = (h << 1).wrapping_add(GEAR[*b as usize]);
+        if h & MASK == 0 {
+            return MIN_CHUNK_LEN + i + 1;
+        }
+    }
+    end
+}
+
+fn chunk_hash(chunk: &[u8]) -> [u8; CHUNK_HASH_LEN] {
+    let mut hash = [0_u8; CHUNK_HASH_LEN];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, chunk).as_ref());
+    hash
+}
+
+/// cut the content of `reader` into chunks, `hash` gets the content as it is read
+pub(crate) fn split<R: Read>(mut reader: R, hash: &mut ContentHash) -> Result<Vec<ChunkInfo>> {
+    let mut chunks = Vec::new();
+    let mut buf = Vec::with_capacity(2 * MAX_CHUNK_LEN);
+    let mut eof = false;
+    loop {
+        while !eof && buf.len() < MAX_CHUNK_LEN {
+            let start = buf.len();
+            buf.resize(2 * MAX_CHUNK_LEN, 0);
+            let len = reader.read(&mut buf[start..])?;
+            buf.truncate(start + len);
+            hash.update(&buf[start..]);
+            eof = len == 0;
+        }
+        if buf.is_empty() {
+            return Ok(chunks);
+        }
+        let len = cut(&buf);
+        chunks.push(ChunkInfo {
+            hash: chunk_hash(&buf[..len]),
+            len: len as u32,
+        });
+        buf.drain(..len);
+    }
+}
+
+/// the content of the chunks the receiver wants, read again from the file they were cut from
+pub(crate) struct Wanted {
+    file: File,
+    /// parts of the file left to send, adjacent chunks together
+    ranges: VecDeque<(u64, u64)>,
+    size: u64,
+    position: u64,
+    hash: ContentHash,
+}
+
+impl Wanted {
+    /// `hash` is the one of the whole content, given by `split`
+    pub(crate) fn new(file: File, chunks: &[ChunkInfo], want: &[bool], hash: ContentHash) -> Self {
+        let mut ranges: VecDeque<(u64, u64)> = VecDeque::new();
+        let mut offset = 0;
+        for (chunk, wanted) in chunks.iter().zip(want) {
+            let end = offset + u64::from(chunk.len);
+            if *wanted {
+                match ranges.back_mut() {
+                    Some(range) if range.1 == offset => range.1 = end,
+                    _ => ranges.push_back((offset, end)),
+                }
+            }
+            offset = end;
+        }
+        Wanted {
+            file,
+            ranges,
+            size: offset,
+            position: 0,
+            hash,
+        }
+    }
+
+    /// the next data to send, None once every wanted chunk is sent
+    pub(crate) fn next_data(&mut self) -> Result<Option<Vec<u8>>> {
+        let range = match self.ranges.front_mut() {
+            Some(range) => range,
+            None => {
+                self.position = self.size;
+                return Ok(None);
+            }
+        };
+        let len = (range.1 - range.0).min(FILE_DATA_LEN as u64);
+        let mut data = vec![0_u8; len as usize];
+        self.file.seek(SeekFrom::Start(range.0))?;
+        self.file.read_exact(&mut data)?;
+        range.0 += len;
+        self.position = range.0;
+        if range.0 == range.1 {
+            self.ranges.pop_front();
+        }
+        Ok(Some(data))
+    }
+
+    /// how far in the content the sent data goes
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub(crate) fn finish(self) -> ContentHash {
+        self.hash
+    }
+}
+
+/// where a chunk is found
+#[derive(Debug, Clone)]
+struct Location {
+    path: Arc<PathBuf>,
+    offset: u64,
+    len: u32,
+}
+
+/// a line of the index file
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    hash: String,
+    path: PathBuf,
+    offset: u64,
+    len: u32,
+}
+
+/// the chunks of the files received so far, kept in a JSON-lines file; a chunk is read again and
+/// checked against its hash before it is used, the files may have changed since
+pub(crate) struct ChunkIndex {
+    path: PathBuf,
+    chunks: Mutex<HashMap<[u8; CHUNK_HASH_LEN], Location>>,
+}
+
+impl ChunkIndex {
+    /// load the index file at `path`, empty when it does not exist yet
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let mut chunks = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match parse_entry(&line) {
+                        Ok((hash, location)) => {
+                            chunks.insert(hash, location);
+                        }
+                        Err(e) => debug!("invalid chunk index entry {:?}: {}", line, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(ChunkIndex {
+            path: path.to_path_buf(),
+            chunks: Mutex::new(chunks),
+        })
+    }
+
+    /// the content of `chunk`, None when it is not in the index or its file changed
+    pub(crate) fn read(&self, chunk: &ChunkInfo) -> Option<Vec<u8>> {
+        let location = self.chunks.lock().unwrap().get(&chunk.hash).cloned()?;
+        if location.len != chunk.len {
+            return None;
+        }
+        let res = File::open(location.path.as_path()).and_then(|mut file| {
+            file.seek(SeekFrom::Start(location.offset))?;
+            let mut data = vec![0_u8; chunk.len as usize];
+            file.read_exact(&mut data)?;
+            Ok(data)
+        });
+        match res {
+            Ok(data) if chunk_hash(&data) == chunk.hash => Some(data),
+            _ => {
+                debug!("chunk no longer in {:?}", location.path);
+                self.chunks.lock().unwrap().remove(&chunk.hash);
+                None
+            }
+        }
+    }
+
+    /// record the chunks of the file at `path`, which follow each other from its start
+    pub(crate) fn add(&self, path: &Path, chunks: &[ChunkInfo]) -> Result<()> {
+        let path = Arc::new(fs::canonicalize(path)?);
+        let mut lines = Vec::new();
+        let mut added = HashMap::new();
+        let mut offset = 0;
+        for chunk in chunks {
+            let location = Location {
+                path: path.clone(),
+                offset,
+                len: chunk.len,
+            };
+            offset += u64::from(chunk.len);
+            if added.insert(chunk.hash, location).is_some() {
+                continue;
+            }
+            let entry = Entry {
+                hash: to_hex(&chunk.hash),
+                path: path.to_path_buf(),
+                offset: offset - u64::from(chunk.len),
+                len: chunk.len,
+            };
+            serde_json::to_writer(&mut lines, &entry)?;
+            lines.push(b'\n');
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&lines)?;
+        let mut map = self.chunks.lock().unwrap();
+        for (hash, location) in added {
+            map.insert(hash, location);
+        }
+        Ok(())
+    }
+}
+
+/// a file rebuilt from the chunks of the index and the ones sent, in their order
+pub(crate) struct Assembly {
+    index: Arc<ChunkIndex>,
+    chunks: Vec<ChunkInfo>,
+    want: Vec<bool>,
+    /// the chunk being written
+    next: usize,
+    /// the hash and length of the data received of a wanted chunk
+    ctx: digest::Context,
+    filled: usize,
+}
+
+impl Assembly {
+    /// the chunks missing from the index are wanted
+    pub(crate) fn new(index: Arc<ChunkIndex>, chunks: Vec<ChunkInfo>) -> Self {
+        let want = chunks.iter().map(|c| index.read(c).is_none()).collect();
+        Assembly {
+            index,
+            chunks,
+            want,
+            next: 0,
+            ctx: digest::Context::new(&digest::SHA256),
+            filled: 0,
+        }
+    }
+
+    pub(crate) fn want(&self) -> &[bool] {
+        &self.want
+    }
+
+    pub(crate) fn into_chunks(self) -> Vec<ChunkInfo> {
+        self.chunks
+    }
+
+    /// give the chunks of the index up to the next wanted one to `write`
+    pub(crate) fn fill<F>(&mut self, mut write: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        while self.next < self.chunks.len() && !self.want[self.next] {
+            let data = self
+                .index
+                .read(&self.chunks[self.next])
+                .ok_or_else(|| Error::other("a chunk changed during the transfer"))?;
+            write(&data)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    /// give `data`, the content of the wanted chunks, to `write` along with the chunks of the
+    /// index after them, a wanted chunk is checked against its hash once complete
+    pub(crate) fn data<F>(&mut self, mut data: &[u8], mut write: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        while !data.is_empty() {
+            let chunk = match self.chunks.get(self.next) {
+                Some(chunk) => *chunk,
+                None => return Err(Error::new(ErrorKind::InvalidData, "data after the chunks")),
+            };
+            let len = (chunk.len as usize - self.filled).min(data.len());
+            self.ctx.update(&data[..len]);
+            write(&data[..len])?;
+            self.filled += len;
+            data = &data[len..];
+            if self.filled == chunk.len as usize {
+                let ctx = std::mem::replace(&mut self.ctx, digest::Context::new(&digest::SHA256));
+                if ctx.finish().as_ref() != chunk.hash {
+                    return Err(Integrity::error(String::from(
+                        "chunk does not match its hash",
+                    )));
+                }
+                self.filled = 0;
+                self.next += 1;
+                self.fill(&mut write)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_entry(line: &str) -> Result<([u8; CHUNK_HASH_LEN], Location)> {
+    let entry: Entry = serde_json::from_str(line)?;
+    let location = Location {
+        path: Arc::new(entry.path),
+        offset: entry.offset,
+        len: entry.len,
+    };
+    Ok((from_hex(&entry.hash)?, location))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<[u8; CHUNK_HASH_LEN]> {
+    let mut bytes = [0_u8; CHUNK_HASH_LEN];
+    if hex.len() != 2 * CHUNK_HASH_LEN || !hex.is_ascii() {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid hash"));
+    }
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid hash"))?;
+    }
+    Ok(bytes)
+}
@@ -1,12 +1,23 @@
+use crate::cancel::CancelHandle;
+use crate::chunks::{self, Wanted};
+use crate::delta::{Encoder, Op};
+use crate::error::Cancelled;
+use crate::observer::{Event, Observer};
+use crate::packet::cancel::{CancelData, CancelScope};
+use crate::packet::delta::SignatureData;
 use crate::packet::file_info::FileInfo;
 use crate::packet::start_file::StartFileData;
-use crate::packet::Packet;
+use crate::packet::{Encoding, Packet, FILE_DATA_LEN, MAX_ENTRIES};
+use crate::source::{ContentHash, SourceFile};
+use crate::sparse::{Piece, SparseReader};
 use crate::streamer::Streamer;
-use std::path::PathBuf;
+use crate::transport::{Probe, RawSocket};
+use crate::zerocopy::ZeroCopy;
 use std::{
+    collections::HashMap,
     fs::File,
-    io::{BufRead, BufReader, Read, Write},
-    usize,
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write},
+    sync::Arc,
 };
 
 enum ClientState {
@@ -20,37 +31,175 @@ enum ClientState {
     Error,
 }
 
+/// how a session ended when the state machine did not fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOutcome {
+    Finished,
+    Rejected,
+}
+
 pub struct ClientStateMachine<S>
 where
     S: Read + Write,
 {
     state: ClientState,
     str: Streamer<S>,
-    items: Vec<PathBuf>,
-    opt_reader: Option<BufReader<File>>,
+    items: Vec<SourceFile>,
+    opt_reader: Option<BufReader<Box<dyn Read>>>,
+    opt_info: Option<FileInfo>,
+    opt_hash: Option<ContentHash>,
+    /// the current file when it is sent as a delta of the receiver's copy
+    opt_delta: Option<Encoder>,
+    /// signatures of the copies the receiver has, by index
+    signatures: HashMap<usize, SignatureData>,
+    /// the current file when only the chunks the receiver wants are sent
+    opt_chunks: Option<Wanted>,
+    /// the current file when it has holes, which are not read
+    opt_sparse: Option<SparseReader>,
+    /// the socket the stream writes to as it is, for the kernel to copy file data to it
+    opt_socket: Option<RawSocket>,
+    /// the current file when the kernel copies its data to the socket
+    opt_zero_copy: Option<ZeroCopy>,
+    /// tells when the receiver wrote, to notice the files it cancels
+    opt_probe: Option<Probe>,
+    dedup: bool,
+    /// the receiver sent Dedup
+    peer_dedup: bool,
     sent_size: usize,
     cur_index: usize,
+    finished: Vec<bool>,
+    offsets: Vec<Option<u64>>,
+    resume: bool,
+    checksum: bool,
+    skipped: usize,
+    stdin_consumed: bool,
+    opt_error: Option<Error>,
+    opt_observer: Option<Arc<dyn Observer>>,
+    cancel: CancelHandle,
+    outcome: ClientOutcome,
 }
 
 impl<S> ClientStateMachine<S>
 where
     S: Read + Write,
 {
-    pub fn new(s: S, items: &[PathBuf]) -> Self {
+    pub fn new(s: S, items: &[SourceFile]) -> Self {
         ClientStateMachine {
             state: ClientState::Init,
             str: Streamer::new(s),
             items: items.to_vec(),
             opt_reader: None,
+            opt_info: None,
+            opt_hash: None,
+            opt_delta: None,
+            signatures: HashMap::new(),
+            opt_chunks: None,
+            opt_sparse: None,
+            opt_socket: None,
+            opt_zero_copy: None,
+            opt_probe: None,
+            dedup: false,
+            peer_dedup: false,
             sent_size: 0,
             cur_index: 0,
+            finished: vec![false; items.len()],
+            offsets: vec![Some(0); items.len()],
+            resume: false,
+            checksum: false,
+            skipped: 0,
+            stdin_consumed: false,
+            opt_error: None,
+            opt_observer: None,
+            cancel: CancelHandle::new(),
+            outcome: ClientOutcome::Finished,
+        }
+    }
+
+    /// continue an interrupted session: `finished` marks the files whose EndFile was already sent,
+    /// the receiver is asked how much of each file it kept before anything is sent
+    pub fn resume(s: S, items: &[SourceFile], finished: &[bool]) -> Self {
+        let mut sm = Self::new(s, items);
+        sm.finished = finished.to_vec();
+        sm.resume = true;
+        sm
+    }
+
+    pub fn with_observer(mut self, opt_observer: Option<Arc<dyn Observer>>) -> Self {
+        self.opt_observer = opt_observer;
+        self
+    }
+
+    /// stop sending when `cancel` asks for it
+    pub fn with_cancel(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// put the SHA-256 of the files in the request, the receiver then compares the content of
+    /// the files it already has and not only their size and modification time
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// announce the chunks of the files to a receiver keeping an index of chunks, and only send
+    /// the ones it does not have
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// `opt_socket` is the socket under `s`, plaintext and unbuffered: the kernel then copies
+    /// the files read as they are to it with `sendfile(2)`, on Linux
+    pub fn with_socket(mut self, opt_socket: Option<RawSocket>) -> Self {
+        self.opt_socket = opt_socket;
+        self
+    }
+
+    /// skip the rest of the files the receiver cancels, checked with `opt_probe` between the
+    /// packets of a file; without it the receiver drops them until EndFile
+    pub fn with_probe(mut self, opt_probe: Option<Probe>) -> Self {
+        self.opt_probe = opt_probe;
+        self
+    }
+
+    /// start without Hello and speak version 1, for a receiver predating the negotiation
+    pub fn with_legacy(mut self, legacy: bool) -> Self {
+        if legacy {
+            self.str.set_encoding(Encoding::Json);
         }
+        self
+    }
+
+    /// take over a session whose encoding is already negotiated
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.str.set_encoding(encoding);
+        self
     }
 
     /// start the state machine
-    pub fn start(&mut self) {
+    pub fn start(&mut self) -> Result<ClientOutcome> {
         self.state = ClientState::Init;
-        self.next()
+        self.next();
+        match self.opt_error.take() {
+            Some(err) => Err(err),
+            None => Ok(self.outcome),
+        }
+    }
+
+    /// files completely sent so far, including the ones sent by previous attempts
+    pub fn finished(&self) -> &[bool] {
+        &self.finished
+    }
+
+    /// files not sent because the receiver already has them
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// the standard input started to be sent, it cannot be sent again
+    pub fn stdin_consumed(&self) -> bool {
+        self.stdin_consumed
     }
 
     /// state machine
@@ -58,37 +207,104 @@ where
         loop {
             match self.state {
                 ClientState::Init => {
-                    let infos: Vec<FileInfo> =
-                        self.items.iter().map(|p| FileInfo::from_path(p)).collect();
-                    match self.str.write_packet(Packet::Send(infos)) {
+                    let checksum = self.checksum;
+                    let infos = match self
+                        .items
+                        .iter()
+                        .map(|item| item.request_info(checksum))
+                        .collect::<Result<Vec<FileInfo>>>()
+                    {
+                        Ok(infos) => infos,
+                        Err(e) => {
+                            self.error(e);
+                            continue;
+                        }
+                    };
+                    self.notify(Event::Requested {
+                        files: infos.clone(),
+                    });
+                    let packet = if self.resume {
+                        Packet::Resume(infos)
+                    } else {
+                        Packet::Send(infos)
+                    };
+                    match self.write(packet) {
                         Ok(_) => self.state = ClientState::WaitForResponse,
-                        _ => self.error(),
+                        Err(e) => self.error(e),
                     }
                 }
                 ClientState::WaitForResponse => match self.str.read_packet() {
-                    Ok(Packet::Accept) => self.state = ClientState::Accepted,
-                    Ok(Packet::Reject) => self.state = ClientState::Finish,
-                    _ => self.error(),
+                    Ok(Packet::Accept) => {
+                        self.notify(Event::Accepted);
+                        self.state = ClientState::Accepted
+                    }
+                    Ok(Packet::ResumeAt(offsets)) => {
+                        self.notify(Event::Accepted);
+                        self.process_resume_at(offsets)
+                    }
+                    Ok(Packet::Have(have)) => {
+                        self.notify(Event::Accepted);
+                        self.process_have(have)
+                    }
+                    Ok(Packet::Signature(data)) => self.process_signature(data),
+                    Ok(Packet::Dedup) => self.peer_dedup = true,
+                    Ok(Packet::Reject) => {
+                        self.notify(Event::Rejected);
+                        self.outcome = ClientOutcome::Rejected;
+                        self.state = ClientState::Finish
+                    }
+                    Ok(Packet::Cancel(data)) => self.process_cancelled(data),
+                    Ok(_) => self.unexpected_packet(),
+                    Err(e) => self.error(e),
                 },
-                ClientState::Accepted => self.process_start_file(),
-                ClientState::StartSendingFile => {
-                    self.process_file_data();
+                ClientState::Accepted
+                | ClientState::StartSendingFile
+                | ClientState::SendFileData
+                    if self.cancel.is_cancelled() =>
+                {
+                    self.process_cancel()
                 }
-                ClientState::SendFileData => {
-                    self.process_file_data();
+                ClientState::Accepted => match self.next_pending(0) {
+                    Some(index) => {
+                        self.cur_index = index;
+                        self.process_start_file()
+                    }
+                    None => self.process_finish(),
+                },
+                ClientState::StartSendingFile | ClientState::SendFileData => {
+                    if let Some(reason) = self.cancel.take_file_reason() {
+                        self.process_cancel_file(&reason);
+                        continue;
+                    }
+                    match self.probe() {
+                        Ok(false) => self.process_file_data(),
+                        Ok(true) => match self.str.read_packet() {
+                            Ok(Packet::Cancel(data)) if data.scope == CancelScope::File => {
+                                self.process_file_cancelled(data)
+                            }
+                            Ok(Packet::Cancel(data)) => self.process_cancelled(data),
+                            Ok(_) => self.unexpected_packet(),
+                            Err(e) => self.error(e),
+                        },
+                        Err(e) => self.error(e),
+                    }
                 }
                 ClientState::EndSendingFile => {
-                    let fin = self.cur_index >= self.total() - 1;
-                    if !fin {
-                        // increase current and continue
-                        self.cur_index += 1;
-                        self.process_start_file()
-                    } else {
-                        // finish
-                        match self.str.write_packet(Packet::Finish) {
-                            Ok(_) => self.state = ClientState::Finish,
-                            Err(_) => self.error(),
+                    self.finished[self.cur_index] = true;
+                    if let Some(mut file) = self.opt_info.take() {
+                        file.hash = self.opt_hash.take().map(ContentHash::finish);
+                        self.notify(Event::FileCompleted {
+                            index: self.cur_index,
+                            file,
+                        });
+                    }
+                    match self.next_pending(self.cur_index + 1) {
+                        Some(index) => {
+                            // move to next file and continue
+                            self.cur_index = index;
+                            self.process_start_file()
                         }
+                        None => self.process_finish(),
                     }
                 }
                 ClientState::Finish => break,
@@ -103,44 +319,231 @@ where
         self.items.len()
     }
 
+    /// index of the next file to send, starting from `from`
+    fn next_pending(&self, from: usize) -> Option<usize> {
+        (from..self.total()).find(|i| self.offsets[*i].is_some())
+    }
+
+    /// decide where to continue each file from the sizes kept by the receiver
+    fn process_resume_at(&mut self, kept: Vec<Option<u64>>) {
+        match resume_offsets(&self.items, &kept, &self.finished) {
+            Ok(offsets) => {
+                self.offsets = offsets;
+                self.state = ClientState::Accepted
+            }
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// leave out the files the receiver already has, they count as sent
+    fn process_have(&mut self, have: Vec<bool>) {
+        let offsets = match have_offsets(&self.items, &have) {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                self.error(e);
+                return;
+            }
+        };
+        for index in (0..self.total()).filter(|i| have[*i]) {
+            match self.items[index].info() {
+                Ok(file) => self.notify(Event::Skipped { index, file }),
+                Err(e) => {
+                    self.error(e);
+                    return;
+                }
+            }
+            self.finished[index] = true;
+            self.skipped += 1;
+        }
+        self.offsets = offsets;
+        self.state = ClientState::Accepted
+    }
+
+    /// keep the signature of a copy the receiver has, the answer follows
+    fn process_signature(&mut self, data: SignatureData) {
+        match self.items.get(data.index) {
+            Some(item) if !item.is_stdin() => {
+                self.signatures.insert(data.index, data);
+            }
+            _ => self.unexpected_packet(),
+        }
+    }
+
     fn process_start_file(&mut self) {
-        match self.items.get(self.cur_index) {
+        let offset = self.offsets[self.cur_index].unwrap_or(0);
+        match self.items.get(self.cur_index).cloned() {
             Some(item) => {
                 // read file
-                match File::open(item) {
-                    Ok(file) => {
-                        self.opt_reader = Some(BufReader::with_capacity(61 * 1024, file));
+                let file_info = match item.info() {
+                    Ok(info) => info,
+                    Err(e) => {
+                        self.error(e);
+                        return;
+                    }
+                };
+                self.stdin_consumed |= item.is_stdin();
+                match ContentHash::resume(&item.path, offset) {
+                    Ok(hash) => self.opt_hash = Some(hash),
+                    Err(e) => {
+                        self.error(e);
+                        return;
+                    }
+                }
+                let opt_signature = self.signatures.remove(&self.cur_index);
+                // a file the receiver has a copy of is sent as a delta instead
+                let dedup = self.dedup
+                    && self.peer_dedup
+                    && offset == 0
+                    && !item.is_stdin()
+                    && opt_signature.is_none();
+                match (item.open_at(offset), opt_signature) {
+                    (Ok(file), Some(signature)) if offset == 0 => {
+                        let hash = self.opt_hash.take().unwrap_or_else(ContentHash::new);
+                        self.opt_reader = None;
+                        self.opt_delta = Some(Encoder::new(file, &signature, hash));
                         self.sent_size = 0;
                     }
-                    Err(_) => {
-                        self.error();
+                    (Ok(file), _) => {
+                        self.opt_reader = Some(BufReader::with_capacity(FILE_DATA_LEN, file));
+                        self.sent_size = offset as usize;
+                        let plain = !item.is_stdin() && !dedup;
+                        // holes are only known to version 2, the chunks of a file cover them
+                        if self.str.encoding().version() >= 2 && plain {
+                            match SparseReader::open(&item.path, offset) {
+                                Ok(Some(reader)) => {
+                                    self.opt_reader = None;
+                                    self.opt_sparse = Some(reader);
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    self.error(e);
+                                    return;
+                                }
+                            }
+                        }
+                        match self.opt_socket {
+                            Some(socket) if plain && self.opt_sparse.is_none() => {
+                                let hash = self.opt_hash.take().unwrap_or_else(ContentHash::new);
+                                match ZeroCopy::open(socket, &item.path, offset, hash) {
+                                    Ok(Some(zero_copy)) => {
+                                        self.opt_reader = None;
+                                        self.opt_zero_copy = Some(zero_copy);
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        self.error(e);
+                                        return;
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    (Err(e), _) => {
+                        self.error(e);
                         return;
                     }
                 }
 
                 // send packet to server
-                let data =
-                    StartFileData::new(FileInfo::from_path(item), self.cur_index, self.items.len());
-                match self.str.write_packet(Packet::StartFile(data)) {
+                self.opt_info = Some(file_info.clone());
+                self.notify(Event::FileStarted {
+                    index: self.cur_index,
+                    file: file_info.clone(),
+                });
+                let data = StartFileData::new(file_info, self.cur_index, self.items.len(), offset);
+                match self.write(Packet::StartFile(data)) {
+                    Ok(_) if dedup => self.process_chunks(&item),
                     Ok(_) => self.state = ClientState::StartSendingFile,
-                    Err(_) => self.error(),
+                    Err(e) => self.error(e),
                 }
             }
-            None => self.error(),
+            None => self.unexpected_packet(),
+        }
+    }
+
+    /// announce the chunks of the current file, the receiver answers with the ones it wants
+    fn process_chunks(&mut self, item: &SourceFile) {
+        let (reader, mut hash) = match (self.opt_reader.take(), self.opt_hash.take()) {
+            (Some(reader), Some(hash)) => (reader, hash),
+            _ => return self.unexpected_packet(),
+        };
+        let chunks = match chunks::split(reader, &mut hash) {
+            Ok(chunks) => chunks,
+            Err(e) => return self.error(e),
+        };
+        let file = match File::open(&item.path) {
+            Ok(file) => file,
+            Err(e) => return self.error(e),
+        };
+        if chunks.len() > MAX_ENTRIES {
+            // too many to announce, the file is sent whole
+            self.opt_reader = Some(BufReader::with_capacity(FILE_DATA_LEN, Box::new(file)));
+            self.opt_hash = Some(ContentHash::new());
+            self.state = ClientState::StartSendingFile;
+            return;
+        }
+        if let Err(e) = self.write(Packet::Chunks(chunks.clone())) {
+            return self.error(e);
+        }
+        let mut opt_cancelled = None;
+        let want = loop {
+            match self.str.read_packet() {
+                Ok(Packet::Want(want)) if want.len() == chunks.len() => break want,
+                // the receiver still answers the chunks of a file it cancels
+                Ok(Packet::Cancel(data)) if data.scope == CancelScope::File => {
+                    opt_cancelled = Some(data)
+                }
+                Ok(Packet::Cancel(data)) => return self.process_cancelled(data),
+                Ok(_) => return self.unexpected_packet(),
+                Err(e) => return self.error(e),
+            }
+        };
+        self.opt_chunks = Some(Wanted::new(file, &chunks, &want, hash));
+        self.state = ClientState::StartSendingFile;
+        if let Some(data) = opt_cancelled {
+            self.process_file_cancelled(data)
         }
     }
 
     fn process_file_data(&mut self) {
+        if self.opt_delta.is_some() {
+            return self.process_delta();
+        }
+        if self.opt_chunks.is_some() {
+            return self.process_wanted();
+        }
+        if self.opt_sparse.is_some() {
+            return self.process_sparse();
+        }
+        if self.opt_zero_copy.is_some() {
+            return self.process_zero_copy();
+        }
         if let Some(reader) = self.opt_reader.as_mut() {
-            let buf = reader.fill_buf().unwrap();
+            let buf = match reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) => {
+                    self.error(e);
+                    return;
+                }
+            };
             let len = buf.len();
             if len > 0 {
-                let vec: Vec<u8> = buf.iter().copied().collect();
+                let vec: Vec<u8> = buf.to_vec();
                 reader.consume(len);
+                if let Some(hash) = self.opt_hash.as_mut() {
+                    hash.update(&vec);
+                }
                 self.sent_size += len;
-                match self.str.write_packet(Packet::FileData(vec)) {
-                    Ok(_) => self.state = ClientState::SendFileData,
-                    Err(_) => self.error(),
+                match self.write(Packet::FileData(vec)) {
+                    Ok(_) => {
+                        self.notify(Event::Progress {
+                            index: self.cur_index,
+                            bytes: self.sent_size as u64,
+                        });
+                        self.state = ClientState::SendFileData
+                    }
+                    Err(e) => self.error(e),
                 };
             } else {
                 self.process_end_file();
@@ -148,14 +551,274 @@ where
         }
     }
 
+    /// send the next piece of a file as a delta: blocks of the receiver's copy or new data
+    fn process_delta(&mut self) {
+        let res = match self.opt_delta.as_mut() {
+            Some(encoder) => encoder.next_op(),
+            None => return,
+        };
+        let packet = match res {
+            Ok(Some(Op::Data(data))) => Packet::FileData(data),
+            Ok(Some(Op::Copy(data))) => Packet::Copy(data),
+            Ok(None) => {
+                self.opt_hash = self.opt_delta.take().map(Encoder::finish);
+                return self.process_end_file();
+            }
+            Err(e) => return self.error(e),
+        };
+        if let Some(encoder) = self.opt_delta.as_ref() {
+            self.sent_size = encoder.encoded() as usize;
+        }
+        self.send_piece(packet)
+    }
+
+    /// send the next data of the chunks the receiver wants
+    fn process_wanted(&mut self) {
+        let res = match self.opt_chunks.as_mut() {
+            Some(wanted) => wanted.next_data(),
+            None => return,
+        };
+        let data = match res {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                self.opt_hash = self.opt_chunks.take().map(Wanted::finish);
+                return self.process_end_file();
+            }
+            Err(e) => return self.error(e),
+        };
+        if let Some(wanted) = self.opt_chunks.as_ref() {
+            self.sent_size = wanted.position() as usize;
+        }
+        self.send_piece(Packet::FileData(data))
+    }
+
+    /// send the next data of a sparse file, or the next hole
+    fn process_sparse(&mut self) {
+        let res = match self.opt_sparse.as_mut() {
+            Some(reader) => reader.next_piece(),
+            None => return,
+        };
+        let packet = match res {
+            Ok(Some(Piece::Data(data))) => {
+                if let Some(hash) = self.opt_hash.as_mut() {
+                    hash.update(&data);
+                }
+                Packet::FileData(data)
+            }
+            Ok(Some(Piece::Hole(hole))) => {
+                if let Some(hash) = self.opt_hash.as_mut() {
+                    hash.update_zeros(hole.len);
+                }
+                Packet::Hole(hole)
+            }
+            Ok(None) => {
+                self.opt_sparse = None;
+                return self.process_end_file();
+            }
+            Err(e) => return self.error(e),
+        };
+        if let Some(reader) = self.opt_sparse.as_ref() {
+            self.sent_size = reader.position() as usize;
+        }
+        self.send_piece(packet)
+    }
+
+    /// let the kernel write the next data of the current file to the socket
+    fn process_zero_copy(&mut self) {
+        let res = match self.opt_zero_copy.as_mut() {
+            Some(zero_copy) => zero_copy.next_data(),
+            None => return,
+        };
+        match res {
+            Ok(true) => {}
+            Ok(false) => {
+                self.opt_hash = self.opt_zero_copy.take().map(ZeroCopy::into_hash);
+                return self.process_end_file();
+            }
+            Err(e) => {
+                let err = self.explain(e);
+                return self.error(err);
+            }
+        }
+        if let Some(zero_copy) = self.opt_zero_copy.as_ref() {
+            self.sent_size = zero_copy.position() as usize;
+        }
+        self.sent_piece()
+    }
+
+    /// a piece of the current file which goes up to `sent_size` in its content
+    fn send_piece(&mut self, packet: Packet) {
+        match self.write(packet) {
+            Ok(_) => self.sent_piece(),
+            Err(e) => self.error(e),
+        }
+    }
+
+    fn sent_piece(&mut self) {
+        self.notify(Event::Progress {
+            index: self.cur_index,
+            bytes: self.sent_size as u64,
+        });
+        self.state = ClientState::SendFileData
+    }
+
     fn process_end_file(&mut self) {
-        match self.str.write_packet(Packet::EndFile) {
+        match self.write(Packet::EndFile) {
             Ok(_) => self.state = ClientState::EndSendingFile,
-            Err(_) => self.error(),
+            Err(e) => self.error(e),
+        }
+    }
+
+    fn process_finish(&mut self) {
+        if let Err(e) = self.write(Packet::Finish) {
+            return self.error(e);
+        }
+        // from version 3 the receiver answers, after the Cancel of a file it got whole meanwhile
+        while self.str.encoding().version() >= 3 {
+            match self.str.read_packet() {
+                Ok(Packet::Finish) => break,
+                Ok(Packet::Cancel(data)) if data.scope == CancelScope::File => {}
+                Ok(Packet::Cancel(data)) => return self.process_cancelled(data),
+                Ok(_) => return self.unexpected_packet(),
+                Err(e) => return self.error(e),
+            }
+        }
+        self.notify(Event::Finished);
+        self.state = ClientState::Finish
+    }
+
+    /// tell the receiver to stop the session
+    fn process_cancel(&mut self) {
+        let reason = self.cancel.session_reason().unwrap_or_default();
+        self.opt_reader = None;
+        self.opt_delta = None;
+        self.opt_chunks = None;
+        self.opt_sparse = None;
+        self.opt_zero_copy = None;
+        self.notify(Event::Cancelled {
+            index: None,
+            reason: reason.clone(),
+            by_peer: false,
+        });
+        let data = CancelData::new(CancelScope::Session, &reason);
+        match self.write(Packet::Cancel(data)) {
+            Ok(_) => self.error(Cancelled::error(&reason, false)),
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// tell the receiver to drop the current file, then go on with the next one
+    fn process_cancel_file(&mut self, reason: &str) {
+        self.drop_current();
+        self.notify(Event::Cancelled {
+            index: Some(self.cur_index),
+            reason: reason.to_string(),
+            by_peer: false,
+        });
+        match self.write(Packet::Cancel(CancelData::new(CancelScope::File, reason))) {
+            Ok(_) => self.skip_current(),
+            Err(e) => self.error(e),
         }
     }
 
-    fn error(&mut self) {
+    /// the receiver dropped a file, the current one unless it got it whole meanwhile: the rest
+    /// of the current file is not sent, EndFile tells the receiver where the next one starts
+    fn process_file_cancelled(&mut self, data: CancelData) {
+        if data.index.is_some_and(|index| index != self.cur_index) {
+            return;
+        }
+        self.drop_current();
+        self.notify(Event::Cancelled {
+            index: Some(self.cur_index),
+            reason: data.reason,
+            by_peer: true,
+        });
+        match self.write(Packet::EndFile) {
+            Ok(_) => self.skip_current(),
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// forget the current file without finishing it
+    fn drop_current(&mut self) {
+        self.opt_reader = None;
+        self.opt_delta = None;
+        self.opt_chunks = None;
+        self.opt_sparse = None;
+        self.opt_zero_copy = None;
+        self.opt_info = None;
+        self.opt_hash = None;
+    }
+
+    /// go on with the file after the current one
+    fn skip_current(&mut self) {
+        match self.next_pending(self.cur_index + 1) {
+            Some(index) => {
+                self.cur_index = index;
+                self.process_start_file()
+            }
+            None => self.process_finish(),
+        }
+    }
+
+    /// whether the receiver wrote something to read before sending more
+    fn probe(&mut self) -> Result<bool> {
+        match self.opt_probe.as_mut() {
+            Some(probe) => probe(),
+            None => Ok(false),
+        }
+    }
+
+    /// the receiver stopped the session
+    fn process_cancelled(&mut self, data: CancelData) {
+        if data.scope == CancelScope::Session {
+            self.notify(Event::Cancelled {
+                index: None,
+                reason: data.reason.clone(),
+                by_peer: true,
+            });
+        }
+        self.error(data.peer_error())
+    }
+
+    fn write(&mut self, packet: Packet) -> Result<usize> {
+        self.str.write_packet(packet).map_err(|e| self.explain(e))
+    }
+
+    /// a receiver cancelling the session sends Cancel and hangs up, so a write failing because
+    /// the connection is gone may have a Cancel waiting to be read that explains it; after a
+    /// local error the receiver is still waiting, nothing is read
+    fn explain(&mut self, err: Error) -> Error {
+        if !hung_up(&err) {
+            return err;
+        }
+        match self.str.read_packet() {
+            Ok(Packet::Cancel(data)) if data.scope != CancelScope::File => {
+                if data.scope == CancelScope::Session {
+                    self.notify(Event::Cancelled {
+                        index: None,
+                        reason: data.reason.clone(),
+                        by_peer: true,
+                    });
+                }
+                data.peer_error()
+            }
+            _ => err,
+        }
+    }
+
+    fn notify(&self, event: Event) {
+        if let Some(observer) = self.opt_observer.as_ref() {
+            observer.on_event(&event)
+        }
+    }
+
+    fn unexpected_packet(&mut self) {
+        self.error(Error::new(ErrorKind::InvalidData, "unexpected packet"))
+    }
+
+    fn error(&mut self, err: Error) {
+        self.opt_error = Some(err);
         self.state = ClientState::Error
     }
 
@@ -163,3 +826,44 @@ where
         //TODO shutdown
     }
 }
+
+/// the write failed because the receiver hung up, rather than because of this side
+pub(crate) fn hung_up(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+/// where to start each file after Have, None to skip the file
+pub(crate) fn have_offsets(items: &[SourceFile], have: &[bool]) -> Result<Vec<Option<u64>>> {
+    if have.len() != items.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "unexpected packet"));
+    }
+    Ok(have
+        .iter()
+        .map(|h| if *h { None } else { Some(0) })
+        .collect())
+}
+
+/// where to continue each file from the sizes kept by the receiver, None to skip the file
+pub(crate) fn resume_offsets(
+    items: &[SourceFile],
+    kept: &[Option<u64>],
+    finished: &[bool],
+) -> Result<Vec<Option<u64>>> {
+    if kept.len() != items.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "unexpected packet"));
+    }
+
+    let mut offsets = Vec::with_capacity(items.len());
+    for (i, k) in kept.iter().enumerate() {
+        let size = items[i].info()?.size;
+        offsets.push(match k {
+            Some(n) if *n == size && finished[i] => None,
+            Some(n) if *n < size => Some(*n),
+            _ => Some(0),
+        });
+    }
+    Ok(offsets)
+}
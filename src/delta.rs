@@ -0,0 +1,317 @@
+//! rsync-style delta of a file the receiver has another copy of: the receiver sends the
+//! checksums of the blocks of its copy, the sender sends the blocks it finds in the new content
+//! as Copy and the rest as FileData
+
+use crate::packet::delta::{BlockSum, CopyData, SignatureData, STRONG_LEN};
+use crate::packet::{FILE_DATA_LEN, MAX_BLOCK_LEN, MAX_ENTRIES};
+use crate::source::ContentHash;
+use ring::digest;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::Path;
+
+/// smaller files are sent whole, a delta would save little
+pub(crate) const MIN_DELTA_LEN: u64 = 64 * 1024;
+
+const MIN_BLOCK_LEN: u64 = 1024;
+
+/// blocks of about the square root of the size, fewer than `MAX_ENTRIES`
+pub(crate) fn block_len(size: u64) -> Option<u32> {
+    let len = ((size as f64).sqrt() as u64)
+        .max(MIN_BLOCK_LEN)
+        .max(size.div_ceil(MAX_ENTRIES as u64));
+    u32::try_from(len).ok().filter(|len| *len <= MAX_BLOCK_LEN)
+}
+
+/// checksums of the copy at `path` of the file at `index` of a request, None when the copy is
+/// too small to be worth a delta
+pub(crate) fn signature(path: &Path, index: usize) -> Result<Option<SignatureData>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let block_len = match block_len(size) {
+        Some(len) if size >= MIN_DELTA_LEN => len,
+        _ => return Ok(None),
+    };
+    let count = size / u64::from(block_len);
+    let mut reader = BufReader::with_capacity(256 * 1024, file);
+    let mut buf = vec![0_u8; block_len as usize];
+    let mut blocks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        reader.read_exact(&mut buf)?;
+        blocks.push(BlockSum {
+            weak: Rolling::new(&buf).sum(),
+            strong: strong_sum(&buf),
+        });
+    }
+    Ok(Some(SignatureData {
+        index,
+        block_len,
+        blocks,
+    }))
+}
+
+fn strong_sum(block: &[u8]) -> [u8; STRONG_LEN] {
+    let mut strong = [0_u8; STRONG_LEN];
+    strong.copy_from_slice(&digest::digest(&digest::SHA256, block).as_ref()[..STRONG_LEN]);
+    strong
+}
+
+/// rolling checksum of rsync: `a` sums the bytes of the window and `b` sums `a` after each of
+/// them, both modulo 2^16
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let (mut a, mut b) = (0_u32, 0_u32);
+        for x in block {
+            a = a.wrapping_add(u32::from(*x));
+            b = b.wrapping_add(a);
+        }
+        Rolling {
+            a,
+            b,
+            len: block.len() as u32,
+        }
+    }
+
+    /// move the window one byte forward
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(next));
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(u32::from(out)))
+            .wrapping_add(self.a);
+    }
+
+    fn sum(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// a piece of the new content of a file
+pub(crate) enum Op {
+    Data(Vec<u8>),
+    Copy(CopyData),
+}
+
+/// the new content of a file as literal data and copies of blocks of the receiver's copy
+pub(crate) struct Encoder {
+    reader: Box<dyn Read>,
+    block_len: usize,
+    /// the blocks of each weak checksum
+    weak: HashMap<u32, Vec<u64>>,
+    strong: Vec<[u8; STRONG_LEN]>,
+    hash: ContentHash,
+    buf: Vec<u8>,
+    /// start of the literal data not sent yet
+    lit: usize,
+    /// start of the window compared to the blocks, after the literal data
+    pos: usize,
+    opt_rolling: Option<Rolling>,
+    /// blocks found last, sent once the next ones are not contiguous
+    opt_copy: Option<CopyData>,
+    out: VecDeque<Op>,
+    eof: bool,
+    done: bool,
+    encoded: u64,
+}
+
+impl Encoder {
+    /// `hash` gets the content as it is read
+    pub(crate) fn new(reader: Box<dyn Read>, signature: &SignatureData, hash: ContentHash) -> Self {
+        let mut weak: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (i, block) in signature.blocks.iter().enumerate() {
+            weak.entry(block.weak).or_default().push(i as u64);
+        }
+        Encoder {
+            reader,
+            block_len: signature.block_len as usize,
+            weak,
+            strong: signature.blocks.iter().map(|b| b.strong).collect(),
+            hash,
+            buf: Vec::new(),
+            lit: 0,
+            pos: 0,
+            opt_rolling: None,
+            opt_copy: None,
+            out: VecDeque::new(),
+            eof: false,
+            done: false,
+            encoded: 0,
+        }
+    }
+
+    /// the next piece of the content, None at its end
+    pub(crate) fn next_op(&mut self) -> Result<Option<Op>> {
+        while self.out.is_empty() && !self.done {
+            self.step()?;
+        }
+        let op = self.out.pop_front();
+        self.encoded += match op.as_ref() {
+            Some(Op::Data(data)) => data.len() as u64,
+            Some(Op::Copy(copy)) => copy.count * self.block_len as u64,
+            None => 0,
+        };
+        Ok(op)
+    }
+
+    /// length of the content given by the pieces so far
+    pub(crate) fn encoded(&self) -> u64 {
+        self.encoded
+    }
+
+    /// SHA-256 of the content, once it is all encoded
+    pub(crate) fn finish(self) -> ContentHash {
+        self.hash
+    }
+
+    fn step(&mut self) -> Result<()> {
+        if self.pos - self.lit >= FILE_DATA_LEN {
+            self.push_data(self.pos);
+            return Ok(());
+        }
+        self.fill()?;
+        if self.buf.len() - self.pos < self.block_len {
+            // the end of the content, shorter than a block
+            while self.lit < self.buf.len() {
+                self.push_data(self.buf.len().min(self.lit + FILE_DATA_LEN));
+            }
+            self.flush_copy();
+            self.done = true;
+            return Ok(());
+        }
+
+        let window = &self.buf[self.pos..self.pos + self.block_len];
+        let sum = self
+            .opt_rolling
+            .get_or_insert_with(|| Rolling::new(window))
+            .sum();
+        match self.find(sum) {
+            Some(block) => {
+                if self.lit < self.pos {
+                    self.push_data(self.pos);
+                }
+                self.push_copy(block);
+                self.pos += self.block_len;
+                self.lit = self.pos;
+                self.opt_rolling = None;
+            }
+            None => {
+                let next = self.pos + self.block_len;
+                match (self.opt_rolling.as_mut(), self.buf.get(next)) {
+                    (Some(rolling), Some(byte)) => rolling.roll(self.buf[self.pos], *byte),
+                    _ => self.opt_rolling = None,
+                }
+                self.pos += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// a block with the content of the window, preferably the one continuing the last copy
+    fn find(&self, sum: u32) -> Option<u64> {
+        let candidates = self.weak.get(&sum)?;
+        let strong = strong_sum(&self.buf[self.pos..self.pos + self.block_len]);
+        let next = self.opt_copy.map(|c| c.block + c.count);
+        candidates
+            .iter()
+            .copied()
+            .filter(|b| self.strong[*b as usize] == strong)
+            .min_by_key(|b| Some(*b) != next)
+    }
+
+    /// read until the window and the byte after it are in the buffer, or the end
+    fn fill(&mut self) -> Result<()> {
+        if self.eof || self.buf.len() > self.pos + self.block_len {
+            return Ok(());
+        }
+        // drop what was sent
+        self.buf.drain(..self.lit);
+        self.pos -= self.lit;
+        self.lit = 0;
+        while !self.eof && self.buf.len() <= self.pos + self.block_len {
+            let start = self.buf.len();
+            self.buf
+                .resize(start + self.block_len.max(FILE_DATA_LEN), 0);
+            let len = self.reader.read(&mut self.buf[start..])?;
+            self.buf.truncate(start + len);
+            self.hash.update(&self.buf[start..]);
+            self.eof = len == 0;
+        }
+        Ok(())
+    }
+
+    /// the literal data up to `end`
+    fn push_data(&mut self, end: usize) {
+        self.flush_copy();
+        self.out
+            .push_back(Op::Data(self.buf[self.lit..end].to_vec()));
+        self.lit = end;
+    }
+
+    fn push_copy(&mut self, block: u64) {
+        match self.opt_copy.as_mut() {
+            Some(copy) if copy.block + copy.count == block => copy.count += 1,
+            _ => {
+                self.flush_copy();
+                self.opt_copy = Some(CopyData { block, count: 1 });
+            }
+        }
+    }
+
+    fn flush_copy(&mut self) {
+        if let Some(copy) = self.opt_copy.take() {
+            self.out.push_back(Op::Copy(copy));
+        }
+    }
+}
+
+/// the copy a file is rebuilt from, with the checksums of its blocks sent to the sender
+pub(crate) struct Basis {
+    file: File,
+    signature: SignatureData,
+    buf: Vec<u8>,
+}
+
+impl Basis {
+    pub(crate) fn open(path: &Path, signature: SignatureData) -> Result<Self> {
+        Ok(Basis {
+            file: File::open(path)?,
+            buf: vec![0_u8; signature.block_len as usize],
+            signature,
+        })
+    }
+
+    /// give the blocks of `copy` to `write` one by one, returns their length
+    pub(crate) fn copy<F>(&mut self, copy: &CopyData, mut write: F) -> Result<u64>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        let end = match copy.block.checked_add(copy.count) {
+            Some(end) if end <= self.signature.blocks.len() as u64 => end,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid block")),
+        };
+        let block_len = u64::from(self.signature.block_len);
+        self.file.seek(SeekFrom::Start(copy.block * block_len))?;
+        for block in copy.block..end {
+            self.file.read_exact(&mut self.buf)?;
+            // the copy may have changed since its signature
+            if strong_sum(&self.buf) != self.signature.blocks[block as usize].strong {
+                return Err(Error::other(
+                    "the copy of the file changed during the transfer",
+                ));
+            }
+            write(&self.buf)?;
+        }
+        Ok(copy.count * block_len)
+    }
+}
@@ -0,0 +1,177 @@
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// UDP port the beacons are broadcast to
+pub const DISCOVERY_PORT: u16 = 7879;
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// what a receiver broadcasts about itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Beacon {
+    pub name: String,
+    pub port: u16,
+    pub fingerprint: String,
+}
+
+/// a receiver found on the local network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub fingerprint: String,
+}
+
+/// the background thread broadcasting a beacon, stopped when dropped
+pub struct Announcer {
+    /// dropped to wake the thread up and stop it
+    opt_stop: Option<Sender<()>>,
+    opt_thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.opt_stop = None;
+        if let Some(thread) = self.opt_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// broadcast the beacon periodically from a background thread, until the Announcer is dropped
+pub fn announce(beacon: Beacon) -> Result<Announcer> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))?;
+    socket.set_broadcast(true)?;
+    let data = serde_json::to_vec(&beacon).map_err(Error::from)?;
+    let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DISCOVERY_PORT);
+    info!(
+        "announcing '{}' on UDP port {}",
+        beacon.name, DISCOVERY_PORT
+    );
+
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::spawn(move || loop {
+        if let Err(e) = socket.send_to(&data, target) {
+            warn!("cannot send beacon: {}", e);
+        }
+        if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(ANNOUNCE_INTERVAL) {
+            return;
+        }
+    });
+    Ok(Announcer {
+        opt_stop: Some(stop),
+        opt_thread: Some(thread),
+    })
+}
+
+/// listen for beacons during `timeout` and return every peer heard of
+pub fn discover(timeout: Duration) -> Result<Vec<Peer>> {
+    let mut peers: Vec<Peer> = Vec::new();
+    listen(timeout, |peer| {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+        false
+    })?;
+    Ok(peers)
+}
+
+/// listen for the beacon of the peer called `name`
+pub fn resolve(name: &str, timeout: Duration) -> Result<Peer> {
+    let mut found = None;
+    listen(timeout, |peer| {
+        let is_match = peer.name == name;
+        if is_match {
+            found = Some(peer);
+        }
+        is_match
+    })?;
+    found.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("no peer named '{}' found on the local network", name),
+        )
+    })
+}
+
+/// receive beacons until `timeout` expires or `on_peer` returns true
+fn listen<F>(timeout: Duration, mut on_peer: F) -> Result<()>
+where
+    F: FnMut(Peer) -> bool,
+{
+    let socket = bind_shared(DISCOVERY_PORT)?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; 1024];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        };
+
+        match serde_json::from_slice::<Beacon>(&buf[..len]) {
+            Ok(beacon) => {
+                let peer = Peer {
+                    name: beacon.name,
+                    addr: SocketAddr::new(from.ip(), beacon.port),
+                    fingerprint: beacon.fingerprint,
+                };
+                debug!("found peer: {:?}", peer);
+                if on_peer(peer) {
+                    return Ok(());
+                }
+            }
+            Err(e) => debug!("ignore invalid beacon from {}: {}", from, e),
+        }
+    }
+}
+
+/// a socket on `port` of every interface that other processes listening for beacons can bind
+/// too, such as a second `peers` or a sender looking up a peer
+#[cfg(unix)]
+fn bind_shared(port: u16) -> Result<UdpSocket> {
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // closed with the UdpSocket on any error below
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let ptr = &on as *const libc::c_int as *const libc::c_void;
+        let len = mem::size_of_val(&on) as libc::socklen_t;
+        if unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, ptr, len) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    addr.sin_addr.s_addr = u32::from(Ipv4Addr::UNSPECIFIED).to_be();
+    let ptr = &addr as *const libc::sockaddr_in as *const libc::sockaddr;
+    let len = mem::size_of_val(&addr) as libc::socklen_t;
+    if unsafe { libc::bind(fd, ptr, len) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind_shared(port: u16) -> Result<UdpSocket> {
+    UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+}
@@ -1,52 +1,227 @@
-use crate::client::ClientStateMachine;
-use crate::server::ServerStateMachine;
-use crate::tls::{TlsTcpClient, TlsTcpServer};
+use crate::cancel::CancelHandle;
+use crate::discovery::{self, Peer};
+use crate::error::{Error, Result};
+use crate::observer::Observer;
+use crate::packet::file_info::FileInfo;
+use crate::packet::sync_request::ConflictPolicy;
+use crate::puller::Puller;
+use crate::receiver::{ReceiveReport, Receiver, ReceiverBuilder};
+use crate::select::Selection;
+use crate::sync::{SyncSummary, Syncer};
+use crate::transport::{Connection, Endpoint, Proxy};
+#[cfg(feature = "quic")]
+use crate::AsyncReceiver;
 use log::info;
-use std::fs::File;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
-use std::path::PathBuf;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "quic")]
+use tokio::runtime::Runtime;
 
+pub use crate::sender::{RetryPolicy, DISCOVERY_TIMEOUT};
+use crate::sender::{SendReport, Sender, SenderBuilder};
+
+/// serves the sessions of a receiver one connection at a time
 pub struct ServerDriver {
-    listener: TcpListener,
+    receiver: Backend,
+}
+
+enum Backend {
+    Blocking(Receiver),
+    /// QUIC is only served by the async receiver
+    #[cfg(feature = "quic")]
+    Quic(Runtime, AsyncReceiver),
 }
 
 impl ServerDriver {
-    pub fn create_server(port: u16) -> Self {
-        // start TCP
-        info!("starting server at port: {}", port);
-        let listener = TcpListener::bind(create_localhost_addr(port)).expect("cannot start TCP");
-        ServerDriver { listener }
+    pub fn create_server(port: u16) -> Result<Self> {
+        let receiver = Receiver::builder()
+            .bind(create_localhost_addr(port))
+            .build()?;
+        Ok(Self::new(receiver))
+    }
+
+    /// listen on an URL such as `tcp://0.0.0.0:7878` or `unix:/run/sendfile.sock`
+    pub fn bind(addr: &str) -> Result<Self> {
+        Self::build(Receiver::builder().endpoint(parse_endpoint(addr)?))
     }
 
-    pub fn accept_conn(&self) {
-        info!("waiting for new TCP connection....");
-        let (str, addr) = self.listener.accept().unwrap();
-        info!("accepted new client at: {}", addr);
-        let mut server = TlsTcpServer::new(str);
+    pub fn new(receiver: Receiver) -> Self {
+        ServerDriver {
+            receiver: Backend::Blocking(receiver),
+        }
+    }
 
-        // state machine
-        let mut sm = ServerStateMachine::new(server.create_tls_str());
-        sm.start()
+    /// build the receiver configured by `builder`, with the async one for QUIC endpoints
+    pub fn build(builder: ReceiverBuilder) -> Result<Self> {
+        #[cfg(feature = "quic")]
+        if let Endpoint::Quic(_) = builder.endpoint {
+            let runtime = runtime()?;
+            let receiver = runtime.block_on(builder.build_async())?;
+            return Ok(ServerDriver {
+                receiver: Backend::Quic(runtime, receiver),
+            });
+        }
+        Ok(Self::new(builder.build()?))
+    }
+
+    pub fn accept_conn(&self) -> Result<ReceiveReport> {
+        match &self.receiver {
+            Backend::Blocking(receiver) => receiver.accept(),
+            #[cfg(feature = "quic")]
+            Backend::Quic(runtime, receiver) => runtime.block_on(receiver.accept()),
+        }
     }
 }
 
-pub fn client_send_files(paths: Vec<PathBuf>, addr: String) {
-    let socket_addr: SocketAddr = addr
-        .parse()
-        .expect("Invalid server address, a valid example: 127.0.0.1:8080");
-    info!("sending files: {:?} to {}", paths, socket_addr);
+/// options of the client when sending files
+#[derive(Clone)]
+pub struct SendOptions {
+    pub retry: RetryPolicy,
+    /// name given to the standard input when `-` is one of the paths
+    pub stdin_name: String,
+    /// which files of the directories go, see `SenderBuilder::selection`
+    pub selection: Selection,
+    /// hash the files so that the receiver compares their content, see `SenderBuilder::checksum`
+    pub checksum: bool,
+    /// only send the chunks the receiver does not have, see `SenderBuilder::dedup`
+    pub dedup: bool,
+    /// let the kernel copy the files to a plaintext socket, see `SenderBuilder::zero_copy`
+    pub zero_copy: bool,
+    /// only trust the receiver with this certificate, see `SenderBuilder::fingerprint`
+    pub opt_fingerprint: Option<String>,
+    /// connect through this proxy, see `SenderBuilder::proxy`
+    pub opt_proxy: Option<Proxy>,
+    pub cancel: CancelHandle,
+    /// gets the events of the transfer
+    pub opt_observer: Option<Arc<dyn Observer>>,
+}
 
-    // check all files are exists
-    for p in &paths {
-        let f = File::open(&p).unwrap();
-        if !f.metadata().unwrap().is_file() {
-            panic!("invalid file: {:?}", p);
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            retry: RetryPolicy::default(),
+            stdin_name: String::from("stdin"),
+            selection: Selection::default(),
+            checksum: false,
+            dedup: false,
+            zero_copy: false,
+            opt_fingerprint: None,
+            opt_proxy: None,
+            cancel: CancelHandle::new(),
+            opt_observer: None,
         }
     }
+}
+
+impl fmt::Debug for SendOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendOptions")
+            .field("retry", &self.retry)
+            .field("stdin_name", &self.stdin_name)
+            .field("selection", &self.selection)
+            .field("checksum", &self.checksum)
+            .field("dedup", &self.dedup)
+            .field("zero_copy", &self.zero_copy)
+            .field("fingerprint", &self.opt_fingerprint)
+            .field("proxy", &self.opt_proxy)
+            .field("cancel", &self.cancel)
+            .field("observer", &self.opt_observer.is_some())
+            .finish()
+    }
+}
+
+pub fn client_send_files(paths: Vec<PathBuf>, addr: &str, opts: SendOptions) -> Result<SendReport> {
+    send_files(
+        paths,
+        Sender::builder().endpoint(parse_endpoint(addr)?),
+        opts,
+    )
+}
+
+/// send files to the peer advertised as `name` on the local network
+pub fn client_send_files_to(
+    paths: Vec<PathBuf>,
+    name: &str,
+    opts: SendOptions,
+) -> Result<SendReport> {
+    send_files(paths, Sender::builder().peer(name), opts)
+}
+
+/// list the peers announcing themselves on the local network
+pub fn client_discover(timeout: Duration) -> Result<Vec<Peer>> {
+    Ok(discovery::discover(timeout)?)
+}
+
+/// list the files shared by the server below `path`
+pub fn client_list(addr: &str, path: &str) -> Result<Vec<FileInfo>> {
+    let mut puller = Puller::new(connect(&parse_endpoint(addr)?)?, Path::new("."));
+    let files = puller.list(path)?;
+    puller.finish()?;
+    Ok(files)
+}
+
+/// download shared files or directories from the server into `out_dir`
+pub fn client_get(addr: &str, names: &[String], out_dir: &Path) -> Result<()> {
+    let endpoint = parse_endpoint(addr)?;
+    info!("getting files: {:?} from {}", names, endpoint);
+    let mut puller = Puller::new(connect(&endpoint)?, out_dir);
+    Ok(puller.get(names)?)
+}
+
+/// mirror the local directory `dir` with the directory synchronized by the server
+pub fn client_sync(addr: &str, dir: &Path, policy: ConflictPolicy) -> Result<SyncSummary> {
+    let endpoint = parse_endpoint(addr)?;
+    info!("synchronizing {:?} with {}", dir, endpoint);
+    let mut syncer = Syncer::new(connect(&endpoint)?, dir);
+    Ok(syncer.sync(policy)?)
+}
+
+fn send_files(
+    paths: Vec<PathBuf>,
+    builder: SenderBuilder,
+    opts: SendOptions,
+) -> Result<SendReport> {
+    let mut builder = builder
+        .files(paths)
+        .retry(opts.retry)
+        .stdin_name(&opts.stdin_name)
+        .selection(opts.selection)
+        .checksum(opts.checksum)
+        .dedup(opts.dedup)
+        .zero_copy(opts.zero_copy)
+        .cancel_handle(opts.cancel)
+        .with_observer(opts.opt_observer);
+    if let Some(fingerprint) = &opts.opt_fingerprint {
+        builder = builder.fingerprint(fingerprint);
+    }
+    if let Some(proxy) = opts.opt_proxy {
+        builder = builder.proxy(proxy);
+    }
+    #[cfg(feature = "quic")]
+    if builder.is_quic() {
+        let sender = builder.build_async()?;
+        return runtime()?.block_on(sender.send());
+    }
+    builder.build()?.send()
+}
+
+/// runs the async sender and receiver for the QUIC endpoints of the blocking API
+#[cfg(feature = "quic")]
+fn runtime() -> Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+fn connect(endpoint: &Endpoint) -> Result<Box<dyn Connection>> {
+    Ok(endpoint.transport(None)?.connect()?)
+}
 
-    let mut client = TlsTcpClient::connect(socket_addr);
-    let mut cm = ClientStateMachine::new(client.create_tls_str(), &paths);
-    cm.start()
+fn parse_endpoint(addr: &str) -> Result<Endpoint> {
+    addr.parse().map_err(Error::Config)
 }
 
 fn create_localhost_addr(port: u16) -> SocketAddr {
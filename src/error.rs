@@ -0,0 +1,175 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// network or file system failure, including protocol violations of the peer
+    Io(io::Error),
+    /// the receiver refused the files
+    Rejected,
+    /// invalid settings given to a builder
+    Config(String),
+    /// the session was cancelled, here or by the peer
+    Cancelled { reason: String, by_peer: bool },
+    /// the data received does not match what was announced: its size or its hash differs
+    Integrity(String),
+    /// some of the files were sent before `source` stopped the transfer
+    Partial {
+        sent: usize,
+        total: usize,
+        source: Box<Error>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// the failure of a transfer that sent `sent` of its `total` files
+    pub(crate) fn partial(source: Error, sent: usize, total: usize) -> Error {
+        match sent {
+            0 => source,
+            _ => Error::Partial {
+                sent,
+                total,
+                source: Box::new(source),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Rejected => write!(f, "rejected by receiver"),
+            Error::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Error::Cancelled {
+                reason,
+                by_peer: true,
+            } => write!(f, "cancelled by peer: {}", reason),
+            Error::Cancelled { reason, .. } => write!(f, "cancelled: {}", reason),
+            Error::Integrity(msg) => write!(f, "integrity failure: {}", msg),
+            Error::Partial {
+                sent,
+                total,
+                source,
+            } => write!(f, "{} ({}/{} file(s) sent)", source, sent, total),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Partial { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if let Some(integrity) = Integrity::get(&e) {
+            return Error::Integrity(integrity.0.clone());
+        }
+        match Cancelled::get(&e) {
+            Some(cancelled) => Error::Cancelled {
+                reason: cancelled.reason.clone(),
+                by_peer: cancelled.by_peer,
+            },
+            None => Error::Io(e),
+        }
+    }
+}
+
+/// carried by the `io::Error` of a cancelled state machine, turned into `Error::Cancelled`
+#[derive(Debug)]
+pub(crate) struct Cancelled {
+    pub reason: String,
+    pub by_peer: bool,
+}
+
+impl Cancelled {
+    pub fn error(reason: &str, by_peer: bool) -> io::Error {
+        let cancelled = Cancelled {
+            reason: reason.to_string(),
+            by_peer,
+        };
+        io::Error::new(io::ErrorKind::Interrupted, cancelled)
+    }
+
+    /// the cancellation carried by `err`, if it is one
+    pub fn get(err: &io::Error) -> Option<&Cancelled> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Cancelled>())
+    }
+
+    /// the session was cancelled rather than failed
+    pub fn is(err: &io::Error) -> bool {
+        Cancelled::get(err).is_some()
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.by_peer {
+            true => write!(f, "cancelled by peer: {}", self.reason),
+            false => write!(f, "cancelled: {}", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// carried by the `io::Error` of a size or hash mismatch, turned into `Error::Integrity`
+#[derive(Debug)]
+pub(crate) struct Integrity(String);
+
+impl Integrity {
+    pub fn error(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Integrity(msg))
+    }
+
+    fn get(err: &io::Error) -> Option<&Integrity> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Integrity>())
+    }
+
+    /// the data received did not match what was announced
+    pub fn is(err: &io::Error) -> bool {
+        Integrity::get(err).is_some()
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Integrity {}
+
+/// carried by the `io::Error` of a peer hanging up on Hello: it predates the negotiation and
+/// only speaks version 1
+#[derive(Debug)]
+pub(crate) struct Unversioned;
+
+impl Unversioned {
+    pub fn error() -> io::Error {
+        io::Error::new(io::ErrorKind::UnexpectedEof, Unversioned)
+    }
+
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref()
+            .is_some_and(|inner| inner.downcast_ref::<Unversioned>().is_some())
+    }
+}
+
+impl fmt::Display for Unversioned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the peer closed the connection on Hello")
+    }
+}
+
+impl std::error::Error for Unversioned {}
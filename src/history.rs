@@ -0,0 +1,118 @@
+//! transfers done by the binary, one JSON object per line of
+//! `$XDG_DATA_HOME/sendfile/history.jsonl` (or `~/.local/share/sendfile/history.jsonl`)
+
+use crate::packet::file_info::FileInfo;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Sent => write!(f, "sent"),
+            Direction::Received => write!(f, "received"),
+        }
+    }
+}
+
+/// one transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// end of the transfer, in seconds since the Unix epoch
+    pub time: u64,
+    pub direction: Direction,
+    pub peer: String,
+    pub files: usize,
+    pub bytes: u64,
+    /// `ok`, or the error that stopped the transfer
+    pub outcome: String,
+}
+
+impl Record {
+    /// a transfer of `files` ending now
+    pub fn new(direction: Direction, peer: &str, files: &[FileInfo], outcome: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Record {
+            time,
+            direction,
+            peer: peer.to_string(),
+            files: files.len(),
+            bytes: files.iter().map(|f| f.size).sum(),
+            outcome: outcome.to_string(),
+        }
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_DATA_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(windows) => PathBuf::from(env::var_os("LOCALAPPDATA")?),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(dir.join("sendfile").join("history.jsonl"))
+}
+
+pub fn append(path: &Path, record: &Record) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+/// every record, oldest first; nothing when no transfer was recorded yet
+pub fn load(path: &Path) -> io::Result<Vec<Record>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// `YYYY-MM-DD hh:mm:ss` in UTC
+pub fn format_time(secs: u64) -> String {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // civil date of a day count, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
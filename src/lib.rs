@@ -1,6 +1,42 @@
-mod server;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod cancel;
+mod chunks;
 mod client;
-mod tls;
+pub mod config;
+mod delta;
+pub mod discovery;
+pub mod driver;
+mod error;
+pub mod history;
+mod observer;
 mod packet;
+mod puller;
+mod receiver;
+pub mod relay;
+mod select;
+mod sender;
+mod server;
+mod source;
+mod sparse;
 mod streamer;
-pub mod driver;
\ No newline at end of file
+mod sync;
+pub mod testing;
+mod tls;
+pub mod transport;
+mod zerocopy;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncReceiver, AsyncSender};
+pub use cancel::CancelHandle;
+pub use error::{Error, Result};
+pub use observer::{Event, Observer};
+pub use packet::file_info::FileInfo;
+pub use packet::sync_request::ConflictPolicy;
+pub use receiver::{ReceiveReport, Receiver, ReceiverBuilder};
+pub use select::Selection;
+pub use sender::{RetryPolicy, SendReport, Sender, SenderBuilder};
+pub use server::AcceptPolicy;
+pub use sync::SyncSummary;
+pub use tls::KeyPair;
+pub use transport::{Endpoint, PeerInfo};
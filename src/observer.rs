@@ -0,0 +1,55 @@
+use crate::packet::file_info::FileInfo;
+use serde::Serialize;
+
+/// what happens during a session, on the sender or on the receiver side; serialized as an
+/// object tagged with `"event"`, such as `{"event":"progress","index":0,"bytes":65536}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// the files offered by the sender
+    Requested {
+        files: Vec<FileInfo>,
+    },
+    Accepted,
+    Rejected,
+    FileStarted {
+        index: usize,
+        file: FileInfo,
+    },
+    /// bytes of the current file transferred so far
+    Progress {
+        index: usize,
+        bytes: u64,
+    },
+    /// `file.hash` is the SHA-256 of the content transferred
+    FileCompleted {
+        index: usize,
+        file: FileInfo,
+    },
+    /// the receiver already has the same file, it is not transferred
+    Skipped {
+        index: usize,
+        file: FileInfo,
+    },
+    /// a file (`index` is set) or the whole session was cancelled, here or by the peer
+    Cancelled {
+        index: Option<usize>,
+        reason: String,
+        by_peer: bool,
+    },
+    Finished,
+}
+
+/// receives the events of the sessions it is given to
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
@@ -0,0 +1,463 @@
+//! compact layout of the packet bodies of protocol version 2, specified in PROTOCOL.md
+
+use crate::packet::cancel::{CancelData, CancelScope};
+use crate::packet::chunks::{ChunkInfo, CHUNK_HASH_LEN};
+use crate::packet::delta::{BlockSum, CopyData, SignatureData, STRONG_LEN};
+use crate::packet::file_info::FileInfo;
+use crate::packet::hole::HoleData;
+use crate::packet::start_file::StartFileData;
+use crate::packet::sync_request::{ConflictPolicy, SyncRequest};
+use crate::packet::{invalid, Packet, MAX_ENTRIES, MAX_MESSAGE_LEN, MAX_NAMES};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+/// flags of a file
+const HAS_MTIME: u8 = 1;
+const HAS_HASH: u8 = 2;
+const STREAMED: u8 = 4;
+
+/// length of a SHA-256
+const HASH_LEN: usize = 32;
+
+/// the body of a control packet, FileData and Hello are written as they are
+pub(crate) fn encode(packet: &Packet) -> Result<Vec<u8>> {
+    let mut w = Writer { buf: Vec::new() };
+    match packet {
+        Packet::Send(files) | Packet::Resume(files) | Packet::Listing(files) => w.files(files)?,
+        Packet::StartFile(data) => w.start_file(data)?,
+        Packet::ResumeAt(offsets) => w.offsets(offsets)?,
+        Packet::List(path) => w.str(path),
+        Packet::Get(names) => w.strings(names),
+        Packet::Sync(request) => w.sync(request)?,
+        Packet::Cancel(data) => w.cancel(data),
+        Packet::Have(have) => w.bits(have),
+        Packet::Signature(data) => w.signature(data),
+        Packet::Copy(data) => w.copy(data),
+        Packet::Chunks(chunks) => w.chunks(chunks),
+        Packet::Want(want) => w.bits(want),
+        Packet::Hole(data) => w.hole(data),
+        _ => {}
+    }
+    Ok(w.buf)
+}
+
+/// decode the body of a control packet, every byte of it
+pub(crate) fn decode(action: u8, buf: &[u8]) -> Result<Packet> {
+    let mut r = Reader { buf, names_len: 0 };
+    let packet = match action {
+        0 => Packet::Send(r.files()?),
+        3 => Packet::StartFile(r.start_file()?),
+        7 => Packet::Resume(r.files()?),
+        8 => Packet::ResumeAt(r.offsets()?),
+        9 => Packet::List(r.str()?),
+        10 => Packet::Listing(r.files()?),
+        11 => Packet::Get(r.strings()?),
+        12 => Packet::Sync(r.sync()?),
+        13 => Packet::Cancel(r.cancel()?),
+        15 => Packet::Have(r.bits()?),
+        16 => Packet::Signature(r.signature()?),
+        17 => Packet::Copy(r.copy()?),
+        19 => Packet::Chunks(r.chunks()?),
+        20 => Packet::Want(r.bits()?),
+        21 => Packet::Hole(r.hole()?),
+        _ => return Err(Error::from(ErrorKind::InvalidData)),
+    };
+    match r.buf.is_empty() {
+        true => Ok(packet),
+        false => Err(invalid("unexpected data after the packet")),
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// unsigned LEB128: 7 bits per byte, the lowest first, the high bit set on all but the last
+    fn varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.buf.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.buf.push(n as u8);
+    }
+
+    fn usize(&mut self, n: usize) {
+        self.varint(n as u64)
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn strings(&mut self, strings: &[String]) {
+        self.usize(strings.len());
+        strings.iter().for_each(|s| self.str(s));
+    }
+
+    fn files(&mut self, files: &[FileInfo]) -> Result<()> {
+        self.usize(files.len());
+        let mut prev = "";
+        for file in files {
+            self.file(file, prev)?;
+            prev = &file.name;
+        }
+        Ok(())
+    }
+
+    /// the start the name shares with `prev`, the name of the previous file, is not repeated
+    fn file(&mut self, file: &FileInfo, prev: &str) -> Result<()> {
+        let mut flags = 0;
+        if file.mtime.is_some() {
+            flags |= HAS_MTIME;
+        }
+        if file.hash.is_some() {
+            flags |= HAS_HASH;
+        }
+        if file.streamed {
+            flags |= STREAMED;
+        }
+        self.buf.push(flags);
+        let shared = shared_prefix(prev, &file.name);
+        self.usize(shared);
+        self.str(&file.name[shared..]);
+        self.varint(file.size);
+        if let Some(mtime) = file.mtime {
+            self.varint(mtime);
+        }
+        if let Some(hash) = file.hash.as_deref() {
+            self.buf.extend_from_slice(&from_hex(hash)?);
+        }
+        Ok(())
+    }
+
+    fn start_file(&mut self, data: &StartFileData) -> Result<()> {
+        self.usize(data.index);
+        self.usize(data.total);
+        self.varint(data.offset);
+        self.file(&data.file_info, "")
+    }
+
+    /// 0 for a file not wanted, the offset plus 1 otherwise
+    fn offsets(&mut self, offsets: &[Option<u64>]) -> Result<()> {
+        self.usize(offsets.len());
+        for offset in offsets {
+            match offset {
+                None => self.varint(0),
+                Some(offset) => match offset.checked_add(1) {
+                    Some(n) => self.varint(n),
+                    None => return Err(Error::new(ErrorKind::InvalidInput, "offset too large")),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// bit `i % 8` of byte `i / 8` for entry `i`
+    fn bits(&mut self, bits: &[bool]) {
+        self.usize(bits.len());
+        for chunk in bits.chunks(8) {
+            let byte = chunk
+                .iter()
+                .rev()
+                .fold(0, |byte, bit| byte << 1 | *bit as u8);
+            self.buf.push(byte);
+        }
+    }
+
+    /// the weak checksum of a block on 4 bytes, little-endian, then its strong checksum
+    fn signature(&mut self, data: &SignatureData) {
+        self.usize(data.index);
+        self.varint(u64::from(data.block_len));
+        self.usize(data.blocks.len());
+        for block in &data.blocks {
+            self.buf.extend_from_slice(&block.weak.to_le_bytes());
+            self.buf.extend_from_slice(&block.strong);
+        }
+    }
+
+    fn copy(&mut self, data: &CopyData) {
+        self.varint(data.block);
+        self.varint(data.count);
+    }
+
+    fn hole(&mut self, data: &HoleData) {
+        self.varint(data.offset);
+        self.varint(data.len);
+    }
+
+    /// the length of a chunk, then its hash
+    fn chunks(&mut self, chunks: &[ChunkInfo]) {
+        self.usize(chunks.len());
+        for chunk in chunks {
+            self.varint(u64::from(chunk.len));
+            self.buf.extend_from_slice(&chunk.hash);
+        }
+    }
+
+    fn sync(&mut self, request: &SyncRequest) -> Result<()> {
+        self.buf.push(match request.policy {
+            ConflictPolicy::Newer => 0,
+            ConflictPolicy::Local => 1,
+            ConflictPolicy::Remote => 2,
+            ConflictPolicy::Skip => 3,
+        });
+        self.files(&request.files)
+    }
+
+    fn cancel(&mut self, data: &CancelData) {
+        self.buf.push(match data.scope {
+            CancelScope::File => 0,
+            CancelScope::Session => 1,
+            CancelScope::Integrity => 2,
+        });
+        self.str(&data.reason);
+        // left out when unknown, for the peers without it
+        if let Some(index) = data.index {
+            self.usize(index);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    /// length of the names of a manifest once expanded, bounded like the packet itself
+    names_len: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(invalid("truncated packet"));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    /// the shortest encoding only, at most 10 bytes
+    fn varint(&mut self) -> Result<u64> {
+        let mut n = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            let bits = u64::from(b & 0x7f);
+            if (shift == 63 && bits > 1) || (shift > 0 && b == 0) {
+                return Err(invalid("invalid number"));
+            }
+            n |= bits << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("invalid number"))
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        usize::try_from(self.varint()?).map_err(|_| invalid("number too large"))
+    }
+
+    fn count(&mut self, max: usize) -> Result<usize> {
+        match self.usize()? {
+            count if count <= max => Ok(count),
+            _ => Err(invalid("too many entries")),
+        }
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("invalid UTF-8"))
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        let count = self.count(MAX_NAMES)?;
+        // every string takes a byte at least
+        let mut strings = Vec::with_capacity(count.min(self.buf.len()));
+        for _ in 0..count {
+            strings.push(self.str()?);
+        }
+        Ok(strings)
+    }
+
+    fn files(&mut self) -> Result<Vec<FileInfo>> {
+        let count = self.count(MAX_ENTRIES)?;
+        let mut files: Vec<FileInfo> = Vec::with_capacity(count.min(self.buf.len()));
+        for _ in 0..count {
+            let prev = files.last().map_or("", |f| f.name.as_str());
+            let file = self.file(prev)?;
+            files.push(file);
+        }
+        Ok(files)
+    }
+
+    fn file(&mut self, prev: &str) -> Result<FileInfo> {
+        let flags = self.byte()?;
+        if flags & !(HAS_MTIME | HAS_HASH | STREAMED) != 0 {
+            return Err(invalid("unknown file flags"));
+        }
+        let shared = self.usize()?;
+        if shared > prev.len() || !prev.is_char_boundary(shared) {
+            return Err(invalid("invalid name prefix"));
+        }
+        let name = format!("{}{}", &prev[..shared], self.str()?);
+        self.names_len += name.len();
+        if self.names_len > MAX_MESSAGE_LEN {
+            return Err(invalid("names too long"));
+        }
+        let size = self.varint()?;
+        let mtime = match flags & HAS_MTIME {
+            0 => None,
+            _ => Some(self.varint()?),
+        };
+        let hash = match flags & HAS_HASH {
+            0 => None,
+            _ => Some(to_hex(self.take(HASH_LEN)?)),
+        };
+        Ok(FileInfo {
+            name,
+            size,
+            mtime,
+            hash,
+            streamed: flags & STREAMED != 0,
+        })
+    }
+
+    fn start_file(&mut self) -> Result<StartFileData> {
+        let index = self.usize()?;
+        let total = self.usize()?;
+        let offset = self.varint()?;
+        let file_info = self.file("")?;
+        Ok(StartFileData::new(file_info, index, total, offset))
+    }
+
+    fn offsets(&mut self) -> Result<Vec<Option<u64>>> {
+        let count = self.count(MAX_ENTRIES)?;
+        let mut offsets = Vec::with_capacity(count.min(self.buf.len()));
+        for _ in 0..count {
+            offsets.push(self.varint()?.checked_sub(1));
+        }
+        Ok(offsets)
+    }
+
+    fn bits(&mut self) -> Result<Vec<bool>> {
+        let count = self.count(MAX_ENTRIES)?;
+        let bytes = self.take(count.div_ceil(8))?;
+        // the unused bits of the last byte
+        let padding = match count % 8 {
+            0 => 0,
+            used => bytes[bytes.len() - 1] >> used,
+        };
+        if padding != 0 {
+            return Err(invalid("invalid padding"));
+        }
+        Ok((0..count)
+            .map(|i| bytes[i / 8] & 1 << (i % 8) != 0)
+            .collect())
+    }
+
+    fn signature(&mut self) -> Result<SignatureData> {
+        let index = self.usize()?;
+        let block_len = u32::try_from(self.varint()?).map_err(|_| invalid("number too large"))?;
+        let count = self.count(MAX_ENTRIES)?;
+        let mut blocks = Vec::with_capacity(count.min(self.buf.len() / (4 + STRONG_LEN)));
+        for _ in 0..count {
+            let mut weak = [0_u8; 4];
+            weak.copy_from_slice(self.take(4)?);
+            let mut strong = [0_u8; STRONG_LEN];
+            strong.copy_from_slice(self.take(STRONG_LEN)?);
+            blocks.push(BlockSum {
+                weak: u32::from_le_bytes(weak),
+                strong,
+            });
+        }
+        Ok(SignatureData {
+            index,
+            block_len,
+            blocks,
+        })
+    }
+
+    fn copy(&mut self) -> Result<CopyData> {
+        let block = self.varint()?;
+        let count = self.varint()?;
+        Ok(CopyData { block, count })
+    }
+
+    fn hole(&mut self) -> Result<HoleData> {
+        let offset = self.varint()?;
+        let len = self.varint()?;
+        Ok(HoleData { offset, len })
+    }
+
+    fn chunks(&mut self) -> Result<Vec<ChunkInfo>> {
+        let count = self.count(MAX_ENTRIES)?;
+        let mut chunks = Vec::with_capacity(count.min(self.buf.len() / (1 + CHUNK_HASH_LEN)));
+        for _ in 0..count {
+            let len = u32::try_from(self.varint()?).map_err(|_| invalid("number too large"))?;
+            let mut hash = [0_u8; CHUNK_HASH_LEN];
+            hash.copy_from_slice(self.take(CHUNK_HASH_LEN)?);
+            chunks.push(ChunkInfo { hash, len });
+        }
+        Ok(chunks)
+    }
+
+    fn sync(&mut self) -> Result<SyncRequest> {
+        let policy = match self.byte()? {
+            0 => ConflictPolicy::Newer,
+            1 => ConflictPolicy::Local,
+            2 => ConflictPolicy::Remote,
+            3 => ConflictPolicy::Skip,
+            _ => return Err(invalid("unknown conflict policy")),
+        };
+        let files = self.files()?;
+        Ok(SyncRequest { files, policy })
+    }
+
+    fn cancel(&mut self) -> Result<CancelData> {
+        let scope = match self.byte()? {
+            0 => CancelScope::File,
+            1 => CancelScope::Session,
+            2 => CancelScope::Integrity,
+            _ => return Err(invalid("unknown cancel scope")),
+        };
+        let reason = self.str()?;
+        let index = match self.buf.is_empty() {
+            true => None,
+            false => Some(self.usize()?),
+        };
+        Ok(CancelData {
+            scope,
+            reason,
+            index,
+        })
+    }
+}
+
+/// length of the common start of both names, on a character boundary
+fn shared_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, ca), cb)| ca != cb)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i)
+}
+
+fn from_hex(hash: &str) -> Result<[u8; HASH_LEN]> {
+    let err = || Error::new(ErrorKind::InvalidInput, format!("invalid hash: {}", hash));
+    if hash.len() != 2 * HASH_LEN || !hash.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(err());
+    }
+    let mut bytes = [0_u8; HASH_LEN];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16).map_err(|_| err())?;
+    }
+    Ok(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,58 @@
+use super::MAX_REASON_LEN;
+use crate::error::{Cancelled, Integrity};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// what a Cancel packet stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CancelScope {
+    /// the file being transferred, the session goes on with the next one
+    File,
+    /// the whole session, nothing is sent after the Cancel packet
+    Session,
+    /// the whole session, because the data received does not match what was announced; only
+    /// written from version 3
+    Integrity,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CancelData {
+    pub scope: CancelScope,
+    pub reason: String,
+    /// the file a receiver stops, the sender may already be sending the next one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+impl CancelData {
+    /// the reason is cut to the `MAX_REASON_LEN` bytes a peer takes
+    pub fn new(scope: CancelScope, reason: &str) -> Self {
+        let mut len = reason.len().min(MAX_REASON_LEN);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        CancelData {
+            scope,
+            reason: reason[..len].to_string(),
+            index: None,
+        }
+    }
+
+    /// the error ending the session of the side this Cancel is sent to
+    pub fn peer_error(&self) -> io::Error {
+        match self.scope {
+            CancelScope::Integrity => Integrity::error(self.reason.clone()),
+            _ => Cancelled::error(&self.reason, true),
+        }
+    }
+
+    /// the receiver stopping the file at `index`
+    pub fn file(index: usize, reason: &str) -> Self {
+        CancelData {
+            index: Some(index),
+            ..Self::new(CancelScope::File, reason)
+        }
+    }
+}
@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// length of the hash of a chunk, a full SHA-256
+pub const CHUNK_HASH_LEN: usize = 32;
+
+/// a piece of a file cut where its content matches a pattern, so that the same bytes are cut the
+/// same way in any file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkInfo {
+    pub hash: [u8; CHUNK_HASH_LEN],
+    pub len: u32,
+}
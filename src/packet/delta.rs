@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// length of the strong checksum of a block: the start of its SHA-256
+pub const STRONG_LEN: usize = 16;
+
+/// checksums of a block of the copy the receiver has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockSum {
+    /// rolling checksum, cheap to compute at every byte of the new content
+    pub weak: u32,
+    pub strong: [u8; STRONG_LEN],
+}
+
+/// the blocks of the copy the receiver has of a requested file, sent before its answer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignatureData {
+    /// index of the file in the request
+    pub index: usize,
+    pub block_len: u32,
+    /// the full blocks of the copy, a shorter block at its end is left out
+    pub blocks: Vec<BlockSum>,
+}
+
+/// `count` blocks of the receiver's copy starting with block `block`, in place of their content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CopyData {
+    pub block: u64,
+    pub count: u64,
+}
@@ -1,20 +1,58 @@
-use std::path::{PathBuf};
-use std::fs::File;
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, Metadata};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FileInfo {
     pub name: String,
-    pub size: u64
+    pub size: u64,
+    /// last modification, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// SHA-256 of the content, computed when comparing directories and once a file is
+    /// transferred
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// content of unknown length (piped from stdin), `size` is meaningless and the end
+    /// is only known at EndFile
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streamed: bool,
 }
 
 impl FileInfo {
-    pub fn from_path(path: &PathBuf) -> Self {
-        let f = File::open(path).unwrap();
-        let meta = f.metadata().unwrap();
+    pub fn from_path(path: &PathBuf) -> Result<Self> {
+        let meta = File::open(path)?.metadata()?;
+        let name = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no file name in UTF-8"))?;
+        Ok(Self::from_metadata(String::from(name), &meta))
+    }
+
+    pub fn from_metadata(name: String, meta: &Metadata) -> Self {
         FileInfo {
-            name: String::from(path.file_name().map(|s| s.to_str()).unwrap().unwrap()),
-            size: meta.len()
+            name,
+            size: meta.len(),
+            mtime: meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            hash: None,
+            streamed: false,
         }
     }
-}
\ No newline at end of file
+
+    pub fn streamed(name: String) -> Self {
+        FileInfo {
+            name,
+            size: 0,
+            mtime: None,
+            hash: None,
+            streamed: true,
+        }
+    }
+}
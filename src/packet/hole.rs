@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+/// `len` zero bytes of the current file at `offset`, which the sender's file system does not
+/// store, in place of their FileData
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoleData {
+    pub offset: u64,
+    pub len: u64,
+}
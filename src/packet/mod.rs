@@ -1,8 +1,19 @@
+mod binary;
+pub mod cancel;
+pub mod chunks;
+pub mod delta;
 pub mod file_info;
+pub mod hole;
 pub mod start_file;
+pub mod sync_request;
 
+use crate::packet::cancel::CancelData;
+use crate::packet::chunks::ChunkInfo;
+use crate::packet::delta::{CopyData, SignatureData};
 use crate::packet::file_info::FileInfo;
+use crate::packet::hole::HoleData;
 use crate::packet::start_file::StartFileData;
+use crate::packet::sync_request::SyncRequest;
 use serde::{Deserialize, Serialize};
 use std::io::{Error, ErrorKind, Result};
 
@@ -14,18 +25,214 @@ pub enum Packet {
     FileData(Vec<u8>),
     EndFile,
     Finish,
+    Resume(Vec<FileInfo>),
+    ResumeAt(Vec<Option<u64>>),
+    List(String),
+    Listing(Vec<FileInfo>),
+    Get(Vec<String>),
+    Sync(SyncRequest),
+    /// stop the current file or the whole session, from either side
+    Cancel(CancelData),
+    /// protocol versions offered before the first request, or the one chosen in the answer
+    Hello(Vec<u8>),
+    /// answer to Send accepting it, except the files marked true which the receiver already
+    /// has; only sent from protocol version 2
+    Have(Vec<bool>),
+    /// blocks of the copy the receiver has of a file, sent before its answer to Send; only
+    /// sent from protocol version 2
+    Signature(SignatureData),
+    /// blocks of the receiver's copy of the current file, between StartFile and EndFile
+    Copy(CopyData),
+    /// the receiver keeps an index of the chunks of the files it received, sent before its
+    /// answer to Send; only sent from protocol version 2
+    Dedup,
+    /// chunks of the current file, after its StartFile when the receiver sent Dedup
+    Chunks(Vec<ChunkInfo>),
+    /// answer to Chunks, the chunks marked true are sent and the receiver has the others
+    Want(Vec<bool>),
+    /// a hole of the current file, between StartFile and EndFile; only sent from protocol
+    /// version 2
+    Hole(HoleData),
 }
 
+/// how the bodies of the control packets are written, chosen for each session with Hello
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// protocol version 1: JSON, a packet fits in one frame
+    Json,
+    /// protocol version 2: the layout of PROTOCOL.md, a packet may span several frames
+    Binary,
+    /// protocol version 3: the layout of version 2, the receiver tells the sender about the
+    /// files it cancels and answers Finish
+    Binary3,
+}
+
+/// protocol versions spoken by this side, the preferred first
+pub const VERSIONS: [u8; 3] = [3, 2, 1];
+
+impl Encoding {
+    pub fn version(self) -> u8 {
+        match self {
+            Encoding::Json => 1,
+            Encoding::Binary => 2,
+            Encoding::Binary3 => 3,
+        }
+    }
+
+    /// the preferred of the versions offered by the peer
+    pub fn choose(offered: &[u8]) -> Result<Self> {
+        VERSIONS
+            .iter()
+            .find(|v| offered.contains(v))
+            .and_then(|v| Self::from_version(*v))
+            .ok_or_else(|| invalid("no common protocol version"))
+    }
+
+    /// the version chosen by the peer in its answer to Hello
+    pub fn from_answer(answer: &[u8]) -> Result<Self> {
+        match answer {
+            [version] => Self::from_version(*version),
+            _ => None,
+        }
+        .ok_or_else(|| invalid("invalid protocol version"))
+    }
+
+    fn from_version(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Encoding::Json),
+            2 => Some(Encoding::Binary),
+            3 => Some(Encoding::Binary3),
+            _ => None,
+        }
+    }
+
+    /// a frame of this length is continued by the next one, which has the same action
+    pub fn is_continued(self, len: usize) -> bool {
+        self != Encoding::Json && len == MAX_BODY_LEN
+    }
+}
+
+/// size of the frame header: [1 byte for action] + [2 bytes for len]
+pub const HEADER_LEN: usize = 3;
+
+/// largest body of a frame, its length is written on 2 bytes
+pub const MAX_BODY_LEN: usize = u16::MAX as usize;
+
+/// longest data of a FileData written by a sender, the size of its reads of a file
+pub(crate) const FILE_DATA_LEN: usize = 61 * 1024;
+
+/// largest body of a packet spanning several frames
+pub const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+/// most files in a request, a listing or a manifest
+pub const MAX_ENTRIES: usize = 1 << 20;
+
+/// most names in Get
+pub const MAX_NAMES: usize = 4096;
+
+/// longest block of a Signature
+pub const MAX_BLOCK_LEN: u32 = 16 << 20;
+
+/// longest chunk of Chunks
+pub const MAX_CHUNK_LEN: u32 = 1 << 20;
+
+/// longest file name, in bytes
+pub const MAX_NAME_LEN: usize = 4096;
+
+/// most components of a file name, `dir/sub/file` has 3
+pub const MAX_NAME_DEPTH: usize = 64;
+
+/// longest reason of a Cancel packet, in bytes
+pub const MAX_REASON_LEN: usize = 1024;
+
+/// deepest nesting of arrays and objects in a JSON body, the bodies of the protocol are flat
+const MAX_JSON_DEPTH: usize = 4;
+
 impl Packet {
-    pub fn from_data(action: u8, buf: &[u8]) -> Result<Self> {
+    /// action and data length of a frame header
+    pub fn parse_header(header: [u8; HEADER_LEN]) -> (u8, u16) {
+        (header[0], u16::from_le_bytes([header[1], header[2]]))
+    }
+
+    /// frame header of the given action and data length
+    pub fn header(action: u8, len: u16) -> [u8; HEADER_LEN] {
+        let len = len.to_le_bytes();
+        [action, len[0], len[1]]
+    }
+
+    /// convert to frames: [1 byte for action] + [2 bytes for len] + [additional data],
+    /// fails when the data does not fit in a frame, or in `MAX_MESSAGE_LEN` from version 2
+    pub fn into_bytes(self, encoding: Encoding) -> Result<Vec<u8>> {
+        let action = self.get_action();
+        let data = self.get_data(encoding)?;
+        let max = match encoding {
+            Encoding::Json => MAX_BODY_LEN,
+            Encoding::Binary | Encoding::Binary3 => MAX_MESSAGE_LEN,
+        };
+        if data.len() > max {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("packet too large: {} bytes, at most {}", data.len(), max),
+            ));
+        }
+
+        // full frames, then the rest in a shorter one, empty if need be
+        let frames = data.len() / MAX_BODY_LEN + 1;
+        let mut vec: Vec<u8> = Vec::with_capacity(frames * HEADER_LEN + data.len());
+        let mut rest = data.as_slice();
+        loop {
+            let len = rest.len().min(MAX_BODY_LEN);
+            vec.extend_from_slice(&Self::header(action, len as u16));
+            vec.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            if !encoding.is_continued(len) {
+                return Ok(vec);
+            }
+        }
+    }
+
+    /// decode the data of a packet sent by the peer, rejecting anything beyond the limits of the
+    /// protocol
+    pub fn from_data(action: u8, buf: &[u8], encoding: Encoding) -> Result<Self> {
+        let packet = Self::decode(action, buf, encoding)?;
+        packet.check_limits()?;
+        Ok(packet)
+    }
+
+    fn decode(action: u8, buf: &[u8], encoding: Encoding) -> Result<Self> {
+        if !buf.is_empty() && matches!(action, 1 | 2 | 5 | 6 | 18) {
+            return Err(invalid("unexpected data"));
+        }
+        match (action, encoding) {
+            (1, _) => Ok(Packet::Accept),
+            (2, _) => Ok(Packet::Reject),
+            (4, _) => Ok(Packet::FileData(buf.to_vec())),
+            (5, _) => Ok(Packet::EndFile),
+            (6, _) => Ok(Packet::Finish),
+            (14, _) => Ok(Packet::Hello(buf.to_vec())),
+            (18, _) => Ok(Packet::Dedup),
+            (_, Encoding::Json) => Self::decode_json(action, buf),
+            (_, Encoding::Binary) | (_, Encoding::Binary3) => binary::decode(action, buf),
+        }
+    }
+
+    fn decode_json(action: u8, buf: &[u8]) -> Result<Self> {
         match action {
             0 => Self::parse_json::<Vec<FileInfo>>(buf).map(Packet::Send),
-            1 => Ok(Packet::Accept),
-            2 => Ok(Packet::Reject),
             3 => Self::parse_json::<StartFileData>(buf).map(Packet::StartFile),
-            4 => Ok(Packet::FileData(buf.iter().copied().collect())),
-            5 => Ok(Packet::EndFile),
-            6 => Ok(Packet::Finish),
+            7 => Self::parse_json::<Vec<FileInfo>>(buf).map(Packet::Resume),
+            8 => Self::parse_json::<Vec<Option<u64>>>(buf).map(Packet::ResumeAt),
+            9 => Self::parse_json::<String>(buf).map(Packet::List),
+            10 => Self::parse_json::<Vec<FileInfo>>(buf).map(Packet::Listing),
+            11 => Self::parse_json::<Vec<String>>(buf).map(Packet::Get),
+            12 => Self::parse_json::<SyncRequest>(buf).map(Packet::Sync),
+            13 => Self::parse_json::<CancelData>(buf).map(Packet::Cancel),
+            15 => Self::parse_json::<Vec<bool>>(buf).map(Packet::Have),
+            16 => Self::parse_json::<SignatureData>(buf).map(Packet::Signature),
+            17 => Self::parse_json::<CopyData>(buf).map(Packet::Copy),
+            19 => Self::parse_json::<Vec<ChunkInfo>>(buf).map(Packet::Chunks),
+            20 => Self::parse_json::<Vec<bool>>(buf).map(Packet::Want),
+            21 => Self::parse_json::<HoleData>(buf).map(Packet::Hole),
             _ => Err(Error::from(ErrorKind::InvalidData)),
         }
     }
@@ -39,14 +246,49 @@ impl Packet {
             Packet::FileData(_) => 4,
             Packet::EndFile => 5,
             Packet::Finish => 6,
+            Packet::Resume(_) => 7,
+            Packet::ResumeAt(_) => 8,
+            Packet::List(_) => 9,
+            Packet::Listing(_) => 10,
+            Packet::Get(_) => 11,
+            Packet::Sync(_) => 12,
+            Packet::Cancel(_) => 13,
+            Packet::Hello(_) => 14,
+            Packet::Have(_) => 15,
+            Packet::Signature(_) => 16,
+            Packet::Copy(_) => 17,
+            Packet::Dedup => 18,
+            Packet::Chunks(_) => 19,
+            Packet::Want(_) => 20,
+            Packet::Hole(_) => 21,
+        }
+    }
+
+    pub fn get_data(self, encoding: Encoding) -> Result<Vec<u8>> {
+        match (self, encoding) {
+            (Packet::FileData(data), _) | (Packet::Hello(data), _) => Ok(data),
+            (packet, Encoding::Binary) | (packet, Encoding::Binary3) => binary::encode(&packet),
+            (packet, Encoding::Json) => Ok(packet.json_data()),
         }
     }
 
-    pub fn get_data(self) -> Vec<u8> {
+    fn json_data(self) -> Vec<u8> {
         match self {
             Packet::Send(data) => Self::json_bytes(data),
             Packet::StartFile(data) => Self::json_bytes(data),
-            Packet::FileData(data) => data,
+            Packet::Resume(data) => Self::json_bytes(data),
+            Packet::ResumeAt(data) => Self::json_bytes(data),
+            Packet::List(data) => Self::json_bytes(data),
+            Packet::Listing(data) => Self::json_bytes(data),
+            Packet::Get(data) => Self::json_bytes(data),
+            Packet::Sync(data) => Self::json_bytes(data),
+            Packet::Cancel(data) => Self::json_bytes(data),
+            Packet::Have(data) => Self::json_bytes(data),
+            Packet::Signature(data) => Self::json_bytes(data),
+            Packet::Copy(data) => Self::json_bytes(data),
+            Packet::Chunks(data) => Self::json_bytes(data),
+            Packet::Want(data) => Self::json_bytes(data),
+            Packet::Hole(data) => Self::json_bytes(data),
             _ => vec![],
         }
     }
@@ -55,9 +297,53 @@ impl Packet {
     where
         T: Deserialize<'a>,
     {
+        check_depth(buf)?;
         serde_json::from_slice::<T>(buf).map_err(std::io::Error::from)
     }
 
+    fn check_limits(&self) -> Result<()> {
+        match self {
+            Packet::Send(files) | Packet::Resume(files) | Packet::Listing(files) => {
+                check_files(files)
+            }
+            Packet::Sync(request) => check_files(&request.files),
+            Packet::StartFile(data) => {
+                check_name(&data.file_info.name)?;
+                match data.total <= MAX_ENTRIES && data.index < data.total {
+                    true => Ok(()),
+                    false => Err(invalid("invalid file index")),
+                }
+            }
+            Packet::ResumeAt(offsets) => check_count(offsets.len()),
+            Packet::Have(have) | Packet::Want(have) => check_count(have.len()),
+            Packet::Signature(data) => {
+                check_count(data.blocks.len())?;
+                match data.block_len > 0 && data.block_len <= MAX_BLOCK_LEN {
+                    true => Ok(()),
+                    false => Err(invalid("invalid block length")),
+                }
+            }
+            Packet::Copy(data) if data.count == 0 => Err(invalid("no block to copy")),
+            Packet::Hole(data) if data.len == 0 => Err(invalid("empty hole")),
+            Packet::Chunks(chunks) => {
+                check_count(chunks.len())?;
+                match chunks.iter().all(|c| c.len > 0 && c.len <= MAX_CHUNK_LEN) {
+                    true => Ok(()),
+                    false => Err(invalid("invalid chunk length")),
+                }
+            }
+            Packet::List(path) if path.is_empty() => Ok(()),
+            Packet::List(path) => check_name(path),
+            Packet::Get(names) if names.len() > MAX_NAMES => Err(invalid("too many names")),
+            Packet::Get(names) => names.iter().try_for_each(|name| check_name(name)),
+            Packet::Cancel(data) if data.reason.len() > MAX_REASON_LEN => {
+                Err(invalid("cancel reason too long"))
+            }
+            Packet::Hello(versions) if versions.is_empty() => Err(invalid("no protocol version")),
+            _ => Ok(()),
+        }
+    }
+
     fn json_bytes<T>(data: T) -> Vec<u8>
     where
         T: Serialize,
@@ -66,3 +352,56 @@ impl Packet {
         json.into_bytes()
     }
 }
+
+fn check_files(files: &[FileInfo]) -> Result<()> {
+    check_count(files.len())?;
+    files.iter().try_for_each(|f| check_name(&f.name))
+}
+
+fn check_count(count: usize) -> Result<()> {
+    match count <= MAX_ENTRIES {
+        true => Ok(()),
+        false => Err(invalid("too many entries")),
+    }
+}
+
+/// names are checked again against the directory they are joined to
+fn check_name(name: &str) -> Result<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(invalid("file name too long"));
+    }
+    if name.split('/').count() > MAX_NAME_DEPTH {
+        return Err(invalid("file name too deep"));
+    }
+    if name.contains('\0') {
+        return Err(invalid("invalid file name"));
+    }
+    Ok(())
+}
+
+/// reject deep nesting before serde_json recurses into it
+fn check_depth(buf: &[u8]) -> Result<()> {
+    let (mut depth, mut in_string, mut escaped) = (0_usize, false, false);
+    for b in buf {
+        match (in_string, b) {
+            (true, _) if escaped => escaped = false,
+            (true, b'\\') => escaped = true,
+            (true, b'"') => in_string = false,
+            (true, _) => {}
+            (false, b'"') => in_string = true,
+            (false, b'[') | (false, b'{') => {
+                depth += 1;
+                if depth > MAX_JSON_DEPTH {
+                    return Err(invalid("too deeply nested"));
+                }
+            }
+            (false, b']') | (false, b'}') => depth = depth.saturating_sub(1),
+            (false, _) => {}
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
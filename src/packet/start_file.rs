@@ -1,22 +1,26 @@
 use core::str;
-use std::usize;
 
-use serde::{Deserialize, Serialize};
 use crate::packet::file_info::FileInfo;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct StartFileData {
     pub file_info: FileInfo,
     pub index: usize,
-    pub total: usize
+    pub total: usize,
+    /// position to continue a partially received file from (0 for a fresh file)
+    #[serde(default)]
+    pub offset: u64,
 }
 
 impl StartFileData {
-    pub fn new(file_info: FileInfo, index: usize, total: usize) -> Self {
+    pub fn new(file_info: FileInfo, index: usize, total: usize, offset: u64) -> Self {
         StartFileData {
             file_info,
             index,
-            total
+            total,
+            offset,
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,51 @@
+use crate::packet::file_info::FileInfo;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// which version wins when a file exists on both sides with a different content
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// the most recently modified file wins, nothing is done when both have the same time
+    #[default]
+    Newer,
+    /// the version of the peer starting the sync wins
+    Local,
+    /// the version of the other peer wins
+    Remote,
+    /// conflicting files are left untouched on both sides
+    Skip,
+}
+
+impl ConflictPolicy {
+    /// the same policy seen from the other peer
+    pub fn mirror(self) -> Self {
+        match self {
+            ConflictPolicy::Local => ConflictPolicy::Remote,
+            ConflictPolicy::Remote => ConflictPolicy::Local,
+            p => p,
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "newer" => Ok(ConflictPolicy::Newer),
+            "local" => Ok(ConflictPolicy::Local),
+            "remote" => Ok(ConflictPolicy::Remote),
+            "skip" => Ok(ConflictPolicy::Skip),
+            _ => Err(format!("invalid conflict policy: {}", s)),
+        }
+    }
+}
+
+/// manifest of the peer starting the sync
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncRequest {
+    pub files: Vec<FileInfo>,
+    pub policy: ConflictPolicy,
+}
@@ -0,0 +1,63 @@
+use crate::packet::file_info::FileInfo;
+use crate::packet::Packet;
+use crate::server::ServerStateMachine;
+use crate::streamer::Streamer;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// client side of the share mode: list the files of a sharing peer and download some of them
+pub struct Puller<S>
+where
+    S: Read + Write,
+{
+    str: Streamer<S>,
+    out_dir: PathBuf,
+}
+
+impl<S> Puller<S>
+where
+    S: Read + Write,
+{
+    pub fn new(s: S, out_dir: &Path) -> Self {
+        Puller {
+            str: Streamer::new(s),
+            out_dir: out_dir.to_path_buf(),
+        }
+    }
+
+    /// shared files below `path` (empty for the whole shared directory)
+    pub fn list(&mut self, path: &str) -> Result<Vec<FileInfo>> {
+        self.str.write_packet(Packet::List(path.to_string()))?;
+        match self.str.read_packet()? {
+            Packet::Listing(files) => Ok(files),
+            Packet::Reject => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("cannot list {:?}", path),
+            )),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        }
+    }
+
+    /// end a session used for listing only
+    pub fn finish(&mut self) -> Result<()> {
+        self.str.write_packet(Packet::Finish).map(|_| ())
+    }
+
+    /// download the given shared files or directories, the peer becomes the sender
+    pub fn get(&mut self, names: &[String]) -> Result<()> {
+        self.str.write_packet(Packet::Get(names.to_vec()))?;
+        match self.str.read_packet()? {
+            Packet::Accept => {
+                let encoding = self.str.encoding();
+                ServerStateMachine::new(self.str.get_mut(), &self.out_dir, None)
+                    .with_encoding(encoding)
+                    .start()
+            }
+            Packet::Reject => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("cannot get {:?}", names),
+            )),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        }
+    }
+}
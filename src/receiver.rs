@@ -0,0 +1,328 @@
+use crate::cancel::CancelHandle;
+use crate::chunks::ChunkIndex;
+use crate::discovery::{self, Announcer, Beacon};
+use crate::error::{Error, Result};
+use crate::observer::Observer;
+use crate::packet::file_info::FileInfo;
+use crate::server::{AcceptPolicy, ServerStateMachine};
+use crate::tls::KeyPair;
+#[cfg(unix)]
+use crate::transport::UnixListener;
+use crate::transport::{Endpoint, Listener, PeerCredentials, PeerInfo};
+use log::{info, warn};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// what a session with a sender did
+#[derive(Debug, Clone)]
+pub struct ReceiveReport {
+    pub peer: String,
+    /// owner of the sender process, for Unix domain sockets
+    pub credentials: Option<PeerCredentials>,
+    /// a transfer to this receiver was accepted, false for rejected, share and sync sessions
+    pub accepted: bool,
+    /// the files completely received
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Clone)]
+pub struct ReceiverBuilder {
+    pub(crate) endpoint: Endpoint,
+    pub(crate) opt_listener: Option<Arc<dyn Listener>>,
+    pub(crate) out_dir: PathBuf,
+    pub(crate) opt_share: Option<PathBuf>,
+    pub(crate) opt_sync: Option<PathBuf>,
+    pub(crate) to_stdout: bool,
+    pub(crate) delta: bool,
+    pub(crate) opt_chunk_index: Option<PathBuf>,
+    pub(crate) opt_keypair: Option<KeyPair>,
+    pub(crate) opt_policy: Option<AcceptPolicy>,
+    pub(crate) opt_announce: Option<String>,
+    pub(crate) opt_observer: Option<Arc<dyn Observer>>,
+    pub(crate) cancel: CancelHandle,
+    pub(crate) opt_socket_mode: Option<u32>,
+    pub(crate) allowed_uids: Vec<u32>,
+}
+
+impl Default for ReceiverBuilder {
+    fn default() -> Self {
+        ReceiverBuilder {
+            endpoint: Endpoint::Tls(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)),
+            opt_listener: None,
+            out_dir: PathBuf::from("out"),
+            opt_share: None,
+            opt_sync: None,
+            to_stdout: false,
+            delta: true,
+            opt_chunk_index: None,
+            opt_keypair: None,
+            opt_policy: None,
+            opt_announce: None,
+            opt_observer: None,
+            cancel: CancelHandle::new(),
+            opt_socket_mode: None,
+            allowed_uids: Vec::new(),
+        }
+    }
+}
+
+impl ReceiverBuilder {
+    /// address to listen on with TLS over TCP (default: 127.0.0.1 with a port chosen by the system)
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.endpoint = Endpoint::Tls(addr);
+        self
+    }
+
+    /// endpoint to listen on, such as `tcp://0.0.0.0:7878` or `unix:/run/sendfile.sock`
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// accept connections from a custom listener instead of an endpoint
+    pub fn listener<L: Listener + 'static>(mut self, listener: L) -> Self {
+        self.opt_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// directory the received files are written to (default: `out`)
+    pub fn output_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.out_dir = dir.into();
+        self
+    }
+
+    /// let senders list and download the files of `dir`
+    pub fn share_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.opt_share = Some(dir.into());
+        self
+    }
+
+    /// let senders synchronize `dir` with one of their directories
+    pub fn sync_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.opt_sync = Some(dir.into());
+        self
+    }
+
+    /// write a single received file to the standard output instead of the output directory
+    pub fn stdout(mut self, to_stdout: bool) -> Self {
+        self.to_stdout = to_stdout;
+        self
+    }
+
+    /// ask senders for a delta of the files that differ from a copy in the output directory
+    /// instead of the whole files (default: true)
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// keep the chunks of the received files in an index at `path`, and only ask senders
+    /// splitting their files into chunks for the ones missing from it
+    pub fn chunk_index<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.opt_chunk_index = Some(path.into());
+        self
+    }
+
+    /// TLS identity presented to senders, a self-signed one is generated otherwise
+    pub fn identity(mut self, keypair: KeyPair) -> Self {
+        self.opt_keypair = Some(keypair);
+        self
+    }
+
+    /// decide which requests to receive, all of them are accepted otherwise;
+    /// the credentials of the peer are known on Unix domain sockets
+    pub fn accept_policy<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&PeerInfo, &[FileInfo]) -> bool + Send + Sync + 'static,
+    {
+        self.opt_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// advertise the receiver on the local network under `name`
+    pub fn announce(mut self, name: &str) -> Self {
+        self.opt_announce = Some(name.to_string());
+        self
+    }
+
+    pub fn observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.opt_observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// stop receiving when `cancel` asks for it: the sender is told why, the partial file is
+    /// removed and no other session is accepted
+    pub fn cancel_handle(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// permissions of the Unix domain socket file, such as 0o660 to only let the owner and the
+    /// group connect (default: the umask of the process)
+    pub fn socket_mode(mut self, mode: u32) -> Self {
+        self.opt_socket_mode = Some(mode);
+        self
+    }
+
+    /// only accept Unix domain socket connections from processes of these users
+    pub fn allow_uids<I: IntoIterator<Item = u32>>(mut self, uids: I) -> Self {
+        self.allowed_uids.extend(uids);
+        self
+    }
+
+    /// the socket options only apply when listening on a Unix domain socket endpoint
+    pub(crate) fn check_socket_options(&self) -> Result<()> {
+        let is_unix = matches!(self.endpoint, Endpoint::Unix(_)) && self.opt_listener.is_none();
+        if !is_unix && (self.opt_socket_mode.is_some() || !self.allowed_uids.is_empty()) {
+            return Err(Error::Config(String::from(
+                "socket mode and allowed users need a unix: endpoint",
+            )));
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn bind_listener(&self, keypair: &KeyPair) -> Result<Arc<dyn Listener>> {
+        if let Endpoint::Unix(path) = &self.endpoint {
+            let mut listener = UnixListener::bind(path)?;
+            if let Some(mode) = self.opt_socket_mode {
+                listener.set_mode(mode)?;
+            }
+            listener.set_allowed_uids(self.allowed_uids.clone());
+            return Ok(Arc::new(listener));
+        }
+        Ok(Arc::from(self.endpoint.bind(keypair)?))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_listener(&self, keypair: &KeyPair) -> Result<Arc<dyn Listener>> {
+        Ok(Arc::from(self.endpoint.bind(keypair)?))
+    }
+
+    pub fn build(self) -> Result<Receiver> {
+        if self.to_stdout && (self.opt_share.is_some() || self.opt_sync.is_some()) {
+            return Err(Error::Config(String::from(
+                "stdout output cannot be combined with share or sync",
+            )));
+        }
+        self.check_socket_options()?;
+        let opt_index = match self.opt_chunk_index.as_deref() {
+            Some(path) => Some(Arc::new(ChunkIndex::open(path)?)),
+            None => None,
+        };
+
+        let keypair = self.opt_keypair.clone().unwrap_or_default();
+        let listener: Arc<dyn Listener> = match self.opt_listener.clone() {
+            Some(listener) => listener,
+            None => self.bind_listener(&keypair)?,
+        };
+        let endpoint = listener.endpoint()?;
+        info!("starting server at: {}", endpoint);
+        info!("certificate fingerprint: {}", keypair.fingerprint());
+        let mut opt_announcer = None;
+        if let Some(name) = self.opt_announce {
+            let port = match endpoint {
+                Endpoint::Tls(addr) => addr.port(),
+                _ => {
+                    return Err(Error::Config(format!(
+                        "cannot announce {}, only TLS endpoints can",
+                        endpoint
+                    )))
+                }
+            };
+            let beacon = Beacon {
+                name,
+                port,
+                fingerprint: keypair.fingerprint(),
+            };
+            opt_announcer = Some(discovery::announce(beacon)?);
+        }
+        Ok(Receiver {
+            listener,
+            keypair,
+            out_dir: self.out_dir,
+            opt_share: self.opt_share,
+            opt_sync: self.opt_sync,
+            to_stdout: self.to_stdout,
+            delta: self.delta,
+            opt_index,
+            opt_policy: self.opt_policy,
+            opt_observer: self.opt_observer,
+            cancel: self.cancel,
+            opt_announcer,
+        })
+    }
+}
+
+/// listens for senders and serves one session per accepted connection
+pub struct Receiver {
+    listener: Arc<dyn Listener>,
+    keypair: KeyPair,
+    out_dir: PathBuf,
+    opt_share: Option<PathBuf>,
+    opt_sync: Option<PathBuf>,
+    to_stdout: bool,
+    delta: bool,
+    opt_index: Option<Arc<ChunkIndex>>,
+    opt_policy: Option<AcceptPolicy>,
+    opt_observer: Option<Arc<dyn Observer>>,
+    cancel: CancelHandle,
+    /// broadcasts the beacon of the receiver as long as it lives
+    opt_announcer: Option<Announcer>,
+}
+
+impl Receiver {
+    pub fn builder() -> ReceiverBuilder {
+        ReceiverBuilder::default()
+    }
+
+    /// where senders connect to, with the port chosen by the system if any
+    pub fn endpoint(&self) -> Result<Endpoint> {
+        Ok(self.listener.endpoint()?)
+    }
+
+    /// SHA-256 fingerprint of the certificate, for senders to pin
+    pub fn fingerprint(&self) -> String {
+        self.keypair.fingerprint()
+    }
+
+    /// stop broadcasting the beacon of `ReceiverBuilder::announce`, which also stops when the
+    /// receiver is dropped
+    pub fn stop_announcing(&mut self) {
+        self.opt_announcer.take();
+    }
+
+    /// wait for the next sender and serve its session
+    pub fn accept(&self) -> Result<ReceiveReport> {
+        let (str, peer) = self.listener.accept()?;
+        if let Some(reason) = self.cancel.session_reason() {
+            return Err(Error::Cancelled {
+                reason,
+                by_peer: false,
+            });
+        }
+        info!("accepted new client at: {}", peer.name);
+
+        // state machine
+        let mut sm = ServerStateMachine::new(str, &self.out_dir, self.opt_share.as_deref())
+            .with_sync_dir(self.opt_sync.as_deref())
+            .with_stdout(self.to_stdout)
+            .with_delta(self.delta)
+            .with_chunk_index(self.opt_index.clone())
+            .with_accept_policy(self.opt_policy.clone())
+            .with_peer(peer.clone())
+            .with_observer(self.opt_observer.clone())
+            .with_cancel(self.cancel.clone());
+        if let Err(e) = sm.start() {
+            warn!("session with {} failed: {}", peer.name, e);
+            return Err(e.into());
+        }
+        Ok(ReceiveReport {
+            peer: peer.name,
+            credentials: peer.credentials,
+            accepted: sm.accepted(),
+            files: sm.received().to_vec(),
+        })
+    }
+}
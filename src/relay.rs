@@ -0,0 +1,240 @@
+//! a relay for peers that cannot reach each other but both reach a third host: the receiver and
+//! the sender connect to the relay with the same session code, and the relay copies the bytes
+//! of each connection to the other one. The TLS session goes through it from end to end, so the
+//! relay can neither read the files nor pass for a receiver whose fingerprint the sender checks
+//!
+//! a peer starts with a line `sendfile-relay 1 ROLE CODE`, ROLE being `send` or `receive`, and
+//! waits for `ok ADDRESS`, the address of the other peer, or `error REASON`
+
+use crate::transport::proxy::{self, Proxy};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const GREETING: &str = "sendfile-relay 1";
+
+/// longest line of the rendezvous
+const MAX_LINE_LEN: usize = 256;
+
+const MAX_CODE_LEN: usize = 64;
+
+/// peers waiting for the other one at most
+const MAX_WAITING: usize = 1024;
+
+/// time given to a peer to say what it comes for
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// which side of a transfer a peer is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Send,
+    Receive,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Send => write!(f, "send"),
+            Role::Receive => write!(f, "receive"),
+        }
+    }
+}
+
+/// a session code: letters, digits, `-` and `_`
+pub(crate) fn check_code(code: &str) -> std::result::Result<(), String> {
+    if code.is_empty() || code.len() > MAX_CODE_LEN {
+        return Err(format!(
+            "session code of 1 to {} characters expected",
+            MAX_CODE_LEN
+        ));
+    }
+    match code
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid session code {:?}, only letters, digits, - and _",
+            code
+        )),
+    }
+}
+
+/// a peer waiting for the other one
+struct Waiting {
+    role: Role,
+    str: TcpStream,
+    addr: SocketAddr,
+}
+
+impl Waiting {
+    /// the peer may have left while waiting
+    fn is_gone(&self) -> bool {
+        let mut buf = [0_u8; 1];
+        if self.str.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let gone = !matches!(self.str.peek(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock);
+        gone || self.str.set_nonblocking(false).is_err()
+    }
+}
+
+/// pairs the peers coming with the same session code
+pub struct Relay {
+    listener: TcpListener,
+    /// by session code
+    waiting: Arc<Mutex<HashMap<String, Waiting>>>,
+}
+
+impl Relay {
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        Ok(Relay {
+            listener: TcpListener::bind(addr)?,
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// accept peers until the listener fails, each one on its own thread
+    pub fn serve(&self) -> Result<()> {
+        loop {
+            let (str, addr) = self.listener.accept()?;
+            let waiting = self.waiting.clone();
+            thread::spawn(move || {
+                if let Err(e) = join(&waiting, str, addr) {
+                    warn!("peer {}: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+/// pair the peer with the one waiting with its code, or let it wait
+fn join(
+    waiting: &Mutex<HashMap<String, Waiting>>,
+    mut str: TcpStream,
+    addr: SocketAddr,
+) -> Result<()> {
+    str.set_read_timeout(Some(JOIN_TIMEOUT))?;
+    let (role, code) = match read_line(&mut str).and_then(|line| parse_join(&line)) {
+        Ok(join) => join,
+        Err(e) => {
+            let _ = writeln!(str, "error {}", e);
+            return Err(e);
+        }
+    };
+    str.set_read_timeout(None)?;
+
+    let mut waiting = waiting.lock().unwrap_or_else(|e| e.into_inner());
+    let other = match waiting.remove(&code) {
+        Some(other) if other.is_gone() => None,
+        Some(other) if other.role == role => {
+            waiting.insert(code, other);
+            writeln!(str, "error another peer waits to {} with this code", role)?;
+            return Ok(());
+        }
+        opt_other => opt_other,
+    };
+    let mut other = match other {
+        Some(other) => other,
+        None => {
+            if waiting.len() >= MAX_WAITING {
+                waiting.retain(|_, w| !w.is_gone());
+            }
+            if waiting.len() >= MAX_WAITING {
+                writeln!(str, "error too many peers waiting")?;
+                return Ok(());
+            }
+            info!("{} waits to {} with code {}", addr, role, code);
+            waiting.insert(code, Waiting { role, str, addr });
+            return Ok(());
+        }
+    };
+    drop(waiting);
+
+    info!("pairing {} and {} with code {}", other.addr, addr, code);
+    writeln!(other.str, "ok {}", addr)?;
+    writeln!(str, "ok {}", other.addr)?;
+    splice(str, other.str)
+}
+
+/// copy the bytes of each connection to the other one until both are done
+fn splice(a: TcpStream, b: TcpStream) -> Result<()> {
+    let (a2, b2) = (a.try_clone()?, b.try_clone()?);
+    let forward = thread::spawn(move || pipe(a2, b2));
+    pipe(b, a);
+    let _ = forward.join();
+    Ok(())
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    match io::copy(&mut from, &mut to) {
+        Ok(_) => {
+            let _ = to.shutdown(Shutdown::Write);
+        }
+        Err(_) => {
+            // the other direction stops too
+            let _ = from.shutdown(Shutdown::Both);
+            let _ = to.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn parse_join(line: &str) -> Result<(Role, String)> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    let rest = line
+        .strip_prefix(GREETING)
+        .and_then(|rest| rest.strip_prefix(' '))
+        .ok_or_else(|| invalid(String::from("not a sendfile peer")))?;
+    let (role, code) = match rest.split_once(' ') {
+        Some(("send", code)) => (Role::Send, code),
+        Some(("receive", code)) => (Role::Receive, code),
+        _ => return Err(invalid(format!("unknown role in {:?}", line))),
+    };
+    check_code(code).map_err(invalid)?;
+    Ok((role, code.to_string()))
+}
+
+/// a line without its end, read byte by byte not to take what follows it
+fn read_line(str: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0_u8; 1];
+    loop {
+        str.read_exact(&mut byte)?;
+        match byte[0] {
+            b'\n' => break,
+            b if line.len() < MAX_LINE_LEN => line.push(b),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "line too long")),
+        }
+    }
+    String::from_utf8(line).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// connect to the relay at `addr`, through the proxy if any, and wait there for the other peer
+/// with `code`, returns the connection to it and its address as the relay sees it
+pub(crate) fn rendezvous(
+    addr: SocketAddr,
+    opt_proxy: Option<&Proxy>,
+    role: Role,
+    code: &str,
+) -> Result<(TcpStream, String)> {
+    let mut str = proxy::dial(addr, opt_proxy)?;
+    writeln!(str, "{} {} {}", GREETING, role, code)?;
+    let line = read_line(&mut str)?;
+    match line.split_once(' ') {
+        Some(("ok", peer)) => Ok((str, peer.to_string())),
+        Some(("error", reason)) => Err(Error::other(format!("relay {}: {}", addr, reason))),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected answer from relay {}", addr),
+        )),
+    }
+}
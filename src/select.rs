@@ -0,0 +1,348 @@
+//! which files of the directories given to the sender go: glob patterns to include or exclude,
+//! the `.gitignore` and `.ignore` files found in them, and limits of depth and size; the files
+//! given themselves always go
+//!
+//! patterns are written as in `.gitignore`: `*`, `?` and `[a-z]` match within a name, `**`
+//! matches any number of directories, a pattern without `/` matches a name at any depth, one
+//! with a `/` matches the path below the directory given, and one ending with `/` only matches
+//! directories
+
+use std::fs::{self, Metadata};
+use std::io::{ErrorKind, Result};
+use std::path::Path;
+
+/// ignore files read in each directory when honoring them, the later one wins
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// directory of git never sent when honoring the ignore files
+const GIT_DIR: &str = ".git";
+
+/// a glob pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    /// the glob split at `/`, starting with `**` for the patterns matching a name
+    segments: Vec<String>,
+    dir_only: bool,
+    /// a line of an ignore file starting with `!`, taking back what an earlier line ignores
+    negated: bool,
+}
+
+impl Pattern {
+    fn new(glob: &str) -> Self {
+        let dir_only = glob.ends_with('/');
+        let glob = glob.trim_end_matches('/');
+        let mut segments: Vec<String> = glob
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+        if !glob.contains('/') {
+            segments.insert(0, String::from("**"));
+        }
+        Pattern {
+            segments,
+            dir_only,
+            negated: false,
+        }
+    }
+
+    /// a line of an ignore file, None for blank lines and comments
+    fn from_ignore_line(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches('\r');
+        // trailing spaces are left out unless the last one is escaped
+        let trimmed = line.trim_end_matches(' ');
+        let line = match trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            true => &line[..trimmed.len() + 1],
+            false => trimmed,
+        };
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, glob) = match line.strip_prefix('!') {
+            Some(glob) => (true, glob),
+            None => (false, line),
+        };
+        let glob = match glob.strip_prefix('\\') {
+            Some(rest) if rest.starts_with('#') || rest.starts_with('!') => rest,
+            _ => glob,
+        };
+        Some(Pattern {
+            negated,
+            ..Pattern::new(glob)
+        })
+    }
+
+    /// `path` is relative, its names separated by `/`
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let names: Vec<&str> = path.split('/').collect();
+        (is_dir || !self.dir_only) && matches_names(&self.segments, &names)
+    }
+}
+
+fn matches_names(segments: &[String], names: &[&str]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        Some((segment, rest)) if segment == "**" => {
+            (0..=names.len()).any(|skip| matches_names(rest, &names[skip..]))
+        }
+        Some((segment, rest)) => match names.split_first() {
+            Some((name, names)) => glob_match(segment, name) && matches_names(rest, names),
+            None => false,
+        },
+    }
+}
+
+/// `*`, `?`, `[...]` and `\` escapes within a single name
+fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut g, mut n) = (0, 0);
+    // where the last `*` resumes: the glob after it, and the next name position it may take
+    let mut opt_star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if glob.get(g) == Some(&'*') {
+            g += 1;
+            opt_star = Some((g, n));
+            continue;
+        }
+        match match_char(&glob, g, name[n]) {
+            Some(next) => {
+                g = next;
+                n += 1;
+            }
+            None => match opt_star {
+                Some((star_g, star_n)) => {
+                    g = star_g;
+                    n = star_n + 1;
+                    opt_star = Some((star_g, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// where the glob goes on after matching `c` at `g`, None when it does not match
+fn match_char(glob: &[char], g: usize, c: char) -> Option<usize> {
+    match glob.get(g)? {
+        '?' => Some(g + 1),
+        '[' => match match_class(glob, g + 1, c) {
+            Some((true, next)) => Some(next),
+            Some((false, _)) => None,
+            // without its `]`, a `[` is itself
+            None => (c == '[').then_some(g + 1),
+        },
+        '\\' if g + 1 < glob.len() => (glob[g + 1] == c).then_some(g + 2),
+        &literal => (literal == c).then_some(g + 1),
+    }
+}
+
+/// whether the class starting at `g`, after its `[`, takes `c`, and where the glob goes on
+fn match_class(glob: &[char], mut g: usize, c: char) -> Option<(bool, usize)> {
+    let negated = matches!(glob.get(g), Some('!') | Some('^'));
+    if negated {
+        g += 1;
+    }
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let low = *glob.get(g)?;
+        if low == ']' && !first {
+            return Some((found != negated, g + 1));
+        }
+        first = false;
+        match (glob.get(g + 1), glob.get(g + 2)) {
+            (Some('-'), Some(&high)) if high != ']' => {
+                found |= low <= c && c <= high;
+                g += 3;
+            }
+            _ => {
+                found |= low == c;
+                g += 1;
+            }
+        }
+    }
+}
+
+/// the patterns of the ignore files of a directory, for the paths below it
+#[derive(Debug)]
+struct IgnoreRules {
+    /// path of the directory below the directory given, empty for that one
+    base: String,
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    fn read(dir: &Path, base: String) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for name in IGNORE_FILES {
+            match fs::read_to_string(dir.join(name)) {
+                Ok(text) => patterns.extend(text.lines().filter_map(Pattern::from_ignore_line)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(IgnoreRules { base, patterns })
+    }
+
+    /// whether the last pattern matching `path` ignores it, None when none matches
+    fn decide(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let path = match self.base.as_str() {
+            "" => path,
+            base => path.strip_prefix(base)?.strip_prefix('/')?,
+        };
+        let pattern = self
+            .patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches(path, is_dir))?;
+        Some(!pattern.negated)
+    }
+}
+
+/// what goes of the directories given to the sender, everything by default
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+    gitignore: bool,
+    opt_max_depth: Option<usize>,
+    opt_min_size: Option<u64>,
+    opt_max_size: Option<u64>,
+}
+
+impl Selection {
+    /// only send the files matching one of the included patterns, or inside a directory
+    /// matching one
+    pub fn include(mut self, glob: &str) -> Self {
+        self.includes.push(Pattern::new(glob));
+        self
+    }
+
+    /// leave out the files and directories matching the pattern, such as `target/`
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.excludes.push(Pattern::new(glob));
+        self
+    }
+
+    /// leave out what the `.gitignore` and `.ignore` files found in the directories ignore,
+    /// and the `.git` directory
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.gitignore = gitignore;
+        self
+    }
+
+    /// only send the files at most `depth` directories down, 1 for the files of the directory
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.opt_max_depth = Some(depth);
+        self
+    }
+
+    /// leave out the files smaller than `size` bytes
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.opt_min_size = Some(size);
+        self
+    }
+
+    /// leave out the files larger than `size` bytes
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.opt_max_size = Some(size);
+        self
+    }
+}
+
+/// applies a selection while walking a path given to the sender
+pub(crate) struct Walker<'a> {
+    selection: &'a Selection,
+    /// names from the path given down to the current file or directory
+    names: Vec<String>,
+    /// of the directories walked into
+    ignores: Vec<IgnoreRules>,
+}
+
+impl<'a> Walker<'a> {
+    pub(crate) fn new(selection: &'a Selection) -> Self {
+        Walker {
+            selection,
+            names: Vec::new(),
+            ignores: Vec::new(),
+        }
+    }
+
+    /// go down to the entry `name` of the current directory
+    pub(crate) fn push(&mut self, name: &str) {
+        self.names.push(name.to_string());
+    }
+
+    /// back up to the current directory
+    pub(crate) fn pop(&mut self) {
+        self.names.pop();
+    }
+
+    /// whether the current file goes
+    pub(crate) fn takes_file(&self, meta: &Metadata) -> bool {
+        if self.names.is_empty() {
+            return true;
+        }
+        let s = self.selection;
+        let path = self.names.join("/");
+        let fits = s.opt_min_size.is_none_or(|min| meta.len() >= min)
+            && s.opt_max_size.is_none_or(|max| meta.len() <= max)
+            && s.opt_max_depth.is_none_or(|max| self.names.len() <= max);
+        fits && !self.is_left_out(&path, false) && self.is_included(&path)
+    }
+
+    /// walk into the current directory at `dir` unless it is left out, false when it is;
+    /// `leave` must follow once its entries are walked
+    pub(crate) fn enter(&mut self, dir: &Path) -> Result<bool> {
+        let path = self.names.join("/");
+        if !self.names.is_empty() {
+            let s = self.selection;
+            let too_deep = s.opt_max_depth.is_some_and(|max| self.names.len() >= max);
+            let git_dir = s.gitignore && self.names.last().is_some_and(|n| n == GIT_DIR);
+            if too_deep || git_dir || self.is_left_out(&path, true) {
+                return Ok(false);
+            }
+        }
+        if self.selection.gitignore {
+            self.ignores.push(IgnoreRules::read(dir, path)?);
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn leave(&mut self) {
+        if self.selection.gitignore {
+            self.ignores.pop();
+        }
+    }
+
+    fn is_left_out(&self, path: &str, is_dir: bool) -> bool {
+        self.selection
+            .excludes
+            .iter()
+            .any(|p| p.matches(path, is_dir))
+            || self
+                .ignores
+                .iter()
+                .rev()
+                .find_map(|rules| rules.decide(path, is_dir))
+                .unwrap_or(false)
+    }
+
+    /// the file at `path` or one of its directories matches an included pattern
+    fn is_included(&self, path: &str) -> bool {
+        let includes = &self.selection.includes;
+        if includes.is_empty() {
+            return true;
+        }
+        let mut prefixes = path
+            .match_indices('/')
+            .map(|(i, _)| (&path[..i], true))
+            .collect::<Vec<_>>();
+        prefixes.push((path, false));
+        prefixes
+            .iter()
+            .any(|&(prefix, is_dir)| includes.iter().any(|p| p.matches(prefix, is_dir)))
+    }
+}
@@ -0,0 +1,369 @@
+use crate::cancel::CancelHandle;
+use crate::client::{ClientOutcome, ClientStateMachine};
+use crate::discovery;
+use crate::error::{Cancelled, Error, Integrity, Result, Unversioned};
+use crate::observer::Observer;
+use crate::packet::file_info::FileInfo;
+use crate::select::Selection;
+use crate::source::{self, SourceFile};
+use crate::transport::{self, Endpoint, Proxy, Transport};
+use log::{error, info, warn};
+use std::cmp::min;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// how long the sender listens for beacons when looking up a peer by name
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how the sender reconnects after a failed connection or a broken session
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// delay before the first retry, doubled after each failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// what a completed send did
+#[derive(Debug, Clone)]
+pub struct SendReport {
+    pub peer: String,
+    /// the files received by the peer
+    pub files: Vec<FileInfo>,
+    /// files of `files` the peer already had, they were not sent again
+    pub skipped: usize,
+    /// connections needed, 1 when nothing failed
+    pub attempts: u32,
+}
+
+pub(crate) enum Target {
+    Endpoint(Endpoint),
+    Peer(String),
+    Transport(Arc<dyn Transport>),
+}
+
+#[derive(Default)]
+pub struct SenderBuilder {
+    opt_target: Option<Target>,
+    paths: Vec<PathBuf>,
+    selection: Selection,
+    retry: RetryPolicy,
+    stdin_name: Option<String>,
+    opt_fingerprint: Option<String>,
+    opt_proxy: Option<Proxy>,
+    checksum: bool,
+    dedup: bool,
+    zero_copy: bool,
+    opt_observer: Option<Arc<dyn Observer>>,
+    cancel: CancelHandle,
+}
+
+impl SenderBuilder {
+    /// the target is a QUIC endpoint, which only the async sender reaches
+    #[cfg(feature = "quic")]
+    pub(crate) fn is_quic(&self) -> bool {
+        matches!(self.opt_target, Some(Target::Endpoint(Endpoint::Quic(_))))
+    }
+
+    /// address of the receiver, reached with TLS over TCP
+    pub fn address(mut self, addr: SocketAddr) -> Self {
+        self.opt_target = Some(Target::Endpoint(Endpoint::Tls(addr)));
+        self
+    }
+
+    /// endpoint of the receiver, such as `tcp://10.0.0.2:7878` or `unix:/run/sendfile.sock`
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.opt_target = Some(Target::Endpoint(endpoint));
+        self
+    }
+
+    /// connect with a custom transport
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.opt_target = Some(Target::Transport(Arc::new(transport)));
+        self
+    }
+
+    /// name of a receiver announcing itself on the local network, its certificate is pinned
+    pub fn peer(mut self, name: &str) -> Self {
+        self.opt_target = Some(Target::Peer(name.to_string()));
+        self
+    }
+
+    /// a file or directory to send, `-` for the standard input
+    pub fn file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+
+    pub fn files<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// which files of the directories go, all of them by default
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// name given to the standard input on the receiver (default: stdin)
+    pub fn stdin_name(mut self, name: &str) -> Self {
+        self.stdin_name = Some(name.to_string());
+        self
+    }
+
+    /// only trust a receiver presenting the certificate with this SHA-256 fingerprint
+    pub fn fingerprint(mut self, fingerprint: &str) -> Self {
+        self.opt_fingerprint = Some(fingerprint.to_string());
+        self
+    }
+
+    /// connect through a SOCKS5 or HTTP proxy, the TLS session still goes to the receiver;
+    /// not for custom transports
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.opt_proxy = Some(proxy);
+        self
+    }
+
+    /// hash the files before sending them, so that the receiver only skips the files it has
+    /// with the same content, and not just with the same size and modification time
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// split the files into content-defined chunks for a receiver keeping an index of chunks,
+    /// so that it is only sent the chunks it does not have, from any file
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// on Linux, over plaintext TCP and Unix domain sockets, let the kernel copy the files to
+    /// the socket with `sendfile(2)` instead of reading them into the sender
+    pub fn zero_copy(mut self, zero_copy: bool) -> Self {
+        self.zero_copy = zero_copy;
+        self
+    }
+
+    pub fn observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.opt_observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// stop sending when `cancel` asks for it, telling the receiver why
+    pub fn cancel_handle(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// an observer shared with other transfers
+    pub(crate) fn with_observer(mut self, opt_observer: Option<Arc<dyn Observer>>) -> Self {
+        self.opt_observer = opt_observer;
+        self
+    }
+
+    pub fn build(self) -> Result<Sender> {
+        let target = self
+            .opt_target
+            .ok_or_else(|| Error::Config(String::from("no receiver address or peer")))?;
+        if self.paths.is_empty() {
+            return Err(Error::Config(String::from("no file to send")));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(Error::Config(String::from(
+                "at least one attempt is needed",
+            )));
+        }
+        // the relay could terminate TLS and pass for any receiver
+        if let (Target::Endpoint(Endpoint::Relay(..)), None) = (&target, &self.opt_fingerprint) {
+            return Err(Error::Config(String::from(
+                "a receiver behind a relay needs the fingerprint of its certificate",
+            )));
+        }
+        Ok(Sender {
+            target,
+            paths: self.paths,
+            selection: self.selection,
+            retry: self.retry,
+            stdin_name: self.stdin_name.unwrap_or_else(|| String::from("stdin")),
+            opt_fingerprint: self.opt_fingerprint,
+            opt_proxy: self.opt_proxy,
+            checksum: self.checksum,
+            dedup: self.dedup,
+            zero_copy: self.zero_copy,
+            opt_observer: self.opt_observer,
+            cancel: self.cancel,
+        })
+    }
+}
+
+/// sends files to a receiver, reconnecting and resuming when the connection breaks
+pub struct Sender {
+    pub(crate) target: Target,
+    pub(crate) paths: Vec<PathBuf>,
+    pub(crate) selection: Selection,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) stdin_name: String,
+    pub(crate) opt_fingerprint: Option<String>,
+    pub(crate) opt_proxy: Option<Proxy>,
+    pub(crate) checksum: bool,
+    pub(crate) dedup: bool,
+    pub(crate) zero_copy: bool,
+    pub(crate) opt_observer: Option<Arc<dyn Observer>>,
+    pub(crate) cancel: CancelHandle,
+}
+
+impl Sender {
+    pub fn builder() -> SenderBuilder {
+        SenderBuilder::default()
+    }
+
+    pub fn send(&self) -> Result<SendReport> {
+        let transport = match &self.target {
+            Target::Endpoint(endpoint) => {
+                endpoint.transport_via(self.opt_fingerprint.as_deref(), self.opt_proxy.as_ref())?
+            }
+            Target::Peer(name) => {
+                let peer = discovery::resolve(name, DISCOVERY_TIMEOUT)?;
+                info!("resolved '{}' to {}", peer.name, peer.addr);
+                Endpoint::Tls(peer.addr)
+                    .transport_via(Some(&peer.fingerprint), self.opt_proxy.as_ref())?
+            }
+            Target::Transport(transport) => transport.clone(),
+        };
+        let peer = transport.endpoint().to_string();
+        info!("sending files: {:?} to {}", self.paths, peer);
+
+        // check all files are exists, and expand directories
+        let files = source::collect_args(&self.paths, &self.stdin_name, &self.selection)?;
+        let infos = files
+            .iter()
+            .map(SourceFile::info)
+            .collect::<io::Result<Vec<FileInfo>>>()?;
+
+        let retry = &self.retry;
+        let mut finished = vec![false; files.len()];
+        let mut stdin_consumed = false;
+        let mut skipped = 0;
+        let mut backoff = retry.initial_backoff;
+        let mut attempt = 1;
+        // the receiver hung up on Hello, it is asked again in JSON
+        let mut legacy = false;
+        loop {
+            if let Some(reason) = self.cancel.session_reason() {
+                return Err(Error::Cancelled {
+                    reason,
+                    by_peer: false,
+                });
+            }
+            let connection = if self.zero_copy {
+                transport
+                    .connect_raw()
+                    .map(|(s, opt_socket)| (s, opt_socket, opt_socket.and_then(transport::probe)))
+            } else {
+                transport
+                    .connect_probed()
+                    .map(|(s, opt_probe)| (s, None, opt_probe))
+            };
+            let res = connection.and_then(|(s, opt_socket, opt_probe)| {
+                let mut cm = if attempt == 1 {
+                    ClientStateMachine::new(s, &files)
+                } else {
+                    ClientStateMachine::resume(s, &files, &finished)
+                }
+                .with_legacy(legacy)
+                .with_checksum(self.checksum)
+                .with_dedup(self.dedup)
+                .with_socket(opt_socket)
+                .with_probe(opt_probe)
+                .with_observer(self.opt_observer.clone())
+                .with_cancel(self.cancel.clone());
+                let res = cm.start();
+                finished = cm.finished().to_vec();
+                skipped += cm.skipped();
+                stdin_consumed = cm.stdin_consumed();
+                res
+            });
+
+            match res {
+                Ok(ClientOutcome::Finished) => {
+                    info!(
+                        "sent {} file(s) to {}, {} already there",
+                        files.len() - skipped,
+                        peer,
+                        skipped
+                    );
+                    return Ok(SendReport {
+                        peer,
+                        files: infos,
+                        skipped,
+                        attempts: attempt,
+                    });
+                }
+                Ok(ClientOutcome::Rejected) => return Err(Error::Rejected),
+                Err(e) if Unversioned::is(&e) && !legacy => {
+                    info!(
+                        "{} does not answer Hello, speaking protocol version 1",
+                        peer
+                    );
+                    legacy = true;
+                }
+                Err(e) if attempt >= retry.max_attempts || !is_retryable(&e) || stdin_consumed => {
+                    let done = finished.iter().filter(|f| **f).count();
+                    error!(
+                        "sending failed after {} attempt(s), {}/{} file(s) sent: {}",
+                        attempt,
+                        done,
+                        files.len(),
+                        e
+                    );
+                    return Err(Error::partial(e.into(), done, files.len()));
+                }
+                Err(e) => {
+                    warn!(
+                        "attempt {}/{} failed: {}, reconnecting in {:?}",
+                        attempt, retry.max_attempts, e, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = min(backoff * 2, retry.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// local problems (missing or unreadable files) are not fixed by reconnecting, nor data the
+/// receiver found different from what was announced
+pub(crate) fn is_retryable(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::InvalidInput
+    ) && !Cancelled::is(err)
+        && !Integrity::is(err)
+}
@@ -1,14 +1,36 @@
+use crate::cancel::CancelHandle;
+use crate::chunks::{self, Assembly, ChunkIndex};
+use crate::client::ClientStateMachine;
+use crate::delta::{self, Basis};
+use crate::error::{Cancelled, Integrity};
+use crate::observer::{Event, Observer};
+use crate::packet::cancel::{CancelData, CancelScope};
+use crate::packet::chunks::ChunkInfo;
+use crate::packet::delta::{CopyData, SignatureData};
 use crate::packet::file_info::FileInfo;
+use crate::packet::hole::HoleData;
 use crate::packet::start_file::StartFileData;
-use crate::packet::Packet;
+use crate::packet::sync_request::SyncRequest;
+use crate::packet::{Encoding, Packet};
+use crate::source::{self, ContentHash, SourceFile};
+use crate::sparse;
 use crate::streamer::Streamer;
-use log::debug;
+use crate::sync;
+use crate::transport::PeerInfo;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{File, OpenOptions},
-    io::{BufWriter, Read, Write},
-    path::Path,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
+/// decide whether to receive the files a peer asks to send, knowing who the peer is
+pub type AcceptPolicy = Arc<dyn Fn(&PeerInfo, &[FileInfo]) -> bool + Send + Sync>;
+
 #[derive(Debug)]
 enum ServerState {
     Init,
@@ -16,6 +38,8 @@ enum ServerState {
     WaitForFile,
     StartReceivingFile,
     ReceiveFileData,
+    /// the current file was cancelled here, its remaining data is dropped
+    DiscardFileData,
     EndReceivingFile,
     Finish,
     Error,
@@ -28,26 +52,153 @@ where
     state: ServerState,
     str: Streamer<S>,
     files: Vec<FileInfo>,
-    opt_writer: Option<BufWriter<File>>,
+    opt_writer: Option<BufWriter<Box<dyn Write>>>,
+    /// the `.part` file behind the writer, where holes are left
+    opt_part: Option<File>,
+    opt_path: Option<PathBuf>,
+    opt_mtime: Option<u64>,
+    opt_current: Option<(usize, FileInfo)>,
+    opt_hash: Option<ContentHash>,
+    /// the copy the current file is rebuilt from, when its delta is received
+    opt_basis: Option<Basis>,
+    /// the chunks the current file is rebuilt from, when they were announced
+    opt_assembly: Option<Assembly>,
+    received_size: u64,
+    received: Vec<FileInfo>,
+    accepted: bool,
+    resume: bool,
+    /// how much of each file ResumeAt offered to keep, the only offsets a file starts at
+    offers: Vec<Option<u64>>,
+    delta: bool,
+    /// signatures sent for the files of the request, by index
+    signatures: HashMap<usize, SignatureData>,
+    opt_index: Option<Arc<ChunkIndex>>,
+    /// Dedup was sent, the sender may announce the chunks of its files
+    dedup: bool,
+    out_dir: PathBuf,
+    opt_share: Option<PathBuf>,
+    opt_sync: Option<PathBuf>,
+    to_stdout: bool,
+    opt_policy: Option<AcceptPolicy>,
+    peer: PeerInfo,
+    opt_observer: Option<Arc<dyn Observer>>,
+    cancel: CancelHandle,
+    opt_error: Option<Error>,
 }
 
 impl<S> ServerStateMachine<S>
 where
     S: Read + Write,
 {
-    pub fn new(s: S) -> Self {
+    /// receive files into `out_dir`, and serve the files of `opt_share` read-only if any
+    pub fn new(s: S, out_dir: &Path, opt_share: Option<&Path>) -> Self {
         ServerStateMachine {
             state: ServerState::Init,
             str: Streamer::new(s),
             files: Vec::new(),
             opt_writer: None,
+            opt_part: None,
+            opt_path: None,
+            opt_mtime: None,
+            opt_current: None,
+            opt_hash: None,
+            opt_basis: None,
+            opt_assembly: None,
+            received_size: 0,
+            received: Vec::new(),
+            accepted: false,
+            resume: false,
+            offers: Vec::new(),
+            delta: true,
+            signatures: HashMap::new(),
+            opt_index: None,
+            dedup: false,
+            out_dir: out_dir.to_path_buf(),
+            opt_share: opt_share.map(Path::to_path_buf),
+            opt_sync: None,
+            to_stdout: false,
+            opt_policy: None,
+            peer: PeerInfo::new(String::new()),
+            opt_observer: None,
+            cancel: CancelHandle::new(),
+            opt_error: None,
         }
     }
 
+    /// let clients synchronize `dir` with one of their directories
+    pub fn with_sync_dir(mut self, dir: Option<&Path>) -> Self {
+        self.opt_sync = dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// write the received file to the standard output instead of `out_dir`,
+    /// requests for more than one file are rejected
+    pub fn with_stdout(mut self, to_stdout: bool) -> Self {
+        self.to_stdout = to_stdout;
+        self
+    }
+
+    /// ask `policy` before receiving files, everything is accepted otherwise
+    pub fn with_accept_policy(mut self, opt_policy: Option<AcceptPolicy>) -> Self {
+        self.opt_policy = opt_policy;
+        self
+    }
+
+    /// who opened the connection, given to the accept policy
+    pub fn with_peer(mut self, peer: PeerInfo) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    pub fn with_observer(mut self, opt_observer: Option<Arc<dyn Observer>>) -> Self {
+        self.opt_observer = opt_observer;
+        self
+    }
+
+    /// stop receiving when `cancel` asks for it, removing the partial file
+    pub fn with_cancel(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// take over a session whose encoding is already negotiated
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.str.set_encoding(encoding);
+        self
+    }
+
+    /// ask for a delta of the files that differ from a copy in `out_dir`, on by default;
+    /// the copy must stay the same until the file is received
+    pub fn with_delta(mut self, delta: bool) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// only ask for the chunks of a file missing from `opt_index`, and add the chunks of the
+    /// received files to it
+    pub(crate) fn with_chunk_index(mut self, opt_index: Option<Arc<ChunkIndex>>) -> Self {
+        self.opt_index = opt_index;
+        self
+    }
+
     /// start the state machine
-    pub fn start(&mut self) {
+    pub fn start(&mut self) -> Result<()> {
         self.state = ServerState::Init;
-        self.next()
+        self.next();
+        match self.opt_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// a transfer was accepted during the session
+    pub fn accepted(&self) -> bool {
+        self.accepted
+    }
+
+    /// files completely received during the session
+    pub fn received(&self) -> &[FileInfo] {
+        &self.received
     }
 
     /// state machine for server (receiver)
@@ -63,46 +214,118 @@ where
                     match self.str.read_packet() {
                         Ok(Packet::Send(data)) => {
                             data.iter().for_each(|f| self.files.push(f.clone()));
+                            self.resume = false;
                             self.state = ServerState::InternalAnswer;
                         }
-                        _ => self.error(),
+                        Ok(Packet::Resume(data)) => {
+                            data.iter().for_each(|f| self.files.push(f.clone()));
+                            self.resume = true;
+                            self.state = ServerState::InternalAnswer;
+                        }
+                        Ok(Packet::List(path)) => self.process_list(path),
+                        Ok(Packet::Get(names)) => self.process_get(names),
+                        Ok(Packet::Sync(request)) => self.process_sync(request),
+                        Ok(Packet::Finish) => self.state = ServerState::Finish,
+                        res => self.unexpected(res),
                     }
                 }
                 ServerState::InternalAnswer => {
                     debug!("internal answer for request: {:?}", self.files);
 
                     // send accept of cancel
-                    let is_accepted = true;
+                    self.notify(Event::Requested {
+                        files: self.files.clone(),
+                    });
+                    let is_accepted = is_accepted(
+                        &self.peer,
+                        &self.files,
+                        self.to_stdout,
+                        self.opt_policy.as_ref(),
+                    );
                     if is_accepted {
-                        match self.str.write_packet(Packet::Accept) {
+                        self.accepted = true;
+                        self.notify(Event::Accepted);
+                        self.dedup = self.opt_index.is_some()
+                            && self.str.encoding().version() >= 2
+                            && !self.to_stdout;
+                        if self.dedup {
+                            if let Err(e) = self.str.write_packet(Packet::Dedup) {
+                                self.error(e);
+                                continue;
+                            }
+                        }
+                        let answer = if self.resume {
+                            let kept = kept_sizes(&self.out_dir, &self.files, self.to_stdout);
+                            self.offers = kept.clone();
+                            Packet::ResumeAt(kept)
+                        } else if self.str.encoding().version() >= 2 && !self.to_stdout {
+                            let have = identical_files(&self.out_dir, &self.files);
+                            self.notify_skipped(&have);
+                            if let Err(e) = self.send_signatures(&have) {
+                                self.error(e);
+                                continue;
+                            }
+                            have_answer(have)
+                        } else {
+                            Packet::Accept
+                        };
+                        match self.str.write_packet(answer) {
                             Ok(_) => self.state = ServerState::WaitForFile,
-                            Err(_) => self.error(),
+                            Err(e) => self.error(e),
                         }
                     } else {
+                        self.notify(Event::Rejected);
                         match self.str.write_packet(Packet::Reject) {
                             Ok(_) => self.state = ServerState::Finish,
-                            Err(_) => self.error(),
+                            Err(e) => self.error(e),
                         }
                     };
                 }
-                ServerState::WaitForFile => match self.str.read_packet() {
-                    Ok(Packet::StartFile(data)) => self.process_start_file(data),
-                    _ => self.error(),
-                },
-                ServerState::StartReceivingFile => match self.str.read_packet() {
-                    Ok(Packet::FileData(data)) => self.process_file_data(data),
-                    _ => self.error(),
-                },
-                ServerState::ReceiveFileData => match self.str.read_packet() {
-                    Ok(Packet::EndFile) => self.process_end_file(),
-                    Ok(Packet::FileData(data)) => self.process_file_data(data),
-                    _ => self.error(),
-                },
-                ServerState::EndReceivingFile => match self.str.read_packet() {
-                    Ok(Packet::StartFile(data)) => self.process_start_file(data),
-                    Ok(Packet::Finish) => self.state = ServerState::Finish,
-                    _ => self.error(),
+                ServerState::WaitForFile
+                | ServerState::StartReceivingFile
+                | ServerState::ReceiveFileData
+                | ServerState::DiscardFileData
+                | ServerState::EndReceivingFile
+                    if self.cancel.is_cancelled() =>
+                {
+                    self.process_cancel()
+                }
+                ServerState::StartReceivingFile | ServerState::ReceiveFileData => {
+                    if let Some(reason) = self.cancel.take_file_reason() {
+                        self.process_cancel_file(&reason);
+                        continue;
+                    }
+                    match self.str.read_packet() {
+                        Ok(Packet::FileData(data)) => self.process_file_data(data),
+                        Ok(Packet::Copy(data)) => self.process_copy(data),
+                        Ok(Packet::Chunks(chunks)) => self.process_chunks(chunks),
+                        Ok(Packet::Hole(data)) => self.process_hole(data),
+                        Ok(Packet::EndFile) => self.process_end_file(),
+                        Ok(Packet::Cancel(data)) => self.process_cancelled(data),
+                        res => self.unexpected(res),
+                    }
+                }
+                ServerState::DiscardFileData => match self.str.read_packet() {
+                    Ok(Packet::FileData(_)) | Ok(Packet::Copy(_)) | Ok(Packet::Hole(_)) => {}
+                    // the sender waits for an answer, nothing is wanted
+                    Ok(Packet::Chunks(chunks)) if self.dedup => {
+                        let want = vec![false; chunks.len()];
+                        if let Err(e) = self.str.write_packet(Packet::Want(want)) {
+                            self.error(e);
+                        }
+                    }
+                    Ok(Packet::EndFile) => self.state = ServerState::EndReceivingFile,
+                    Ok(Packet::Cancel(data)) => self.process_cancelled(data),
+                    res => self.unexpected(res),
                 },
+                ServerState::WaitForFile | ServerState::EndReceivingFile => {
+                    match self.str.read_packet() {
+                        Ok(Packet::StartFile(data)) => self.process_start_file(data),
+                        Ok(Packet::Finish) => self.process_finish(),
+                        Ok(Packet::Cancel(data)) => self.process_cancelled(data),
+                        res => self.unexpected(res),
+                    }
+                }
                 ServerState::Finish => break,
                 ServerState::Error => break,
             }
@@ -111,38 +334,574 @@ where
         self.close()
     }
 
+    /// answer with the shared files below `path`, named relative to the shared directory
+    fn process_list(&mut self, path: String) {
+        debug!("list shared files: {:?}", path);
+        let answer = match self.list_shared(&path) {
+            Ok(files) => Packet::Listing(files),
+            Err(e) => {
+                info!("cannot list {:?}: {}", path, e);
+                Packet::Reject
+            }
+        };
+
+        // wait for more requests, a listing too large for a packet is refused before
+        // anything is written
+        let res = match self.str.write_packet(answer) {
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                info!("cannot list {:?}: {}", path, e);
+                self.str.write_packet(Packet::Reject)
+            }
+            res => res,
+        };
+        match res {
+            Ok(_) => self.state = ServerState::Init,
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// send the requested shared files, acting as the sender for the rest of the session
+    fn process_get(&mut self, names: Vec<String>) {
+        debug!("get shared files: {:?}", names);
+        let files = match self.shared_files(&names) {
+            Ok(files) => files,
+            Err(e) => {
+                info!("cannot share {:?}: {}", names, e);
+                match self.str.write_packet(Packet::Reject) {
+                    Ok(_) => self.state = ServerState::Finish,
+                    Err(e) => self.error(e),
+                }
+                return;
+            }
+        };
+
+        if let Err(e) = self.str.write_packet(Packet::Accept) {
+            self.error(e);
+            return;
+        }
+        let encoding = self.str.encoding();
+        let mut sender = ClientStateMachine::new(self.str.get_mut(), &files)
+            .with_encoding(encoding)
+            .with_observer(self.opt_observer.clone())
+            .with_cancel(self.cancel.clone());
+        match sender.start() {
+            Ok(_) => self.state = ServerState::Finish,
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// answer with the local manifest, then exchange the files both peers planned
+    fn process_sync(&mut self, request: SyncRequest) {
+        let dir = match self.opt_sync.clone() {
+            Some(dir) => dir,
+            None => {
+                info!("cannot sync: sync is disabled");
+                match self.str.write_packet(Packet::Reject) {
+                    Ok(_) => self.state = ServerState::Finish,
+                    Err(e) => self.error(e),
+                }
+                return;
+            }
+        };
+
+        let res = sync::manifest(&dir).and_then(|(files, local)| {
+            self.str.write_packet(Packet::Listing(local.clone()))?;
+            let plan = sync::plan(&local, &request.files, request.policy.mirror());
+            debug!("sync plan: {:?}", plan);
+            let to_send = sync::files_to_send(&files, &plan);
+            let encoding = self.str.encoding();
+            sync::transfer(
+                self.str.get_mut(),
+                encoding,
+                &dir,
+                &to_send,
+                sync::Incoming {
+                    expected: sync::files_to_receive(&request.files, &plan),
+                    peer: self.peer.clone(),
+                    opt_policy: self.opt_policy.clone(),
+                },
+                false,
+                self.opt_observer.clone(),
+            )
+        });
+        match res {
+            Ok(_) => self.state = ServerState::Finish,
+            Err(e) => self.error(e),
+        }
+    }
+
+    fn list_shared(&self, path: &str) -> Result<Vec<FileInfo>> {
+        let share = self.share_dir()?;
+        let dir = if path.is_empty() {
+            share.to_path_buf()
+        } else {
+            source::safe_join(share, path)?
+        };
+        source::collect_dir(&dir)?
+            .into_iter()
+            .filter(|f| self.is_shared(&f.path))
+            .map(|f| {
+                let mut info = f.info()?;
+                info.name = match (path.is_empty(), info.name.is_empty()) {
+                    (true, _) => info.name,
+                    (false, true) => path.to_string(),
+                    (false, false) => format!("{}/{}", path, info.name),
+                };
+                Ok(info)
+            })
+            .collect()
+    }
+
+    fn shared_files(&self, names: &[String]) -> Result<Vec<SourceFile>> {
+        let share = self.share_dir()?;
+        let paths = names
+            .iter()
+            .map(|n| source::safe_join(share, n))
+            .collect::<Result<Vec<PathBuf>>>()?;
+        let files: Vec<SourceFile> = source::collect(&paths)?
+            .into_iter()
+            .filter(|f| self.is_shared(&f.path))
+            .collect();
+        if files.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, "no shared file"));
+        }
+        Ok(files)
+    }
+
+    fn share_dir(&self) -> Result<&Path> {
+        self.opt_share
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "sharing is disabled"))
+    }
+
+    /// symbolic links must not expose files outside of the shared directory
+    fn is_shared(&self, path: &Path) -> bool {
+        let share = match self.opt_share.as_ref().map(fs::canonicalize) {
+            Some(Ok(share)) => share,
+            _ => return false,
+        };
+        match fs::canonicalize(path) {
+            Ok(p) => p.starts_with(share),
+            Err(_) => false,
+        }
+    }
+
     fn process_start_file(&mut self, data: StartFileData) {
         debug!("start receiving file: {:?}", data);
-        let path = Path::new("out").join(data.file_info.name);
-        let opts = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&path)
-            .unwrap();
+        if let Err(e) = check_start_file(&self.files, &self.offers, &data) {
+            self.error(e);
+            return;
+        }
+        self.notify(Event::FileStarted {
+            index: data.index,
+            file: data.file_info.clone(),
+        });
+        self.opt_current = Some((data.index, data.file_info.clone()));
+        self.received_size = data.offset;
+        self.opt_basis = None;
+        self.opt_assembly = None;
+        self.opt_part = None;
+        if self.to_stdout {
+            self.opt_writer = Some(BufWriter::new(Box::new(io::stdout())));
+            self.opt_hash = Some(ContentHash::new());
+            self.state = ServerState::StartReceivingFile;
+            return;
+        }
 
-        self.opt_writer = Some(BufWriter::new(opts));
-        self.state = ServerState::StartReceivingFile
+        // a file resumed from an offset is sent as it is
+        let opt_signature = self
+            .signatures
+            .remove(&data.index)
+            .filter(|_| data.offset == 0);
+        let res = source::safe_join(&self.out_dir, &data.file_info.name).and_then(|path| {
+            let file = open_part(&path, &data.file_info, data.offset)?;
+            let part = file.try_clone()?;
+            let hash = part_hash(&path, data.offset)?;
+            let opt_basis = match opt_signature {
+                Some(signature) => Some(Basis::open(&path, signature)?),
+                None => None,
+            };
+            Ok((path, file, part, hash, opt_basis))
+        });
+        match res {
+            Ok((path, file, part, hash, opt_basis)) => {
+                self.opt_writer = Some(BufWriter::new(Box::new(file)));
+                self.opt_part = Some(part);
+                self.opt_hash = Some(hash);
+                self.opt_basis = opt_basis;
+                self.opt_path = Some(path);
+                self.opt_mtime = data.file_info.mtime;
+                self.state = ServerState::StartReceivingFile
+            }
+            Err(e) => self.error(e),
+        }
     }
 
     fn process_file_data(&mut self, data: Vec<u8>) {
+        if self.opt_assembly.is_some() {
+            return self.process_chunk_data(data);
+        }
+        if let Some((_, file)) = self.opt_current.as_ref() {
+            if let Err(e) = check_received(file, self.received_size + data.len() as u64) {
+                return self.error(e);
+            }
+        }
         if let Some(writer) = self.opt_writer.as_mut() {
-            match writer.write(&data) {
-                Ok(_) => self.state = ServerState::ReceiveFileData,
-                Err(_) => self.error(),
+            match writer.write_all(&data) {
+                Ok(_) => {
+                    self.received_size += data.len() as u64;
+                    if let Some(hash) = self.opt_hash.as_mut() {
+                        hash.update(&data);
+                    }
+                    if let Some((index, _)) = self.opt_current {
+                        self.notify(Event::Progress {
+                            index,
+                            bytes: self.received_size,
+                        });
+                    }
+                    self.state = ServerState::ReceiveFileData
+                }
+                Err(e) => self.error(e),
             }
         } else {
-            self.error()
+            self.unexpected(Ok(Packet::FileData(data)))
+        }
+    }
+
+    /// write blocks of the copy of the file here, as the delta sent by the sender says
+    fn process_copy(&mut self, data: CopyData) {
+        let (basis, writer) = match (self.opt_basis.as_mut(), self.opt_writer.as_mut()) {
+            (Some(basis), Some(writer)) => (basis, writer),
+            _ => return self.unexpected(Ok(Packet::Copy(data))),
+        };
+        let opt_hash = &mut self.opt_hash;
+        let res = basis.copy(&data, |block| {
+            writer.write_all(block)?;
+            if let Some(hash) = opt_hash.as_mut() {
+                hash.update(block);
+            }
+            Ok(())
+        });
+        match res {
+            Ok(len) => {
+                self.received_size += len;
+                if let Some((_, file)) = self.opt_current.as_ref() {
+                    if let Err(e) = check_received(file, self.received_size) {
+                        return self.error(e);
+                    }
+                }
+                if let Some((index, _)) = self.opt_current {
+                    self.notify(Event::Progress {
+                        index,
+                        bytes: self.received_size,
+                    });
+                }
+                self.state = ServerState::ReceiveFileData
+            }
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// leave a hole in the `.part` file, or write its zeros to the standard output
+    fn process_hole(&mut self, hole: HoleData) {
+        let is_valid = match self.opt_current.as_ref() {
+            Some((_, file)) => {
+                hole.offset == self.received_size
+                    && hole.len <= file.size.saturating_sub(self.received_size)
+                    && !file.streamed
+                    && self.opt_assembly.is_none()
+            }
+            None => false,
+        };
+        let writer = match self.opt_writer.as_mut() {
+            Some(writer) if is_valid => writer,
+            _ => return self.error(Error::new(ErrorKind::InvalidData, "invalid hole")),
+        };
+        let res = match self.opt_part.as_ref() {
+            Some(part) => writer.flush().and_then(|_| sparse::skip(part, hole.len)),
+            None => sparse::write_zeros(writer, hole.len),
+        };
+        if let Some(hash) = self.opt_hash.as_mut() {
+            hash.update_zeros(hole.len);
+        }
+        self.received_size += hole.len;
+        self.after_write(res)
+    }
+
+    /// answer with the chunks missing from the index, then write the ones it has up to the
+    /// first one wanted
+    fn process_chunks(&mut self, chunks: Vec<ChunkInfo>) {
+        let (index, size) = match (self.opt_index.clone(), self.opt_current.as_ref()) {
+            (Some(index), Some((_, file)))
+                if self.dedup
+                    && self.opt_path.is_some()
+                    && self.received_size == 0
+                    && matches!(self.state, ServerState::StartReceivingFile) =>
+            {
+                (index, file.size)
+            }
+            _ => return self.unexpected(Ok(Packet::Chunks(chunks))),
+        };
+        if chunks.iter().map(|c| u64::from(c.len)).sum::<u64>() != size {
+            return self.error(Error::new(
+                ErrorKind::InvalidData,
+                "chunks do not match the size",
+            ));
+        }
+        let mut assembly = Assembly::new(index, chunks);
+        if let Err(e) = self
+            .str
+            .write_packet(Packet::Want(assembly.want().to_vec()))
+        {
+            return self.error(e);
+        }
+        let res = self.write_chunks(|assembly, write| assembly.fill(write), &mut assembly);
+        self.opt_assembly = Some(assembly);
+        self.after_write(res)
+    }
+
+    /// the content of the wanted chunks, followed by the ones the index has
+    fn process_chunk_data(&mut self, data: Vec<u8>) {
+        let mut assembly = match self.opt_assembly.take() {
+            Some(assembly) => assembly,
+            None => return self.unexpected(Ok(Packet::FileData(data))),
+        };
+        let res = self.write_chunks(|assembly, write| assembly.data(&data, write), &mut assembly);
+        self.opt_assembly = Some(assembly);
+        self.after_write(res)
+    }
+
+    fn write_chunks<F>(&mut self, f: F, assembly: &mut Assembly) -> Result<()>
+    where
+        F: FnOnce(&mut Assembly, &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()>,
+    {
+        let writer = match self.opt_writer.as_mut() {
+            Some(writer) => writer,
+            None => return Err(Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        };
+        let (opt_hash, received_size) = (&mut self.opt_hash, &mut self.received_size);
+        f(assembly, &mut |data: &[u8]| {
+            writer.write_all(data)?;
+            if let Some(hash) = opt_hash.as_mut() {
+                hash.update(data);
+            }
+            *received_size += data.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn after_write(&mut self, res: Result<()>) {
+        match res {
+            Ok(_) => {
+                if let Some((index, _)) = self.opt_current {
+                    self.notify(Event::Progress {
+                        index,
+                        bytes: self.received_size,
+                    });
+                }
+                self.state = ServerState::ReceiveFileData
+            }
+            Err(e) => self.error(e),
         }
     }
 
     fn process_end_file(&mut self) {
-        if let Some(writer) = self.opt_writer.as_mut() {
-            writer.flush().unwrap();
-            self.state = ServerState::EndReceivingFile
+        self.opt_basis = None;
+        self.opt_part = None;
+        let opt_chunks = self.opt_assembly.take().map(Assembly::into_chunks);
+        let opt_hash = self.opt_hash.take().map(ContentHash::finish);
+        if let Some((_, file)) = self.opt_current.as_ref() {
+            let res = check_size(file, self.received_size)
+                .and_then(|_| opt_hash.as_ref().map_or(Ok(()), |h| check_hash(file, h)));
+            if let Err(e) = res {
+                self.discard_current();
+                self.error(e);
+                return;
+            }
+        }
+        match self.opt_writer.take() {
+            Some(mut writer) => {
+                let opt_path = self.opt_path.take();
+                let res = writer.flush().and_then(|_| {
+                    drop(writer);
+                    match opt_path.as_ref() {
+                        Some(path) => commit_part(path, self.opt_mtime.take()),
+                        None => Ok(()),
+                    }
+                });
+                match res {
+                    Ok(_) => {
+                        if let Some(path) = opt_path {
+                            self.index_file(&path, opt_chunks);
+                        }
+                        if let Some((index, mut file)) = self.opt_current.take() {
+                            file.hash = opt_hash;
+                            self.received.push(file.clone());
+                            self.notify(Event::FileCompleted { index, file });
+                        }
+                        self.state = ServerState::EndReceivingFile
+                    }
+                    Err(e) => self.error(e),
+                }
+            }
+            None => self.unexpected(Ok(Packet::EndFile)),
+        }
+    }
+
+    /// from version 3 Finish is answered, the sender reads the Cancel it may not have read
+    /// before it
+    fn process_finish(&mut self) {
+        if self.str.encoding().version() >= 3 {
+            if let Err(e) = self.str.write_packet(Packet::Finish) {
+                return self.error(e);
+            }
+        }
+        self.notify(Event::Finished);
+        self.state = ServerState::Finish
+    }
+
+    /// tell the sender to stop the session, it hangs up without reading anything else
+    fn process_cancel(&mut self) {
+        let reason = self.cancel.session_reason().unwrap_or_default();
+        self.discard_current();
+        self.notify(Event::Cancelled {
+            index: None,
+            reason: reason.clone(),
+            by_peer: false,
+        });
+        let data = CancelData::new(CancelScope::Session, &reason);
+        match self.str.write_packet(Packet::Cancel(data)) {
+            Ok(_) => self.error(Cancelled::error(&reason, false)),
+            Err(e) => self.error(e),
+        }
+    }
+
+    /// drop the current file here, its data is ignored until EndFile; from version 3 the sender
+    /// is told and skips the rest of it
+    fn process_cancel_file(&mut self, reason: &str) {
+        let opt_index = self.discard_current();
+        self.notify(Event::Cancelled {
+            index: opt_index,
+            reason: reason.to_string(),
+            by_peer: false,
+        });
+        if let Some(index) = opt_index.filter(|_| self.str.encoding().version() >= 3) {
+            let data = CancelData::file(index, reason);
+            if let Err(e) = self.str.write_packet(Packet::Cancel(data)) {
+                warn!("cannot tell the sender about the cancelled file: {}", e);
+            }
+        }
+        self.state = ServerState::DiscardFileData
+    }
+
+    /// the sender stopped the current file or the whole session
+    fn process_cancelled(&mut self, data: CancelData) {
+        let opt_index = self.discard_current();
+        let scope = data.scope;
+        self.notify(Event::Cancelled {
+            index: if scope == CancelScope::File {
+                opt_index
+            } else {
+                None
+            },
+            reason: data.reason.clone(),
+            by_peer: true,
+        });
+        match scope {
+            CancelScope::File => self.state = ServerState::EndReceivingFile,
+            _ => self.error(Cancelled::error(&data.reason, true)),
+        }
+    }
+
+    /// remove the partial output of the file being received, returns its index
+    fn discard_current(&mut self) -> Option<usize> {
+        self.opt_writer = None;
+        self.opt_part = None;
+        self.opt_basis = None;
+        self.opt_assembly = None;
+        self.opt_mtime = None;
+        self.opt_hash = None;
+        if let Some(path) = self.opt_path.take() {
+            remove_part(&path);
+        }
+        self.opt_current.take().map(|(index, _)| index)
+    }
+
+    fn notify(&self, event: Event) {
+        if let Some(observer) = self.opt_observer.as_ref() {
+            observer.on_event(&event)
         }
     }
 
-    fn error(&mut self) {
+    /// offer a delta of the files the receiver has another copy of, their signatures go before
+    /// the answer
+    fn send_signatures(&mut self, have: &[bool]) -> Result<()> {
+        self.signatures.clear();
+        if !self.delta {
+            return Ok(());
+        }
+        for (index, file) in self.files.iter().enumerate() {
+            if have[index] || file.streamed || file.size < delta::MIN_DELTA_LEN {
+                continue;
+            }
+            let path = match source::safe_join(&self.out_dir, &file.name) {
+                Ok(path) if path.is_file() => path,
+                _ => continue,
+            };
+            match delta::signature(&path, index) {
+                Ok(Some(signature)) => {
+                    self.str
+                        .write_packet(Packet::Signature(signature.clone()))?;
+                    self.signatures.insert(index, signature);
+                }
+                Ok(None) => {}
+                Err(e) => info!("cannot read {:?} for a delta: {}", path, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// add the chunks of a received file to the index, cutting the file when they were not
+    /// announced
+    fn index_file(&self, path: &Path, opt_chunks: Option<Vec<ChunkInfo>>) {
+        let index = match self.opt_index.as_ref() {
+            Some(index) => index,
+            None => return,
+        };
+        let res = match opt_chunks {
+            Some(chunks) => Ok(chunks),
+            None => File::open(path).and_then(|f| chunks::split(f, &mut ContentHash::new())),
+        };
+        if let Err(e) = res.and_then(|chunks| index.add(path, &chunks)) {
+            info!("cannot index the chunks of {:?}: {}", path, e);
+        }
+    }
+
+    fn notify_skipped(&self, have: &[bool]) {
+        for (index, file) in self.files.iter().enumerate().filter(|(i, _)| have[*i]) {
+            self.notify(Event::Skipped {
+                index,
+                file: file.clone(),
+            });
+        }
+    }
+
+    fn unexpected(&mut self, res: Result<Packet>) {
+        match res {
+            Ok(_) => self.error(Error::new(ErrorKind::InvalidData, "unexpected packet")),
+            Err(e) => self.error(e),
+        }
+    }
+
+    fn error(&mut self, err: Error) {
+        // rather than only hanging up, so that the sender tells it from a network failure
+        if Integrity::is(&err) {
+            let data = CancelData::new(integrity_scope(self.str.encoding()), &err.to_string());
+            if let Err(e) = self.str.write_packet(Packet::Cancel(data)) {
+                warn!("cannot tell the sender about the integrity failure: {}", e);
+            }
+        }
+        self.opt_error = Some(err);
         self.state = ServerState::Error
     }
 
@@ -150,3 +909,256 @@ where
         //TODO shutdown
     }
 }
+
+/// the scope of the Cancel telling the sender about an integrity failure, senders before
+/// version 3 only know it as the end of the session
+pub(crate) fn integrity_scope(encoding: Encoding) -> CancelScope {
+    match encoding.version() >= 3 {
+        true => CancelScope::Integrity,
+        false => CancelScope::Session,
+    }
+}
+
+/// a transfer request is accepted when the policy agrees, a single file can go to stdout
+pub(crate) fn is_accepted(
+    peer: &PeerInfo,
+    files: &[FileInfo],
+    to_stdout: bool,
+    opt_policy: Option<&AcceptPolicy>,
+) -> bool {
+    (!to_stdout || files.len() == 1) && opt_policy.is_none_or(|p| p(peer, files))
+}
+
+/// the version of a file a `.part` file holds the start of, kept next to it so that only a
+/// transfer of the same version resumes into it
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct PartInfo {
+    size: u64,
+    mtime: u64,
+}
+
+impl PartInfo {
+    fn of(file: &FileInfo) -> Option<Self> {
+        match (file.streamed, file.mtime) {
+            (false, Some(mtime)) => Some(PartInfo {
+                size: file.size,
+                mtime,
+            }),
+            _ => None,
+        }
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        let text = fs::read(part_info_path(path)).ok()?;
+        serde_json::from_slice(&text).ok()
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        fs::write(part_info_path(path), serde_json::to_vec(self)?)
+    }
+}
+
+/// open the `.part` file a transfer of `file` is written to until EndFile, keeping `offset`
+/// bytes of it
+pub(crate) fn open_part(path: &Path, file: &FileInfo, offset: u64) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if offset == 0 {
+        match PartInfo::of(file) {
+            Some(info) => info.write(path)?,
+            None => remove_part_info(path),
+        }
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(offset == 0)
+        .open(part_path(path))?;
+    if offset > 0 {
+        if file.metadata()?.len() < offset {
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot resume file"));
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+    }
+    Ok(file)
+}
+
+/// SHA-256 of the `offset` bytes of the `.part` file kept from an earlier session
+pub(crate) fn part_hash(path: &Path, offset: u64) -> Result<ContentHash> {
+    ContentHash::resume(&part_path(path), offset)
+}
+
+/// how much of each requested file is already here: the length of a `.part` file interrupted
+/// while receiving the same version of the file, or the full size when the complete file
+/// exists
+pub(crate) fn kept_sizes(out_dir: &Path, files: &[FileInfo], to_stdout: bool) -> Vec<Option<u64>> {
+    files
+        .iter()
+        .map(|f| {
+            if f.streamed || to_stdout {
+                return None;
+            }
+            let path = match source::safe_join(out_dir, &f.name) {
+                Ok(path) => path,
+                Err(_) => return None,
+            };
+            let same_version = PartInfo::of(f).is_some_and(|v| PartInfo::read(&path) == Some(v));
+            match (fs::metadata(part_path(&path)), fs::metadata(&path)) {
+                (Ok(meta), _) if meta.len() <= f.size && same_version => Some(meta.len()),
+                (_, Ok(meta)) if meta.len() == f.size => Some(meta.len()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// the files of a request the receiver already has: same size and modification time, and
+/// same content when the request carries its hash
+pub(crate) fn identical_files(out_dir: &Path, files: &[FileInfo]) -> Vec<bool> {
+    files.iter().map(|f| is_identical(out_dir, f)).collect()
+}
+
+fn is_identical(out_dir: &Path, file: &FileInfo) -> bool {
+    let path = match source::safe_join(out_dir, &file.name) {
+        Ok(path) if !file.streamed => path,
+        _ => return false,
+    };
+    let meta = match fs::metadata(&path) {
+        Ok(meta) if meta.is_file() && meta.len() == file.size => meta,
+        _ => return false,
+    };
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    if file.mtime.is_none() || mtime != file.mtime {
+        return false;
+    }
+    match file.hash.as_deref() {
+        Some(hash) => source::hash_file(&path).is_ok_and(|h| h == hash),
+        None => true,
+    }
+}
+
+/// `Have` when the receiver already has some of the files, `Accept` otherwise
+pub(crate) fn have_answer(have: Vec<bool>) -> Packet {
+    match have.contains(&true) {
+        true => Packet::Have(have),
+        false => Packet::Accept,
+    }
+}
+
+/// move the complete `.part` file to its final name
+pub(crate) fn commit_part(path: &Path, mtime: Option<u64>) -> Result<()> {
+    let part = part_path(path);
+    if let Some(secs) = mtime {
+        // keep the time of the sender, so synchronized copies compare equal
+        OpenOptions::new()
+            .write(true)
+            .open(&part)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(secs))?;
+    }
+    fs::rename(part, path)?;
+    remove_part_info(path);
+    Ok(())
+}
+
+/// a started file is the one of the accepted request at its index, so that the accept policy
+/// saw its name and size
+pub(crate) fn check_start_file(
+    files: &[FileInfo],
+    offers: &[Option<u64>],
+    data: &StartFileData,
+) -> Result<()> {
+    let requested = files.get(data.index).filter(|f| {
+        let started = &data.file_info;
+        files.len() == data.total
+            && f.name == started.name
+            && f.size == started.size
+            && f.streamed == started.streamed
+    });
+    if requested.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: not the requested file", data.file_info.name),
+        ));
+    }
+    // only the part offered in ResumeAt for the file is kept
+    let offered = offers.get(data.index).copied().flatten();
+    if data.offset > 0 && offered != Some(data.offset) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{}: resumed at {}, which was not offered",
+                data.file_info.name, data.offset
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// the sender sent as many bytes as it announced, the size of streamed files is not known
+pub(crate) fn check_size(file: &FileInfo, received: u64) -> Result<()> {
+    if file.streamed || received == file.size {
+        return Ok(());
+    }
+    Err(Integrity::error(format!(
+        "{}: received {} bytes, {} announced",
+        file.name, received, file.size
+    )))
+}
+
+/// the content received has the hash the sender announced, if it announced one
+pub(crate) fn check_hash(file: &FileInfo, hash: &str) -> Result<()> {
+    match file.hash.as_deref() {
+        Some(announced) if announced != hash => Err(Integrity::error(format!(
+            "{}: received content of SHA-256 {}, {} announced",
+            file.name, hash, announced
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// the sender sent no more bytes than it announced so far, which the accept policy saw; the
+/// size of streamed files is not known
+pub(crate) fn check_received(file: &FileInfo, received: u64) -> Result<()> {
+    if file.streamed || received <= file.size {
+        return Ok(());
+    }
+    Err(Integrity::error(format!(
+        "{}: more than the {} bytes announced",
+        file.name, file.size
+    )))
+}
+
+/// drop the partial output of a cancelled file
+pub(crate) fn remove_part(path: &Path) {
+    if let Err(e) = fs::remove_file(part_path(path)) {
+        info!("cannot remove partial file of {:?}: {}", path, e);
+    }
+    remove_part_info(path);
+}
+
+fn remove_part_info(path: &Path) {
+    match fs::remove_file(part_info_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            info!("cannot remove {:?}: {}", part_info_path(path), e)
+        }
+        _ => {}
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+fn part_info_path(path: &Path) -> PathBuf {
+    let mut name = part_path(path).into_os_string();
+    name.push(".info");
+    PathBuf::from(name)
+}
@@ -0,0 +1,239 @@
+use crate::packet::file_info::FileInfo;
+use crate::select::{Selection, Walker};
+use crate::sparse;
+use log::debug;
+use ring::digest;
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// path standing for the standard input
+pub const STDIN: &str = "-";
+
+/// a local file to send and the name it gets on the receiver
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+impl SourceFile {
+    pub fn stdin(name: &str) -> Self {
+        SourceFile {
+            path: PathBuf::from(STDIN),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is_stdin(&self) -> bool {
+        self.path == Path::new(STDIN)
+    }
+
+    pub fn info(&self) -> Result<FileInfo> {
+        if self.is_stdin() {
+            return Ok(FileInfo::streamed(self.name.clone()));
+        }
+        let meta = fs::metadata(&self.path)?;
+        Ok(FileInfo::from_metadata(self.name.clone(), &meta))
+    }
+
+    /// content of the file from `offset`, or of the standard input
+    pub fn open_at(&self, offset: u64) -> Result<Box<dyn Read>> {
+        match (self.is_stdin(), offset) {
+            (true, 0) => Ok(Box::new(io::stdin())),
+            (true, _) => Err(Error::new(ErrorKind::InvalidInput, "cannot seek stdin")),
+            (false, _) => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    /// file info to request the transfer with, including the SHA-256 of the content when
+    /// `checksum` is set and the content can be read twice
+    pub fn request_info(&self, checksum: bool) -> Result<FileInfo> {
+        match checksum && !self.is_stdin() {
+            true => self.info_with_hash(),
+            false => self.info(),
+        }
+    }
+
+    /// file info including the SHA-256 of the content
+    pub fn info_with_hash(&self) -> Result<FileInfo> {
+        if self.is_stdin() {
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot hash stdin"));
+        }
+        let mut info = self.info()?;
+        info.hash = Some(hash_file(&self.path)?);
+        Ok(info)
+    }
+}
+
+/// same as `select` for paths given on the command line, where `-` is the standard input
+/// sent as `stdin_name`
+pub fn collect_args(
+    paths: &[PathBuf],
+    stdin_name: &str,
+    selection: &Selection,
+) -> Result<Vec<SourceFile>> {
+    let mut files = Vec::new();
+    for p in paths {
+        if p == Path::new(STDIN) {
+            files.push(SourceFile::stdin(stdin_name));
+        } else {
+            files.extend(select(std::slice::from_ref(p), selection)?);
+        }
+    }
+    Ok(files)
+}
+
+/// expand the given files and directories into the files to send,
+/// directory contents are named relative to the directory's parent (`dir/sub/file`)
+pub fn collect(paths: &[PathBuf]) -> Result<Vec<SourceFile>> {
+    select(paths, &Selection::default())
+}
+
+/// same as `collect`, only taking the files of the directories that `selection` takes
+pub fn select(paths: &[PathBuf], selection: &Selection) -> Result<Vec<SourceFile>> {
+    let mut files = Vec::new();
+    for p in paths {
+        let name = match p.file_name() {
+            Some(n) => n.to_os_string(),
+            None => fs::canonicalize(p)?
+                .file_name()
+                .map(|n| n.to_os_string())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot send a root"))?,
+        };
+        walk(p, to_name(&name)?, &mut Walker::new(selection), &mut files)?;
+    }
+    Ok(files)
+}
+
+/// every file below `dir`, named relative to it
+pub fn collect_dir(dir: &Path) -> Result<Vec<SourceFile>> {
+    let mut files = Vec::new();
+    let all = Selection::default();
+    walk(dir, String::new(), &mut Walker::new(&all), &mut files)?;
+    Ok(files)
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut hash = ContentHash::new();
+    hash.update_from(File::open(path)?)?;
+    Ok(hash.finish())
+}
+
+/// SHA-256 of a file computed while it is transferred
+pub struct ContentHash {
+    ctx: digest::Context,
+}
+
+impl ContentHash {
+    pub fn new() -> Self {
+        ContentHash {
+            ctx: digest::Context::new(&digest::SHA256),
+        }
+    }
+
+    /// start with the first `len` bytes of `path`, transferred by an earlier session
+    pub fn resume(path: &Path, len: u64) -> Result<Self> {
+        let mut hash = Self::new();
+        if len > 0 {
+            hash.update_from(File::open(path)?.take(len))?;
+        }
+        Ok(hash)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.ctx.update(data)
+    }
+
+    /// continue with `len` zero bytes, the content of a hole
+    pub fn update_zeros(&mut self, len: u64) {
+        sparse::zeros(len).for_each(|zeros| self.ctx.update(zeros))
+    }
+
+    fn update_from<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        let mut buf = vec![0_u8; 64 * 1024];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            self.ctx.update(&buf[..len]);
+        }
+    }
+
+    /// hex digest
+    pub fn finish(self) -> String {
+        self.ctx
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// join a name received from the peer to `dir`, refusing names that would escape it
+pub fn safe_join(dir: &Path, name: &str) -> Result<PathBuf> {
+    let rel = Path::new(name);
+    let is_safe =
+        rel.components().count() > 0 && rel.components().all(|c| matches!(c, Component::Normal(_)));
+    if is_safe {
+        Ok(dir.join(rel))
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid file name: {:?}", name),
+        ))
+    }
+}
+
+fn walk(path: &Path, name: String, walker: &mut Walker, files: &mut Vec<SourceFile>) -> Result<()> {
+    let meta = fs::metadata(path)?;
+    if meta.is_file() {
+        if walker.takes_file(&meta) {
+            files.push(SourceFile {
+                path: path.to_path_buf(),
+                name,
+            });
+        }
+    } else if meta.is_dir() {
+        if !walker.enter(path)? {
+            return Ok(());
+        }
+        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            // a link to a directory may lead back to one of its parents
+            if entry.file_type()?.is_symlink() && entry.path().is_dir() {
+                debug!("not following the link to a directory {:?}", entry.path());
+                continue;
+            }
+            let child = to_name(&entry.file_name())?;
+            walker.push(&child);
+            let child_name = if name.is_empty() {
+                child
+            } else {
+                format!("{}/{}", name, child)
+            };
+            walk(&entry.path(), child_name, walker, files)?;
+            walker.pop();
+        }
+        walker.leave();
+    } else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid file: {:?}", path),
+        ));
+    }
+    Ok(())
+}
+
+fn to_name(name: &std::ffi::OsStr) -> Result<String> {
+    name.to_str()
+        .map(String::from)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "file name is not valid UTF-8"))
+}
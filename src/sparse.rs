@@ -0,0 +1,137 @@
+//! holes of sparse files: ranges the file system does not store and reads as zeros, sent as Hole
+//! instead of FileData and left as holes by the receiver
+
+use crate::packet::hole::HoleData;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// a piece of a sparse file
+pub(crate) enum Piece {
+    Data(Vec<u8>),
+    Hole(HoleData),
+}
+
+/// reads a sparse file as its data and its holes
+pub(crate) struct SparseReader {
+    file: File,
+    pos: u64,
+    size: u64,
+    /// end of the data at `pos`
+    data_end: u64,
+}
+
+impl SparseReader {
+    /// read the file at `path` from `offset`, None when it has no hole or the system cannot
+    /// find them
+    pub(crate) fn open(path: &Path, offset: u64) -> Result<Option<Self>> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        if !meta.is_file() || !has_holes(&meta) {
+            return Ok(None);
+        }
+        Ok(Some(SparseReader {
+            file,
+            pos: offset,
+            size: meta.len(),
+            data_end: offset,
+        }))
+    }
+
+    /// the next data or hole, None at the end of the file
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn next_piece(&mut self) -> Result<Option<Piece>> {
+        use crate::packet::FILE_DATA_LEN;
+        use std::os::unix::fs::FileExt;
+
+        if self.pos >= self.size {
+            return Ok(None);
+        }
+        if self.pos >= self.data_end {
+            let data = seek(&self.file, self.pos, libc::SEEK_DATA)?
+                .map_or(self.size, |d| d.min(self.size));
+            if data > self.pos {
+                let hole = HoleData {
+                    offset: self.pos,
+                    len: data - self.pos,
+                };
+                self.pos = data;
+                return Ok(Some(Piece::Hole(hole)));
+            }
+            // the file may change meanwhile, a byte of data at least
+            self.data_end = seek(&self.file, self.pos, libc::SEEK_HOLE)?
+                .map_or(self.size, |h| h.min(self.size))
+                .max(self.pos + 1);
+        }
+        let len = (self.data_end - self.pos).min(FILE_DATA_LEN as u64);
+        let mut data = vec![0_u8; len as usize];
+        self.file.read_exact_at(&mut data, self.pos)?;
+        self.pos += len;
+        Ok(Some(Piece::Data(data)))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn next_piece(&mut self) -> Result<Option<Piece>> {
+        Ok(None)
+    }
+
+    /// how far in the file the pieces so far go
+    pub(crate) fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+/// fewer blocks allocated than the size takes
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn has_holes(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks().saturating_mul(512) < meta.len()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn has_holes(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// the start of the next data or hole from `offset`, None when there is no data after it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+
+    let offset = i64::try_from(offset).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+    let ret = unsafe { libc::lseek64(file.as_raw_fd(), offset, whence) };
+    if ret >= 0 {
+        return Ok(Some(ret as u64));
+    }
+    let err = Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+/// leave a hole of `len` bytes at the position of `file`, the data written before it flushed
+pub(crate) fn skip(mut file: &File, len: u64) -> Result<()> {
+    let len =
+        i64::try_from(len).map_err(|_| Error::new(ErrorKind::InvalidData, "hole too long"))?;
+    let pos = file.seek(SeekFrom::Current(len))?;
+    // a hole at the end of the file is only there once the file is that long
+    if file.metadata()?.len() < pos {
+        file.set_len(pos)?;
+    }
+    Ok(())
+}
+
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// the `len` zero bytes of a hole as slices, for the writers and hashes that cannot skip it
+pub(crate) fn zeros(len: u64) -> impl Iterator<Item = &'static [u8]> {
+    let step = ZEROS.len() as u64;
+    (0..len.div_ceil(step)).map(move |i| &ZEROS[..(len - i * step).min(step) as usize])
+}
+
+/// write the `len` zero bytes of a hole where no hole can be left
+pub(crate) fn write_zeros<W: Write + ?Sized>(writer: &mut W, len: u64) -> Result<()> {
+    zeros(len).try_for_each(|zeros| writer.write_all(zeros))
+}
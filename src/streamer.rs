@@ -1,78 +1,135 @@
-use crate::packet::Packet;
-pub use std::io::{BufReader, Read, Result, Write};
+use crate::error::Unversioned;
+use crate::packet::{Encoding, Packet, HEADER_LEN, MAX_MESSAGE_LEN, VERSIONS};
+use std::io::{Error, ErrorKind};
+pub use std::io::{Read, Result, Write};
+
+/// any stream, to nest state machines without nesting their stream types
+pub trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
 
 pub struct Streamer<S: Read + Write> {
     str: S,
+    /// unknown until the first packet of the session
+    opt_encoding: Option<Encoding>,
 }
 
 impl<S: Read + Write> Streamer<S> {
+    /// the encoding is negotiated with the first packet: the side writing first offers its
+    /// versions with Hello and the other side answers it, a peer starting without Hello
+    /// speaks JSON
     pub fn new(str: S) -> Self {
-        Streamer { str }
+        Streamer {
+            str,
+            opt_encoding: None,
+        }
+    }
+
+    /// the underlying stream, to hand the session over to another state machine
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.str
+    }
+
+    /// the encoding of a session handed over by another state machine
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.opt_encoding = Some(encoding);
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.opt_encoding.unwrap_or(Encoding::Json)
     }
 
     /// convert to bytes array and write to socket
     /// [1 byte for action] + [2 bytes for len] + [additional data]
     pub fn write_packet(&mut self, packet: Packet) -> Result<usize> {
-        let vec = Self::packet_to_bytes(packet);
-        self.str.write(&vec).map(|l| {
-            self.str.flush().unwrap();
-            l
-        })
+        let encoding = match self.opt_encoding {
+            Some(encoding) => encoding,
+            None => self.hello()?,
+        };
+        self.write_bytes(packet.into_bytes(encoding)?)
     }
 
     /// read packet from socket
     /// [1 byte for action] + [2 bytes for len] + [additional data]
+    /// the length on 2 bytes bounds what a peer can make us allocate to `MAX_BODY_LEN`, or to
+    /// `MAX_MESSAGE_LEN` for a packet continued over several frames
     pub fn read_packet(&mut self) -> Result<Packet> {
-        // read action (1 byte)
-        let action = match self.read_action() {
-            Ok(act) => act,
-            Err(e) => return Err(e),
-        };
-
-        // read len (2 bytes)
-        let len = match self.read_len() {
-            Ok(v) => v,
-            Err(e) => return Err(e),
-        };
+        let encoding = self.encoding();
+        let (action, data) = self.read_message(encoding)?;
+        let packet = Packet::from_data(action, &data, encoding)?;
+        match (self.opt_encoding, packet) {
+            (None, Packet::Hello(offered)) => {
+                let encoding = Encoding::choose(&offered)?;
+                let answer = Packet::Hello(vec![encoding.version()]);
+                self.write_bytes(answer.into_bytes(Encoding::Json)?)?;
+                self.opt_encoding = Some(encoding);
+                self.read_packet()
+            }
+            (None, packet) => {
+                self.opt_encoding = Some(Encoding::Json);
+                Ok(packet)
+            }
+            (Some(_), packet) => Ok(packet),
+        }
+    }
 
-        let data_buf = if len > 0 {
-            let mut buf = vec![0_u8; len as usize];
-            match self.str.read_exact(&mut buf) {
-                Ok(_) => buf,
-                Err(e) => return Err(e),
+    /// offer the versions of this side before the first packet, the peer answers with the one
+    /// of the session; a peer of version 1 hangs up instead
+    fn hello(&mut self) -> Result<Encoding> {
+        self.write_bytes(Packet::Hello(VERSIONS.to_vec()).into_bytes(Encoding::Json)?)?;
+        let (action, data) = self.read_message(Encoding::Json).map_err(unversioned)?;
+        let encoding = match Packet::from_data(action, &data, Encoding::Json)? {
+            Packet::Hello(answer) => Encoding::from_answer(&answer)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected answer to Hello",
+                ))
             }
-        } else {
-            vec![]
         };
-        Packet::from_data(action, &data_buf)
+        self.opt_encoding = Some(encoding);
+        Ok(encoding)
     }
 
-    /// convert packet to bytes
-    fn packet_to_bytes(packet: Packet) -> Vec<u8> {
-        let action = packet.get_action();
-        let data = packet.get_data();
-        let len = data.len() as u16;
-
-        let cap = 1 + 2 + len;
-        let mut vec: Vec<u8> = Vec::with_capacity(cap as usize);
-        vec.push(action);
-        len.to_le_bytes().iter().for_each(|b| vec.push(*b));
-        data.iter().for_each(|b| vec.push(*b));
-        vec
+    fn write_bytes(&mut self, vec: Vec<u8>) -> Result<usize> {
+        self.str.write_all(&vec)?;
+        self.str.flush()?;
+        Ok(vec.len())
     }
 
-    fn read_action(&mut self) -> Result<u8> {
-        let mut buf = [0; 1];
-        match self.str.read_exact(&mut buf) {
-            Ok(_) => Ok(*buf.first().unwrap()),
-            Err(err) => Err(err),
+    /// action and data of a packet, made of the frames continuing the first one
+    fn read_message(&mut self, encoding: Encoding) -> Result<(u8, Vec<u8>)> {
+        let (action, mut data) = self.read_frame()?;
+        let mut len = data.len();
+        while encoding.is_continued(len) {
+            let (next, part) = self.read_frame()?;
+            if next != action {
+                return Err(Error::new(ErrorKind::InvalidData, "unexpected frame"));
+            }
+            if data.len() + part.len() > MAX_MESSAGE_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "packet too large"));
+            }
+            len = part.len();
+            data.extend_from_slice(&part);
         }
+        Ok((action, data))
+    }
+
+    fn read_frame(&mut self) -> Result<(u8, Vec<u8>)> {
+        let mut header = [0_u8; HEADER_LEN];
+        self.str.read_exact(&mut header)?;
+        let (action, len) = Packet::parse_header(header);
+
+        let mut data_buf = vec![0_u8; len as usize];
+        self.str.read_exact(&mut data_buf)?;
+        Ok((action, data_buf))
     }
+}
 
-    fn read_len(&mut self) -> Result<u16> {
-        let mut buf = [0; 2];
-        self.str
-            .read_exact(&mut buf)
-            .map(|_| u16::from_ne_bytes(buf))
+/// the peer hung up on Hello rather than answering it
+pub(crate) fn unversioned(err: Error) -> Error {
+    match err.kind() {
+        ErrorKind::UnexpectedEof => Unversioned::error(),
+        _ => err,
     }
 }
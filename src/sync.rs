@@ -0,0 +1,245 @@
+use crate::client::{ClientOutcome, ClientStateMachine};
+use crate::observer::Observer;
+use crate::packet::file_info::FileInfo;
+use crate::packet::sync_request::{ConflictPolicy, SyncRequest};
+use crate::packet::{Encoding, Packet};
+use crate::server::{AcceptPolicy, ServerStateMachine};
+use crate::source::{self, SourceFile};
+use crate::streamer::{ReadWrite, Streamer};
+use crate::transport::PeerInfo;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// names to transfer in each direction, seen from the local peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub send: Vec<String>,
+    pub receive: Vec<String>,
+}
+
+/// what was transferred by a sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    pub sent: usize,
+    pub received: usize,
+}
+
+/// files of `dir` with their size, modification time and hash
+pub fn manifest(dir: &Path) -> Result<(Vec<SourceFile>, Vec<FileInfo>)> {
+    // interrupted transfers are not part of the directory yet
+    let files: Vec<SourceFile> = source::collect_dir(dir)?
+        .into_iter()
+        .filter(|f| !f.name.ends_with(".part"))
+        .collect();
+    let infos = files
+        .iter()
+        .map(SourceFile::info_with_hash)
+        .collect::<Result<Vec<FileInfo>>>()?;
+    Ok((files, infos))
+}
+
+/// compare both manifests, both peers compute the same plan with mirrored policies
+pub fn plan(local: &[FileInfo], remote: &[FileInfo], policy: ConflictPolicy) -> SyncPlan {
+    let mut names: BTreeMap<&str, (Option<&FileInfo>, Option<&FileInfo>)> = BTreeMap::new();
+    local
+        .iter()
+        .for_each(|f| names.entry(&f.name).or_default().0 = Some(f));
+    remote
+        .iter()
+        .for_each(|f| names.entry(&f.name).or_default().1 = Some(f));
+
+    let mut plan = SyncPlan::default();
+    for (name, pair) in names {
+        match pair {
+            (Some(_), None) => plan.send.push(name.to_string()),
+            (None, Some(_)) => plan.receive.push(name.to_string()),
+            (Some(l), Some(r)) if l.size == r.size && l.hash == r.hash => {}
+            (Some(l), Some(r)) => {
+                debug!("conflict on {}: {:?} / {:?}", name, l, r);
+                let send = match policy {
+                    ConflictPolicy::Newer if l.mtime > r.mtime => Some(true),
+                    ConflictPolicy::Newer if l.mtime < r.mtime => Some(false),
+                    ConflictPolicy::Local => Some(true),
+                    ConflictPolicy::Remote => Some(false),
+                    _ => None,
+                };
+                match send {
+                    Some(true) => plan.send.push(name.to_string()),
+                    Some(false) => plan.receive.push(name.to_string()),
+                    None => info!("conflict on {} is left untouched", name),
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    plan
+}
+
+/// local files to send for the plan
+pub fn files_to_send(files: &[SourceFile], plan: &SyncPlan) -> Vec<SourceFile> {
+    files
+        .iter()
+        .filter(|f| plan.send.contains(&f.name))
+        .cloned()
+        .collect()
+}
+
+/// the files a sync session receives: the ones of the plan, as the manifest of the peer
+/// describes them
+pub fn files_to_receive(remote: &[FileInfo], plan: &SyncPlan) -> Vec<FileInfo> {
+    remote
+        .iter()
+        .filter(|f| plan.receive.contains(&f.name))
+        .cloned()
+        .collect()
+}
+
+/// every file of the request is one of `expected`, with the same size and hash
+fn is_planned(files: &[FileInfo], expected: &[FileInfo]) -> bool {
+    let mut names = BTreeMap::new();
+    files.iter().all(|f| {
+        let planned = expected
+            .iter()
+            .any(|e| e.name == f.name && e.size == f.size && e.hash.is_some() && e.hash == f.hash);
+        if !planned {
+            info!("refusing {}, it is not planned as {:?}", f.name, f);
+        }
+        planned && !f.streamed && names.insert(&f.name, ()).is_none()
+    })
+}
+
+/// what a sync session takes from the peer
+pub struct Incoming {
+    /// the files of the plan, as the manifest of the peer describes them
+    pub expected: Vec<FileInfo>,
+    pub peer: PeerInfo,
+    /// the policy of the receiver, asked once the files are checked against the plan
+    pub opt_policy: Option<AcceptPolicy>,
+}
+
+/// exchange both directions over an established session: first the local peer pushes its
+/// files while the other receives, then the roles are swapped
+pub fn transfer(
+    s: &mut dyn ReadWrite,
+    encoding: Encoding,
+    dir: &Path,
+    files: &[SourceFile],
+    incoming: Incoming,
+    send_first: bool,
+    opt_observer: Option<Arc<dyn Observer>>,
+) -> Result<()> {
+    let o = opt_observer;
+    let Incoming {
+        expected,
+        peer,
+        opt_policy,
+    } = incoming;
+    let policy: AcceptPolicy = Arc::new(move |peer: &PeerInfo, files: &[FileInfo]| {
+        is_planned(files, &expected) && opt_policy.as_ref().is_none_or(|p| p(peer, files))
+    });
+    let send = |s: &mut dyn ReadWrite, o| {
+        let outcome = ClientStateMachine::new(s, files)
+            .with_encoding(encoding)
+            .with_checksum(true)
+            .with_observer(o)
+            .start()?;
+        match outcome {
+            ClientOutcome::Finished => Ok(()),
+            ClientOutcome::Rejected => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "files rejected by the peer",
+            )),
+        }
+    };
+    let receive = |s: &mut dyn ReadWrite, o| {
+        let mut server = ServerStateMachine::new(s, dir, None)
+            .with_encoding(encoding)
+            .with_accept_policy(Some(policy.clone()))
+            .with_peer(peer.clone())
+            .with_observer(o);
+        server.start()?;
+        match server.accepted() {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "files of the peer rejected",
+            )),
+        }
+    };
+    if send_first {
+        send(&mut *s, o.clone())?;
+        receive(&mut *s, o)
+    } else {
+        receive(&mut *s, o.clone())?;
+        send(&mut *s, o)
+    }
+}
+
+/// client side of the sync mode
+pub struct Syncer<S>
+where
+    S: Read + Write,
+{
+    str: Streamer<S>,
+    dir: PathBuf,
+}
+
+impl<S> Syncer<S>
+where
+    S: Read + Write,
+{
+    pub fn new(s: S, dir: &Path) -> Self {
+        Syncer {
+            str: Streamer::new(s),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// mirror the local directory with the one of the peer
+    pub fn sync(&mut self, policy: ConflictPolicy) -> Result<SyncSummary> {
+        let (files, local) = manifest(&self.dir)?;
+        let request = SyncRequest {
+            files: local.clone(),
+            policy,
+        };
+        self.str.write_packet(Packet::Sync(request))?;
+        let remote = match self.str.read_packet()? {
+            Packet::Listing(remote) => remote,
+            Packet::Reject => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "sync rejected by server",
+                ))
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unexpected packet")),
+        };
+
+        let plan = plan(&local, &remote, policy);
+        info!(
+            "sync plan: send {:?}, receive {:?}",
+            plan.send, plan.receive
+        );
+        let to_send = files_to_send(&files, &plan);
+        let encoding = self.str.encoding();
+        transfer(
+            self.str.get_mut(),
+            encoding,
+            &self.dir,
+            &to_send,
+            Incoming {
+                expected: files_to_receive(&remote, &plan),
+                peer: PeerInfo::new(String::new()),
+                opt_policy: None,
+            },
+            true,
+            None,
+        )?;
+        Ok(SyncSummary {
+            sent: plan.send.len(),
+            received: plan.receive.len(),
+        })
+    }
+}
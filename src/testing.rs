@@ -0,0 +1,440 @@
+//! runs the sender and receiver state machines against each other in one process, over an
+//! in-memory stream, optionally breaking the frames one side writes
+//!
+//! ```no_run
+//! # use sendfile_cli::testing::{Fault, Harness};
+//! let harness = Harness::new()?;
+//! let path = harness.add_file("a.txt", b"hello")?;
+//! let transfer = harness.transfer_with(&[path], Some(Fault::DropAt(20)), None);
+//! assert!(transfer.received.is_err());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::chunks::ChunkIndex;
+use crate::client::ClientStateMachine;
+use crate::observer::Observer;
+use crate::packet::file_info::FileInfo;
+use crate::packet::{Packet, HEADER_LEN};
+use crate::server::ServerStateMachine;
+use crate::source;
+use crate::streamer::Streamer;
+use std::env;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+pub use crate::client::ClientOutcome;
+pub use crate::packet::Encoding;
+pub use crate::transport::memory::{duplex, MemoryStream};
+
+/// makes the names of the temporary directories of a process unique
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// a new directory below the system temporary directory, removed with its content on drop
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> io::Result<Self> {
+        let name = format!(
+            "sendfile-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = env::temp_dir().join(name);
+        fs::create_dir_all(&path)?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// what goes wrong with the frames written to a `FaultyStream`, frames are counted from 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// the connection drops once this many bytes went through
+    DropAt(usize),
+    /// only the first half of this frame goes through, then the connection drops
+    TruncateFrame(usize),
+    /// the body of this frame is replaced, with malformed JSON for example
+    ReplaceBody(usize, Vec<u8>),
+}
+
+/// a stream forwarding the frames written to it with a fault, reading is left alone until
+/// the connection drops
+pub struct FaultyStream<S> {
+    opt_inner: Option<S>,
+    opt_fault: Option<Fault>,
+    /// bytes written but not forwarded yet, the start of a frame
+    pending: Vec<u8>,
+    frames: usize,
+    forwarded: usize,
+}
+
+impl<S: Read + Write> FaultyStream<S> {
+    pub fn new(inner: S, fault: Fault) -> Self {
+        Self::with(inner, Some(fault))
+    }
+
+    fn with(inner: S, opt_fault: Option<Fault>) -> Self {
+        FaultyStream {
+            opt_inner: Some(inner),
+            opt_fault,
+            pending: Vec::new(),
+            frames: 0,
+            forwarded: 0,
+        }
+    }
+
+    /// forward every complete frame of `pending`
+    fn forward(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.next_frame() {
+            let index = self.frames;
+            self.frames += 1;
+            match &self.opt_fault {
+                Some(Fault::TruncateFrame(i)) if *i == index => {
+                    self.send(&frame[..frame.len() / 2])?;
+                    self.opt_inner = None;
+                    return Err(dropped());
+                }
+                Some(Fault::ReplaceBody(i, body)) if *i == index => {
+                    let frame = frame_bytes(frame[0], body);
+                    self.send(&frame)?
+                }
+                _ => self.send(&frame)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.pending.len() < HEADER_LEN {
+            return None;
+        }
+        let (_, len) = Packet::parse_header([self.pending[0], self.pending[1], self.pending[2]]);
+        let end = HEADER_LEN + len as usize;
+        if self.pending.len() < end {
+            return None;
+        }
+        Some(self.pending.drain(..end).collect())
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let inner = self.opt_inner.as_mut().ok_or_else(dropped)?;
+        let keep = match self.opt_fault {
+            Some(Fault::DropAt(at)) => at.saturating_sub(self.forwarded).min(bytes.len()),
+            _ => bytes.len(),
+        };
+        inner.write_all(&bytes[..keep])?;
+        self.forwarded += keep;
+        if let Some(Fault::DropAt(at)) = self.opt_fault {
+            if self.forwarded >= at {
+                self.opt_inner = None;
+            }
+        }
+        match keep < bytes.len() {
+            true => Err(dropped()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for FaultyStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.opt_inner.as_mut().ok_or_else(dropped)?.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for FaultyStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.opt_inner.is_none() {
+            return Err(dropped());
+        }
+        self.pending.extend_from_slice(buf);
+        self.forward()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.opt_inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Err(dropped()),
+        }
+    }
+}
+
+/// a frame as written on the wire, to script a peer byte by byte
+pub fn frame_bytes(action: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.push(action);
+    frame.extend_from_slice(&(body.len() as u16).to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// a peer replaying recorded bytes and ignoring the answers, for fuzzing
+pub struct Replay<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Replay { input }
+    }
+}
+
+impl Read for Replay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Replay<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// read the frames of `input` like a peer does, up to its end or to the first invalid frame;
+/// returns how many were decoded
+pub fn read_frames(input: &[u8]) -> io::Result<usize> {
+    let mut str = Streamer::new(Replay::new(input));
+    let mut count = 0;
+    loop {
+        match str.read_packet() {
+            Ok(_) => count += 1,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(count),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// decode the data of one packet
+pub fn decode_packet(action: u8, data: &[u8], encoding: Encoding) -> io::Result<()> {
+    Packet::from_data(action, data, encoding).map(|_| ())
+}
+
+fn dropped() -> Error {
+    Error::new(
+        ErrorKind::BrokenPipe,
+        "connection dropped by fault injection",
+    )
+}
+
+/// a stream whose first bytes were already read, they are read again from `head`
+struct Prefixed<S> {
+    head: io::Cursor<[u8; HEADER_LEN]>,
+    inner: S,
+}
+
+impl<S: Read> Read for Prefixed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.head.read(buf)? {
+            0 => self.inner.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+impl<S: Write> Write for Prefixed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// how both sides of a transfer ended
+#[derive(Debug)]
+pub struct Transfer {
+    /// fails whenever the receiver does, the sender waiting for the answer to its Finish
+    pub sent: io::Result<ClientOutcome>,
+    /// the files completely received
+    pub received: io::Result<Vec<FileInfo>>,
+}
+
+/// a sender reading from `source_dir` and a receiver writing to `output_dir`
+pub struct Harness {
+    root: TempDir,
+    /// the sender announces chunks and the receiver keeps an index of them
+    opt_index: Option<Arc<ChunkIndex>>,
+}
+
+impl Harness {
+    pub fn new() -> io::Result<Self> {
+        let root = TempDir::new()?;
+        fs::create_dir(root.path().join("src"))?;
+        fs::create_dir(root.path().join("out"))?;
+        Ok(Harness {
+            root,
+            opt_index: None,
+        })
+    }
+
+    /// a harness whose sender only sends the chunks missing from the index of the receiver,
+    /// kept across its transfers
+    pub fn with_dedup() -> io::Result<Self> {
+        let mut harness = Self::new()?;
+        let index = ChunkIndex::open(&harness.root.path().join("chunks.jsonl"))?;
+        harness.opt_index = Some(Arc::new(index));
+        Ok(harness)
+    }
+
+    pub fn source_dir(&self) -> PathBuf {
+        self.root.path().join("src")
+    }
+
+    pub fn output_dir(&self) -> PathBuf {
+        self.root.path().join("out")
+    }
+
+    /// write a file below the source directory, `name` may contain directories
+    pub fn add_file(&self, name: &str, content: &[u8]) -> io::Result<PathBuf> {
+        let path = self.source_dir().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    /// send `paths` over a healthy connection
+    pub fn transfer(&self, paths: &[PathBuf]) -> Transfer {
+        self.transfer_with(paths, None, None)
+    }
+
+    /// send `paths`, the frames written by the sender and by the receiver suffer the faults
+    pub fn transfer_with(
+        &self,
+        paths: &[PathBuf],
+        opt_sender_fault: Option<Fault>,
+        opt_receiver_fault: Option<Fault>,
+    ) -> Transfer {
+        self.exchange(opt_receiver_fault, |end| {
+            self.send(FaultyStream::with(end, opt_sender_fault), paths)
+        })
+    }
+
+    /// send `paths` again with Resume, like a sender reconnecting after a broken session in
+    /// which it finished none of them
+    pub fn transfer_resumed(&self, paths: &[PathBuf]) -> Transfer {
+        self.exchange(None, |end| {
+            let files = source::collect(paths)?;
+            let finished = vec![false; files.len()];
+            ClientStateMachine::resume(end, &files, &finished).start()
+        })
+    }
+
+    /// run `send` against the receiver, the frames written by the receiver suffer the fault
+    fn exchange<F>(&self, opt_receiver_fault: Option<Fault>, send: F) -> Transfer
+    where
+        F: FnOnce(MemoryStream) -> io::Result<ClientOutcome>,
+    {
+        let (sender_end, receiver_end) = duplex();
+        thread::scope(|scope| {
+            let receiving =
+                scope.spawn(|| self.receive(FaultyStream::with(receiver_end, opt_receiver_fault)));
+            let sent = send(sender_end);
+            let received = receiving.join().expect("receiver panicked");
+            Transfer { sent, received }
+        })
+    }
+
+    /// run the receiver on `stream` until the session ends, the stream is dropped then
+    pub fn receive<S: Read + Write>(&self, stream: S) -> io::Result<Vec<FileInfo>> {
+        let out_dir = self.output_dir();
+        let mut receiver = ServerStateMachine::new(stream, &out_dir, None)
+            .with_chunk_index(self.opt_index.clone());
+        receiver.start()?;
+        Ok(receiver.received().to_vec())
+    }
+
+    /// run a receiver of protocol version 1 on `stream`: it does not know Hello and ends the
+    /// session on it, and receives like `receive` a request sent without it
+    pub fn receive_legacy<S: Read + Write>(&self, mut stream: S) -> io::Result<Vec<FileInfo>> {
+        let mut header = [0_u8; HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let (action, len) = Packet::parse_header(header);
+        if action == Packet::Hello(Vec::new()).get_action() {
+            stream.read_exact(&mut vec![0_u8; usize::from(len)])?;
+            return Err(Error::new(ErrorKind::InvalidData, "unknown action"));
+        }
+        self.receive(Prefixed {
+            head: io::Cursor::new(header),
+            inner: stream,
+        })
+    }
+
+    /// serve the source directory as the shared directory on `stream` until the session ends
+    pub fn share<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let out_dir = self.output_dir();
+        ServerStateMachine::new(stream, &out_dir, Some(&self.source_dir())).start()
+    }
+
+    /// let the peer on `stream` sync with the output directory until the session ends
+    pub fn sync<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let out_dir = self.output_dir();
+        ServerStateMachine::new(stream, &out_dir, None)
+            .with_sync_dir(Some(&out_dir))
+            .start()
+    }
+
+    /// run the sender of `paths` on `stream` until the session ends, the stream is dropped then
+    pub fn send<S: Read + Write>(&self, stream: S, paths: &[PathBuf]) -> io::Result<ClientOutcome> {
+        let files = source::collect(paths)?;
+        ClientStateMachine::new(stream, &files)
+            .with_dedup(self.opt_index.is_some())
+            .start()
+    }
+
+    /// send like `send`, the kernel copying the files to the socket of `stream` on Linux, the
+    /// events going to `opt_observer`
+    #[cfg(unix)]
+    pub fn send_zero_copy<S>(
+        &self,
+        stream: S,
+        paths: &[PathBuf],
+        opt_observer: Option<Arc<dyn Observer>>,
+    ) -> io::Result<ClientOutcome>
+    where
+        S: Read + Write + std::os::unix::io::AsRawFd,
+    {
+        let files = source::collect(paths)?;
+        let socket = stream.as_raw_fd();
+        ClientStateMachine::new(stream, &files)
+            .with_socket(Some(socket))
+            .with_observer(opt_observer)
+            .start()
+    }
+
+    /// send like a sender of protocol version 1, which starts without Hello and speaks JSON
+    pub fn send_legacy<S: Read + Write>(
+        &self,
+        stream: S,
+        paths: &[PathBuf],
+    ) -> io::Result<ClientOutcome> {
+        let files = source::collect(paths)?;
+        ClientStateMachine::new(stream, &files)
+            .with_encoding(Encoding::Json)
+            .start()
+    }
+}
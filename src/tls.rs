@@ -1,4 +1,8 @@
 use log::debug;
+use rustls_pemfile::Item;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
 use std::{net::TcpStream, sync::Arc};
 
 use rcgen::generate_simple_self_signed;
@@ -6,7 +10,6 @@ use rustls::{
     Certificate, ClientConnection, NoClientAuth, PrivateKey, ProtocolVersion, RootCertStore,
     ServerConfig, ServerConnection, Stream,
 };
-use std::net::SocketAddr;
 
 pub struct TlsTcpServer {
     str: TcpStream,
@@ -14,10 +17,7 @@ pub struct TlsTcpServer {
 }
 
 impl TlsTcpServer {
-    pub fn new(str: TcpStream) -> Self {
-        // create key
-        let keypair = KeyPair::new();
-
+    pub fn new(str: TcpStream, keypair: &KeyPair) -> io::Result<Self> {
         // create config
         let mut config =
             ServerConfig::with_cipher_suites(NoClientAuth::new(), rustls::ALL_CIPHERSUITES);
@@ -25,75 +25,227 @@ impl TlsTcpServer {
         let private_key = keypair.get_private_key();
         config
             .set_single_cert(vec![cert], private_key)
-            .expect("bad certificates/private key");
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
         debug!(
             "TLSv1_3: {}",
             config.supports_version(ProtocolVersion::TLSv1_3)
         );
         let arc_config = Arc::new(config);
         let conn = ServerConnection::new(&arc_config);
-        Self { str, conn }
+        Ok(Self { str, conn })
     }
 
-    pub fn create_tls_str(&mut self) -> Stream<ServerConnection, TcpStream> {
+    pub fn create_tls_str(&mut self) -> Stream<'_, ServerConnection, TcpStream> {
         Stream::new(&mut self.conn, &mut self.str)
     }
 }
 
+impl Read for TlsTcpServer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.create_tls_str().read(buf)
+    }
+}
+
+impl Write for TlsTcpServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.create_tls_str().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.create_tls_str().flush()
+    }
+}
+
 pub struct TlsTcpClient {
     str: TcpStream,
     conn: ClientConnection,
 }
 
 impl TlsTcpClient {
-    pub fn connect(addr: SocketAddr) -> Self {
-        let str = TcpStream::connect(addr).unwrap();
+    /// the client of a TLS session over a connected stream
+    pub fn new(str: TcpStream, fingerprint: Option<&str>) -> std::io::Result<Self> {
         let root_store = RootCertStore::empty();
         let mut config = rustls::ClientConfig::new(root_store, &[], rustls::ALL_CIPHERSUITES);
-        config
-            .dangerous()
-            .set_certificate_verifier(Arc::new(danger::NoCertificateVerification {}));
+        match fingerprint {
+            Some(fp) => config.dangerous().set_certificate_verifier(Arc::new(
+                danger::PinnedCertificateVerification {
+                    fingerprint: fp.to_string(),
+                },
+            )),
+            None => config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(danger::NoCertificateVerification {})),
+        }
         debug!(
             "TLSv1_3: {}",
             config.supports_version(ProtocolVersion::TLSv1_3)
         );
         let arc_config = Arc::new(config);
 
-        let dns_name = webpki::DnsNameRef::try_from_ascii_str("localhost").unwrap();
-        let conn = ClientConnection::new(&arc_config, dns_name).unwrap();
-        Self { conn, str }
+        let dns_name = webpki::DnsNameRef::try_from_ascii_str("localhost")
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server name"))?;
+        let conn = ClientConnection::new(&arc_config, dns_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self { conn, str })
     }
 
-    pub fn create_tls_str(&mut self) -> Stream<ClientConnection, TcpStream> {
+    pub fn create_tls_str(&mut self) -> Stream<'_, ClientConnection, TcpStream> {
         Stream::new(&mut self.conn, &mut self.str)
     }
 }
 
+impl Read for TlsTcpClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.create_tls_str().read(buf)
+    }
+}
+
+impl Write for TlsTcpClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.create_tls_str().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.create_tls_str().flush()
+    }
+}
+
+/// TLS identity of a server: a certificate and its private key
+#[derive(Clone)]
 pub struct KeyPair {
-    inner_cert: rcgen::Certificate,
+    cert_der: Vec<u8>,
+    key_der: Vec<u8>,
 }
 
 impl KeyPair {
+    /// generate a new self-signed certificate
     pub fn new() -> Self {
         // generate
         let subject_alt_names = vec!["localhost".to_string()];
         let inner_cert = generate_simple_self_signed(subject_alt_names).unwrap();
 
-        KeyPair { inner_cert }
+        // serialized once: every serialization signs again and gives different bytes
+        KeyPair {
+            cert_der: inner_cert.serialize_der().unwrap(),
+            key_der: inner_cert.serialize_private_key_der(),
+        }
+    }
+
+    pub fn from_der(cert_der: Vec<u8>, key_der: Vec<u8>) -> Self {
+        KeyPair { cert_der, key_der }
+    }
+
+    /// load the first certificate and the first private key (PKCS#8 or RSA) of PEM files
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let invalid = |what: &str, path: &Path| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no {} found in {:?}", what, path),
+            )
+        };
+        let mut certs = BufReader::new(File::open(cert_path)?);
+        let cert_der = rustls_pemfile::read_all(&mut certs)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::X509Certificate(der) => Some(der),
+                _ => None,
+            })
+            .ok_or_else(|| invalid("certificate", cert_path))?;
+        let mut keys = BufReader::new(File::open(key_path)?);
+        let key_der = rustls_pemfile::read_all(&mut keys)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(der) | Item::RSAKey(der) => Some(der),
+                _ => None,
+            })
+            .ok_or_else(|| invalid("private key", key_path))?;
+        Ok(KeyPair { cert_der, key_der })
+    }
+
+    /// write the certificate and the private key to new PEM files, the key as PKCS#8 and only
+    /// readable by its owner
+    pub fn save_pem_files(&self, cert_path: &Path, key_path: &Path) -> io::Result<()> {
+        let encode = |tag: &str, der: &[u8]| {
+            let pem = pem::Pem {
+                tag: tag.to_string(),
+                contents: der.to_vec(),
+            };
+            let config = pem::EncodeConfig {
+                line_ending: pem::LineEnding::LF,
+            };
+            pem::encode_config(&pem, config)
+        };
+        write_new(key_path, &encode("PRIVATE KEY", &self.key_der), 0o600)?;
+        write_new(cert_path, &encode("CERTIFICATE", &self.cert_der), 0o644)
     }
 
     pub fn get_private_key(&self) -> PrivateKey {
-        PrivateKey(self.inner_cert.serialize_private_key_der())
+        PrivateKey(self.key_der.clone())
     }
 
     pub fn signed_public_key(&self) -> Certificate {
-        Certificate(self.inner_cert.serialize_der().unwrap())
+        Certificate(self.cert_der.clone())
+    }
+
+    /// SHA-256 of the certificate, used to identify the server
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.signed_public_key())
     }
 }
 
+impl Default for KeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// create the file, failing when it exists
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_new(path: &Path, text: &str, mode: u32) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    options.open(path)?.write_all(text.as_bytes())
+}
+
+pub fn fingerprint(cert: &Certificate) -> String {
+    fingerprint_der(&cert.0)
+}
+
+/// SHA-256 of a DER encoded certificate, as hex
+pub fn fingerprint_der(der: &[u8]) -> String {
+    let hash = ring::digest::digest(&ring::digest::SHA256, der);
+    hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 mod danger {
     pub struct NoCertificateVerification {}
 
+    pub struct PinnedCertificateVerification {
+        pub fingerprint: String,
+    }
+
+    impl rustls::ServerCertVerifier for PinnedCertificateVerification {
+        fn verify_server_cert(
+            &self,
+            end_entity: &rustls::Certificate,
+            _intermediates: &[rustls::Certificate],
+            _dns_name: webpki::DnsNameRef<'_>,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp: &[u8],
+            _now: std::time::SystemTime,
+        ) -> Result<rustls::ServerCertVerified, rustls::Error> {
+            if super::fingerprint(end_entity) == self.fingerprint {
+                Ok(rustls::ServerCertVerified::assertion())
+            } else {
+                Err(rustls::Error::General(
+                    "certificate fingerprint does not match".to_string(),
+                ))
+            }
+        }
+    }
+
     impl rustls::ServerCertVerifier for NoCertificateVerification {
         fn verify_server_cert(
             &self,
@@ -0,0 +1,156 @@
+//! in-memory connections, for peers in the same process
+
+use super::{Connection, Endpoint, Listener, PeerInfo, Probe, Transport};
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+
+/// one end of an in-memory duplex stream, reading returns end of file once the other end is dropped
+pub struct MemoryStream {
+    tx: Sender<Vec<u8>>,
+    inbox: Arc<Mutex<Inbox>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// what the other end wrote, with the write a probe took early
+struct Inbox {
+    rx: Receiver<Vec<u8>>,
+    opt_probed: Option<Vec<u8>>,
+}
+
+impl MemoryStream {
+    fn new(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> Self {
+        MemoryStream {
+            tx,
+            inbox: Arc::new(Mutex::new(Inbox {
+                rx,
+                opt_probed: None,
+            })),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// tells whether the other end wrote something not read yet, or is gone
+    pub fn probe(&self) -> Probe {
+        let inbox = self.inbox.clone();
+        Box::new(move || {
+            let mut inbox = inbox.lock().unwrap();
+            if inbox.opt_probed.is_some() {
+                return Ok(true);
+            }
+            match inbox.rx.try_recv() {
+                Ok(data) => {
+                    inbox.opt_probed = Some(data);
+                    Ok(true)
+                }
+                Err(TryRecvError::Empty) => Ok(false),
+                Err(TryRecvError::Disconnected) => Ok(true),
+            }
+        })
+    }
+}
+
+/// two connected ends
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let (tx_a, rx_a) = mpsc::channel();
+    let (tx_b, rx_b) = mpsc::channel();
+    (MemoryStream::new(tx_a, rx_b), MemoryStream::new(tx_b, rx_a))
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.buf.len() {
+            let mut inbox = self.inbox.lock().unwrap();
+            let res = match inbox.opt_probed.take() {
+                Some(data) => Ok(data),
+                None => inbox.rx.recv(),
+            };
+            match res {
+                Ok(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = (&self.buf[self.pos..]).read(buf)?;
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// a transport and the listener it connects to
+pub fn pipe() -> (MemoryTransport, MemoryListener) {
+    let (tx, rx) = mpsc::channel();
+    (
+        MemoryTransport { tx: Mutex::new(tx) },
+        MemoryListener { rx: Mutex::new(rx) },
+    )
+}
+
+pub struct MemoryTransport {
+    tx: Mutex<Sender<MemoryStream>>,
+}
+
+impl Transport for MemoryTransport {
+    fn connect(&self) -> Result<Box<dyn Connection>> {
+        Ok(self.connect_probed()?.0)
+    }
+
+    fn connect_probed(&self) -> Result<(Box<dyn Connection>, Option<Probe>)> {
+        let (local, remote) = duplex();
+        self.tx
+            .lock()
+            .unwrap()
+            .send(remote)
+            .map_err(|_| Error::from(ErrorKind::ConnectionRefused))?;
+        let probe = local.probe();
+        Ok((Box::new(local), Some(probe)))
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Memory
+    }
+}
+
+pub struct MemoryListener {
+    rx: Mutex<Receiver<MemoryStream>>,
+}
+
+impl Listener for MemoryListener {
+    /// fails once every transport of the pipe is dropped
+    fn accept(&self) -> Result<(Box<dyn Connection>, PeerInfo)> {
+        let str = self
+            .rx
+            .lock()
+            .unwrap()
+            .recv()
+            .map_err(|_| io::Error::new(ErrorKind::NotConnected, "no more connections"))?;
+        Ok((Box::new(str), PeerInfo::new(Endpoint::Memory.to_string())))
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        Ok(Endpoint::Memory)
+    }
+}
@@ -0,0 +1,261 @@
+//! how peers reach each other: the state machines run over any `Connection`, a `Transport`
+//! opens connections to a receiver and a `Listener` accepts them
+
+pub mod memory;
+pub(crate) mod proxy;
+mod relay;
+mod tcp;
+#[cfg(unix)]
+mod unix;
+
+pub use proxy::Proxy;
+pub use relay::{RelayListener, RelayTransport};
+pub use tcp::{TcpListener, TcpTransport, TlsListener, TlsTransport};
+#[cfg(unix)]
+pub use unix::{UnixListener, UnixTransport};
+
+use crate::tls::KeyPair;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// descriptor of the socket under a connection
+#[cfg(unix)]
+pub use std::os::unix::io::RawFd as RawSocket;
+#[cfg(windows)]
+pub use std::os::windows::io::RawSocket;
+
+/// a byte stream between two peers
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// tells without blocking whether the peer wrote to a connection since it was last read, for a
+/// sender to notice a file the receiver cancelled
+pub type Probe = Box<dyn FnMut() -> Result<bool> + Send>;
+
+/// opens connections to a receiver
+pub trait Transport: Send + Sync {
+    fn connect(&self) -> Result<Box<dyn Connection>>;
+
+    /// a connection with its socket when the bytes written to the connection go to the socket
+    /// as they are, plaintext and unbuffered, so that the kernel can copy file data to it
+    fn connect_raw(&self) -> Result<(Box<dyn Connection>, Option<RawSocket>)> {
+        Ok((self.connect()?, None))
+    }
+
+    /// a connection with a probe of what the receiver writes to it, when the transport has one
+    fn connect_probed(&self) -> Result<(Box<dyn Connection>, Option<Probe>)> {
+        Ok((self.connect()?, None))
+    }
+
+    /// where the connections go, for logs and reports
+    fn endpoint(&self) -> Endpoint;
+}
+
+/// probe a socket: the receiver only writes Cancel while a file is sent, and the TLS records
+/// that are not data only come with the handshake, so a readable socket means a packet
+#[cfg(unix)]
+pub(crate) fn probe(socket: RawSocket) -> Option<Probe> {
+    Some(Box::new(move || {
+        let mut fd = libc::pollfd {
+            fd: socket,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, 0) } {
+            -1 => Err(Error::last_os_error()),
+            n => Ok(n > 0),
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn probe(_socket: RawSocket) -> Option<Probe> {
+    None
+}
+
+/// who is at the other end of an accepted connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// address of the peer, for logs and reports
+    pub name: String,
+    /// identity of the process, only known for Unix domain sockets
+    pub credentials: Option<PeerCredentials>,
+}
+
+impl PeerInfo {
+    pub fn new(name: String) -> Self {
+        PeerInfo {
+            name,
+            credentials: None,
+        }
+    }
+}
+
+/// owner of the peer process, as reported by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// not available on every system
+    pub pid: Option<u32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// accepts the connections of senders
+pub trait Listener: Send + Sync {
+    /// the next connection and who opened it
+    fn accept(&self) -> Result<(Box<dyn Connection>, PeerInfo)>;
+
+    fn endpoint(&self) -> Result<Endpoint>;
+}
+
+/// where a receiver listens, written as an URL:
+/// `tls://127.0.0.1:7878` (or just `127.0.0.1:7878`), `tcp://127.0.0.1:7878`, `quic://127.0.0.1:7878`,
+/// `unix:/run/sendfile.sock`, `relay://10.0.0.1:7900/CODE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tls(SocketAddr),
+    /// TLS to a host given by name, as in `tls://receiver.lab:7878`, only to connect to: the
+    /// name is resolved when connecting, by the proxy if any
+    TlsHost(String),
+    /// plaintext TCP, only for trusted links
+    Tcp(SocketAddr),
+    /// one stream per file, only served by the async sender and receiver of the `quic` feature
+    Quic(SocketAddr),
+    Unix(PathBuf),
+    /// TLS through the relay at the address, to the peer coming with the same session code
+    Relay(SocketAddr, String),
+    /// a pipe of `memory::pipe`, it cannot be connected to by URL
+    Memory,
+}
+
+impl Endpoint {
+    /// open connections to this endpoint, only trusting the certificate with the given
+    /// fingerprint if any
+    pub fn transport(&self, opt_fingerprint: Option<&str>) -> Result<Arc<dyn Transport>> {
+        self.transport_via(opt_fingerprint, None)
+    }
+
+    /// same as `transport`, the TCP connections going through the proxy if any
+    pub fn transport_via(
+        &self,
+        opt_fingerprint: Option<&str>,
+        opt_proxy: Option<&Proxy>,
+    ) -> Result<Arc<dyn Transport>> {
+        let fingerprint = opt_fingerprint.map(String::from);
+        let proxy = opt_proxy.cloned();
+        match self {
+            Endpoint::Tls(addr) => Ok(Arc::new(
+                TlsTransport::new(*addr, fingerprint).with_proxy(proxy),
+            )),
+            Endpoint::TlsHost(host) => Ok(Arc::new(
+                TlsTransport::named(host.clone(), fingerprint).with_proxy(proxy),
+            )),
+            Endpoint::Tcp(addr) => Ok(Arc::new(TcpTransport::new(*addr).with_proxy(proxy))),
+            Endpoint::Relay(addr, code) => Ok(Arc::new(
+                RelayTransport::new(*addr, code.clone(), fingerprint).with_proxy(proxy),
+            )),
+            #[cfg(unix)]
+            Endpoint::Unix(path) if opt_proxy.is_none() => {
+                Ok(Arc::new(UnixTransport::new(path.clone())))
+            }
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    /// listen on this endpoint, `keypair` is the identity presented by TLS
+    pub fn bind(&self, keypair: &KeyPair) -> Result<Box<dyn Listener>> {
+        match self {
+            Endpoint::Tls(addr) => Ok(Box::new(TlsListener::bind(*addr, keypair.clone())?)),
+            Endpoint::Tcp(addr) => Ok(Box::new(TcpListener::bind(*addr)?)),
+            Endpoint::Relay(addr, code) => Ok(Box::new(RelayListener::new(
+                *addr,
+                code.clone(),
+                keypair.clone(),
+            ))),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Box::new(UnixListener::bind(path)?)),
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    /// the socket address of network endpoints
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Tls(addr) | Endpoint::Tcp(addr) | Endpoint::Quic(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    fn unsupported(&self) -> Error {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("cannot use {} as an endpoint here", self),
+        )
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tls(addr) => write!(f, "tls://{}", addr),
+            Endpoint::TlsHost(host) => write!(f, "tls://{}", host),
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Endpoint::Quic(addr) => write!(f, "quic://{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Relay(addr, code) => write!(f, "relay://{}/{}", addr, code),
+            Endpoint::Memory => write!(f, "memory:"),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_addr = |addr: &str| {
+            addr.parse::<SocketAddr>().map_err(|_| {
+                format!(
+                    "invalid address {:?}, a valid example: 127.0.0.1:8080",
+                    addr
+                )
+            })
+        };
+        // a name is only resolved when connecting, for TLS
+        let parse_tls = |addr: &str| match parse_addr(addr) {
+            Err(_) if proxy::split_host(addr).is_some() => Ok(Endpoint::TlsHost(addr.to_string())),
+            res => res.map(Endpoint::Tls),
+        };
+        if let Some(addr) = s.strip_prefix("tls://") {
+            parse_tls(addr)
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            parse_addr(addr).map(Endpoint::Tcp)
+        } else if let Some(addr) = s.strip_prefix("quic://") {
+            parse_addr(addr).map(Endpoint::Quic)
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            // unix:/path and unix:///path
+            let path = path.strip_prefix("//").unwrap_or(path);
+            if path.is_empty() {
+                return Err(String::from("missing socket path"));
+            }
+            Ok(Endpoint::Unix(PathBuf::from(path)))
+        } else if let Some(rest) = s.strip_prefix("relay://") {
+            let (addr, code) = rest
+                .split_once('/')
+                .ok_or_else(|| String::from("missing session code, as in relay://ADDRESS/CODE"))?;
+            crate::relay::check_code(code)?;
+            Ok(Endpoint::Relay(parse_addr(addr)?, code.to_string()))
+        } else if s.contains("://") {
+            Err(format!(
+                "unknown transport in {:?}, use tls://, tcp://, quic://, relay:// or unix:",
+                s
+            ))
+        } else {
+            parse_tls(s)
+        }
+    }
+}
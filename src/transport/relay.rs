@@ -0,0 +1,88 @@
+use super::proxy::Proxy;
+use super::{Connection, Endpoint, Listener, PeerInfo, Transport};
+use crate::relay::{self, Role};
+use crate::tls::{KeyPair, TlsTcpClient, TlsTcpServer};
+use log::info;
+use std::io::Result;
+use std::net::SocketAddr;
+
+/// TLS through a relay, from end to end: the relay pairs the sender with the receiver waiting
+/// there with the same session code
+pub struct RelayTransport {
+    addr: SocketAddr,
+    code: String,
+    opt_fingerprint: Option<String>,
+    opt_proxy: Option<Proxy>,
+}
+
+impl RelayTransport {
+    /// only trust the certificate with the given fingerprint if any
+    pub fn new(addr: SocketAddr, code: String, opt_fingerprint: Option<String>) -> Self {
+        RelayTransport {
+            addr,
+            code,
+            opt_fingerprint,
+            opt_proxy: None,
+        }
+    }
+
+    /// connect to the relay through a proxy
+    pub fn with_proxy(mut self, opt_proxy: Option<Proxy>) -> Self {
+        self.opt_proxy = opt_proxy;
+        self
+    }
+}
+
+impl Transport for RelayTransport {
+    fn connect(&self) -> Result<Box<dyn Connection>> {
+        let (str, _) =
+            relay::rendezvous(self.addr, self.opt_proxy.as_ref(), Role::Send, &self.code)?;
+        let client = TlsTcpClient::new(str, self.opt_fingerprint.as_deref())?;
+        Ok(Box::new(client))
+    }
+
+    #[cfg(unix)]
+    fn connect_probed(&self) -> Result<(Box<dyn Connection>, Option<super::Probe>)> {
+        use std::os::unix::io::AsRawFd;
+
+        let (str, _) =
+            relay::rendezvous(self.addr, self.opt_proxy.as_ref(), Role::Send, &self.code)?;
+        let opt_probe = super::probe(str.as_raw_fd());
+        let client = TlsTcpClient::new(str, self.opt_fingerprint.as_deref())?;
+        Ok((Box::new(client), opt_probe))
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Relay(self.addr, self.code.clone())
+    }
+}
+
+/// waits at a relay for the senders coming with its session code, one at a time
+pub struct RelayListener {
+    addr: SocketAddr,
+    code: String,
+    keypair: KeyPair,
+}
+
+impl RelayListener {
+    pub fn new(addr: SocketAddr, code: String, keypair: KeyPair) -> Self {
+        RelayListener {
+            addr,
+            code,
+            keypair,
+        }
+    }
+}
+
+impl Listener for RelayListener {
+    fn accept(&self) -> Result<(Box<dyn Connection>, PeerInfo)> {
+        info!("waiting for a sender at relay {}....", self.addr);
+        let (str, peer) = relay::rendezvous(self.addr, None, Role::Receive, &self.code)?;
+        let server = TlsTcpServer::new(str, &self.keypair)?;
+        Ok((Box::new(server), PeerInfo::new(peer)))
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        Ok(Endpoint::Relay(self.addr, self.code.clone()))
+    }
+}
@@ -0,0 +1,177 @@
+use super::proxy::{self, Proxy};
+use super::{Connection, Endpoint, Listener, PeerInfo, Transport};
+use crate::tls::{KeyPair, TlsTcpClient, TlsTcpServer};
+use log::{info, warn};
+use std::io::Result;
+use std::net::{self, SocketAddr, TcpStream};
+
+/// where a TLS transport connects to
+enum Host {
+    Addr(SocketAddr),
+    /// `name:port`, resolved when connecting
+    Name(String),
+}
+
+/// TLS over TCP, the default transport
+pub struct TlsTransport {
+    host: Host,
+    opt_fingerprint: Option<String>,
+    opt_proxy: Option<Proxy>,
+}
+
+impl TlsTransport {
+    /// only trust the certificate with the given fingerprint if any
+    pub fn new(addr: SocketAddr, opt_fingerprint: Option<String>) -> Self {
+        TlsTransport {
+            host: Host::Addr(addr),
+            opt_fingerprint,
+            opt_proxy: None,
+        }
+    }
+
+    /// connect to the host `name:port`, the name resolved by the proxy if any
+    pub fn named(host: String, opt_fingerprint: Option<String>) -> Self {
+        TlsTransport {
+            host: Host::Name(host),
+            opt_fingerprint,
+            opt_proxy: None,
+        }
+    }
+
+    /// connect through a proxy
+    pub fn with_proxy(mut self, opt_proxy: Option<Proxy>) -> Self {
+        self.opt_proxy = opt_proxy;
+        self
+    }
+
+    fn dial(&self) -> Result<TcpStream> {
+        match &self.host {
+            Host::Addr(addr) => proxy::dial(*addr, self.opt_proxy.as_ref()),
+            Host::Name(host) => proxy::dial_host(host, self.opt_proxy.as_ref()),
+        }
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect(&self) -> Result<Box<dyn Connection>> {
+        let client = TlsTcpClient::new(self.dial()?, self.opt_fingerprint.as_deref())?;
+        Ok(Box::new(client))
+    }
+
+    #[cfg(unix)]
+    fn connect_probed(&self) -> Result<(Box<dyn Connection>, Option<super::Probe>)> {
+        use std::os::unix::io::AsRawFd;
+
+        let str = self.dial()?;
+        let opt_probe = super::probe(str.as_raw_fd());
+        let client = TlsTcpClient::new(str, self.opt_fingerprint.as_deref())?;
+        Ok((Box::new(client), opt_probe))
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        match &self.host {
+            Host::Addr(addr) => Endpoint::Tls(*addr),
+            Host::Name(host) => Endpoint::TlsHost(host.clone()),
+        }
+    }
+}
+
+pub struct TlsListener {
+    listener: net::TcpListener,
+    keypair: KeyPair,
+}
+
+impl TlsListener {
+    pub fn bind(addr: SocketAddr, keypair: KeyPair) -> Result<Self> {
+        let listener = net::TcpListener::bind(addr)?;
+        Ok(TlsListener { listener, keypair })
+    }
+}
+
+impl Listener for TlsListener {
+    fn accept(&self) -> Result<(Box<dyn Connection>, PeerInfo)> {
+        info!("waiting for new TCP connection....");
+        let (str, addr) = self.listener.accept()?;
+        let server = TlsTcpServer::new(str, &self.keypair)?;
+        Ok((Box::new(server), PeerInfo::new(addr.to_string())))
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        Ok(Endpoint::Tls(self.listener.local_addr()?))
+    }
+}
+
+/// plaintext TCP: nothing is encrypted nor authenticated, only for trusted links
+pub struct TcpTransport {
+    addr: SocketAddr,
+    opt_proxy: Option<Proxy>,
+}
+
+impl TcpTransport {
+    pub fn new(addr: SocketAddr) -> Self {
+        TcpTransport {
+            addr,
+            opt_proxy: None,
+        }
+    }
+
+    /// connect through a proxy
+    pub fn with_proxy(mut self, opt_proxy: Option<Proxy>) -> Self {
+        self.opt_proxy = opt_proxy;
+        self
+    }
+
+    fn open(&self) -> Result<TcpStream> {
+        warn!("connecting to {} without encryption", self.addr);
+        proxy::dial(self.addr, self.opt_proxy.as_ref())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self) -> Result<Box<dyn Connection>> {
+        Ok(Box::new(self.open()?))
+    }
+
+    #[cfg(unix)]
+    fn connect_raw(&self) -> Result<(Box<dyn Connection>, Option<super::RawSocket>)> {
+        use std::os::unix::io::AsRawFd;
+
+        let str = self.open()?;
+        let socket = str.as_raw_fd();
+        Ok((Box::new(str), Some(socket)))
+    }
+
+    #[cfg(unix)]
+    fn connect_probed(&self) -> Result<(Box<dyn Connection>, Option<super::Probe>)> {
+        let (str, opt_socket) = self.connect_raw()?;
+        Ok((str, opt_socket.and_then(super::probe)))
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Tcp(self.addr)
+    }
+}
+
+pub struct TcpListener {
+    listener: net::TcpListener,
+}
+
+impl TcpListener {
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        warn!("listening on {} without encryption", addr);
+        let listener = net::TcpListener::bind(addr)?;
+        Ok(TcpListener { listener })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> Result<(Box<dyn Connection>, PeerInfo)> {
+        info!("waiting for new TCP connection....");
+        let (str, addr) = self.listener.accept()?;
+        Ok((Box::new(str), PeerInfo::new(addr.to_string())))
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        Ok(Endpoint::Tcp(self.listener.local_addr()?))
+    }
+}
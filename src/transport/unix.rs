@@ -0,0 +1,165 @@
+use super::{
+    Connection, Endpoint, Listener, PeerCredentials, PeerInfo, Probe, RawSocket, Transport,
+};
+use log::{info, warn};
+use std::fs::{self, Permissions};
+use std::io::{self, Error, ErrorKind, Result};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{self, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Unix domain socket, for peers on the same host
+pub struct UnixTransport {
+    path: PathBuf,
+}
+
+impl UnixTransport {
+    pub fn new(path: PathBuf) -> Self {
+        UnixTransport { path }
+    }
+}
+
+impl Transport for UnixTransport {
+    fn connect(&self) -> Result<Box<dyn Connection>> {
+        Ok(Box::new(UnixStream::connect(&self.path)?))
+    }
+
+    fn connect_raw(&self) -> Result<(Box<dyn Connection>, Option<RawSocket>)> {
+        let str = UnixStream::connect(&self.path)?;
+        let socket = str.as_raw_fd();
+        Ok((Box::new(str), Some(socket)))
+    }
+
+    fn connect_probed(&self) -> Result<(Box<dyn Connection>, Option<Probe>)> {
+        let (str, opt_socket) = self.connect_raw()?;
+        Ok((str, opt_socket.and_then(super::probe)))
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Unix(self.path.clone())
+    }
+}
+
+/// access to the socket is controlled by the permissions of its file, and optionally by the
+/// user ids of the connecting processes; the file is removed when the listener is dropped
+pub struct UnixListener {
+    listener: net::UnixListener,
+    path: PathBuf,
+    allowed_uids: Vec<u32>,
+}
+
+impl UnixListener {
+    /// listen on `path`, replacing the socket file left by a listener that is gone
+    pub fn bind(path: &Path) -> Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = net::UnixListener::bind(path)?;
+        Ok(UnixListener {
+            listener,
+            path: path.to_path_buf(),
+            allowed_uids: Vec::new(),
+        })
+    }
+
+    /// permissions of the socket file (for example 0o660 to let the group connect)
+    pub fn set_mode(&self, mode: u32) -> Result<()> {
+        fs::set_permissions(&self.path, Permissions::from_mode(mode))
+    }
+
+    /// only keep the connections of processes run by these users, everyone allowed when empty
+    pub fn set_allowed_uids(&mut self, uids: Vec<u32>) {
+        self.allowed_uids = uids
+    }
+}
+
+impl Listener for UnixListener {
+    fn accept(&self) -> Result<(Box<dyn Connection>, PeerInfo)> {
+        loop {
+            info!("waiting for new connection on {:?}....", self.path);
+            let (str, _) = self.listener.accept()?;
+            let credentials = peer_credentials(&str)?;
+            if !self.allowed_uids.is_empty() && !self.allowed_uids.contains(&credentials.uid) {
+                warn!("refused connection of uid {}", credentials.uid);
+                continue;
+            }
+            let peer = PeerInfo {
+                name: format!("unix:{} (uid {})", self.path.display(), credentials.uid),
+                credentials: Some(credentials),
+            };
+            return Ok((Box::new(str), peer));
+        }
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        Ok(Endpoint::Unix(self.path.clone()))
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// a socket file nobody listens on anymore is removed, anything else is left alone
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} exists and is not a socket", path),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("{:?} is used by another server", path),
+        )),
+        Err(_) => {
+            info!("removing stale socket {:?}", path);
+            fs::remove_file(path)
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(str: &UnixStream) -> Result<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            str.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: Some(cred.pid as u32),
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(str: &UnixStream) -> Result<PeerCredentials> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    let ret = unsafe { libc::getpeereid(str.as_raw_fd(), &mut uid, &mut gid) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: None,
+        uid,
+        gid,
+    })
+}
@@ -0,0 +1,163 @@
+//! file data written to a plaintext socket by the kernel with `sendfile(2)`, without copying it
+//! through user space; Linux only, and never over TLS: the rustls in use does not hand the keys
+//! of a session over to the kernel (kernel TLS), so encrypted data always goes through user space.
+//! The data is still read once from the page cache for the SHA-256 of the file
+
+use crate::source::ContentHash;
+use crate::transport::RawSocket;
+use std::fs::File;
+use std::io::Result;
+use std::path::Path;
+
+/// writes the FileData of a file straight to the socket of the session
+pub(crate) struct ZeroCopy {
+    socket: RawSocket,
+    file: File,
+    pos: u64,
+    size: u64,
+    /// SHA-256 of the content so far, the data copied by the kernel is read again for it
+    hash: ContentHash,
+    /// the file cannot be given to `sendfile`, its data is read and written instead
+    fallback: bool,
+}
+
+impl ZeroCopy {
+    /// write the file at `path` from `offset` to `socket`, None when the system cannot;
+    /// `hash` covers the content before `offset`
+    pub(crate) fn open(
+        socket: RawSocket,
+        path: &Path,
+        offset: u64,
+        hash: ContentHash,
+    ) -> Result<Option<Self>> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        if !cfg!(any(target_os = "linux", target_os = "android")) || !meta.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(ZeroCopy {
+            socket,
+            file,
+            pos: offset,
+            size: meta.len(),
+            hash,
+            fallback: false,
+        }))
+    }
+
+    /// write the next FileData to the socket, header and data, false at the end of the file;
+    /// the stream of the session must have nothing buffered
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn next_data(&mut self) -> Result<bool> {
+        use crate::packet::{Packet, FILE_DATA_LEN};
+        use log::warn;
+        use std::io::ErrorKind;
+        use std::os::unix::fs::FileExt;
+
+        if self.pos >= self.size {
+            return Ok(false);
+        }
+        let len = (self.size - self.pos).min(FILE_DATA_LEN as u64) as usize;
+        let action = Packet::FileData(Vec::new()).get_action();
+        send(
+            self.socket,
+            &Packet::header(action, len as u16),
+            libc::MSG_MORE,
+        )?;
+        let copied = !self.fallback && self.copy(len)?;
+        if !self.fallback && !copied {
+            warn!("sendfile is not supported for this file, its data goes through user space");
+            self.fallback = true;
+        }
+        // the data the kernel sent is read back from the page cache for the hash
+        let mut data = vec![0_u8; len];
+        self.file
+            .read_exact_at(&mut data, self.pos)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => shrunk(),
+                _ => e,
+            })?;
+        if !copied {
+            send(self.socket, &data, 0)?;
+        }
+        self.hash.update(&data);
+        self.pos += len as u64;
+        Ok(true)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn next_data(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// how far in the file the data so far goes
+    pub(crate) fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// the hash of the content sent, once the whole file is
+    pub(crate) fn into_hash(self) -> ContentHash {
+        self.hash
+    }
+
+    /// let the kernel write `len` bytes of the file to the socket, false when it cannot and
+    /// nothing was written
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn copy(&self, len: usize) -> Result<bool> {
+        use std::convert::TryFrom;
+        use std::io::{Error, ErrorKind};
+        use std::os::unix::io::AsRawFd;
+
+        let mut offset = libc::off64_t::try_from(self.pos)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "file too large"))?;
+        let mut left = len;
+        while left > 0 {
+            let ret =
+                unsafe { libc::sendfile64(self.socket, self.file.as_raw_fd(), &mut offset, left) };
+            if ret > 0 {
+                left -= ret as usize;
+                continue;
+            }
+            if ret == 0 {
+                return Err(shrunk());
+            }
+            let err = Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => {}
+                Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) if left == len => {
+                    return Ok(false)
+                }
+                _ => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn shrunk() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "the file got shorter during the transfer",
+    )
+}
+
+/// write all of `buf` to the socket, `flags` of `send(2)`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn send(socket: RawSocket, mut buf: &[u8], flags: libc::c_int) -> Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    while !buf.is_empty() {
+        let ptr = buf.as_ptr() as *const libc::c_void;
+        let ret = unsafe { libc::send(socket, ptr, buf.len(), flags | libc::MSG_NOSIGNAL) };
+        if ret < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        buf = &buf[ret as usize..];
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use sendfile_cli::testing::TempDir;
+use sendfile_cli::{CancelHandle, Endpoint, Event, Receiver, Sender};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const BIG_LEN: usize = 32 * 1024 * 1024;
+
+#[test]
+fn file_cancelled_by_the_receiver_is_skipped_by_the_sender() {
+    let tmp = TempDir::new().unwrap();
+    let big = tmp.path().join("big.bin");
+    let small = tmp.path().join("small.txt");
+    fs::write(&big, vec![7_u8; BIG_LEN]).unwrap();
+    fs::write(&small, b"small").unwrap();
+
+    let out = TempDir::new().unwrap();
+    let cancel = CancelHandle::new();
+    let on_start = cancel.clone();
+    let receiver = Receiver::builder()
+        .endpoint(Endpoint::Tls("127.0.0.1:0".parse().unwrap()))
+        .output_dir(out.path())
+        .cancel_handle(cancel)
+        .observer(move |event: &Event| {
+            if let Event::FileStarted { index: 0, .. } = event {
+                on_start.cancel_file("not wanted");
+            }
+        })
+        .build()
+        .unwrap();
+    let endpoint = receiver.endpoint().unwrap();
+    let fingerprint = receiver.fingerprint();
+    let receiving = thread::spawn(move || receiver.accept());
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let sender = Sender::builder()
+        .endpoint(endpoint)
+        .file(&big)
+        .file(&small)
+        .fingerprint(&fingerprint)
+        .observer(move |event: &Event| seen.lock().unwrap().push(event.clone()))
+        .build()
+        .unwrap();
+    sender.send().unwrap();
+    receiving.join().unwrap().unwrap();
+
+    assert!(!out.path().join("big.bin").exists());
+    assert_eq!(fs::read(out.path().join("small.txt")).unwrap(), b"small");
+    let events = events.lock().unwrap();
+    assert!(events.contains(&Event::Cancelled {
+        index: Some(0),
+        reason: "not wanted".to_string(),
+        by_peer: true,
+    }));
+    // the sender stopped reading the big file once told
+    let sent = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Progress { index: 0, bytes } => Some(*bytes),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    assert!(sent < BIG_LEN as u64);
+}
@@ -0,0 +1,50 @@
+use sendfile_cli::config::{self, AcceptSettings};
+use sendfile_cli::testing::TempDir;
+use sendfile_cli::{Error, FileInfo};
+use std::fs;
+
+#[test]
+fn streamed_files_are_refused_when_sizes_are_limited() {
+    let request = [FileInfo::streamed(String::from("stdin"))];
+    assert!(AcceptSettings::default().allows(&request));
+    let accept = AcceptSettings {
+        max_files: Some(1),
+        ..AcceptSettings::default()
+    };
+    assert!(accept.allows(&request));
+    let accept = AcceptSettings {
+        max_total_size: Some(1024),
+        ..AcceptSettings::default()
+    };
+    assert!(!accept.allows(&request));
+}
+
+#[test]
+fn unknown_settings_are_rejected() {
+    let tmp = TempDir::new().unwrap();
+    let path = tmp.path().join("config.toml");
+    let load = |text: &str| {
+        fs::write(&path, text).unwrap();
+        config::load(Some(&path), Some("lab"))
+    };
+
+    let settings =
+        load("port = 9000\n[accept]\nmax_files = 2\n[profiles.lab]\nretries = 1\n").unwrap();
+    assert_eq!(settings.port, Some(9000));
+    assert_eq!(settings.accept.max_files, Some(2));
+    assert_eq!(settings.retries, Some(1));
+
+    for text in [
+        "prot = 9000\n",
+        "[accept]\nmax_file = 2\n",
+        "[profiles.lab]\nretry = 1\n",
+        "[profiles.lab.identity]\ncertificate = \"lab.pem\"\n",
+    ] {
+        match load(text) {
+            Err(Error::Config(message)) => {
+                assert!(message.contains("unknown field"), "{}", message)
+            }
+            res => panic!("{:?} loaded: {:?}", text, res),
+        }
+    }
+}
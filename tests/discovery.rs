@@ -0,0 +1,54 @@
+use sendfile_cli::discovery::{self, DISCOVERY_PORT};
+use sendfile_cli::testing::TempDir;
+use sendfile_cli::{Endpoint, Receiver};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+/// the peers called `name` heard during `timeout`, other tests announce too
+fn heard(name: &str, timeout: Duration) -> usize {
+    let peers = discovery::discover(timeout).unwrap();
+    peers.iter().filter(|p| p.name == name).count()
+}
+
+#[cfg(unix)]
+#[test]
+fn listeners_share_the_discovery_port() {
+    let listening: Vec<_> = (0..2)
+        .map(|_| thread::spawn(|| discovery::discover(Duration::from_millis(500))))
+        .collect();
+    thread::sleep(Duration::from_millis(100));
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let beacon = br#"{"name":"lab","port":7878,"fingerprint":"ab"}"#;
+    socket
+        .send_to(beacon, (Ipv4Addr::LOCALHOST, DISCOVERY_PORT))
+        .unwrap();
+
+    let mut found = 0;
+    for listener in listening {
+        let peers = listener
+            .join()
+            .unwrap()
+            .expect("both listeners bind the port");
+        found += peers.iter().filter(|p| p.name == "lab").count();
+    }
+    // a datagram sent to one address reaches one of the sockets
+    assert_eq!(found, 1);
+}
+
+#[test]
+fn announcing_stops_with_the_receiver() {
+    let out = TempDir::new().unwrap();
+    let mut receiver = Receiver::builder()
+        .endpoint(Endpoint::Tls("127.0.0.1:0".parse().unwrap()))
+        .output_dir(out.path())
+        .announce("stopping")
+        .build()
+        .unwrap();
+    assert_eq!(heard("stopping", Duration::from_millis(2500)), 1);
+
+    receiver.stop_announcing();
+    // a beacon sent just before may still be on its way
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(heard("stopping", Duration::from_millis(2500)), 0);
+}
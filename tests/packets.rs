@@ -0,0 +1,218 @@
+use sendfile_cli::testing::{decode_packet, frame_bytes, read_frames, Encoding};
+use std::io::ErrorKind;
+use std::io::Result;
+
+const SEND: u8 = 0;
+const ACCEPT: u8 = 1;
+const START_FILE: u8 = 3;
+const GET: u8 = 11;
+const CANCEL: u8 = 13;
+const HELLO: u8 = 14;
+const HAVE: u8 = 15;
+const SIGNATURE: u8 = 16;
+const COPY: u8 = 17;
+const DEDUP: u8 = 18;
+const CHUNKS: u8 = 19;
+const WANT: u8 = 20;
+const HOLE: u8 = 21;
+
+fn json(action: u8, data: &[u8]) -> Result<()> {
+    decode_packet(action, data, Encoding::Json)
+}
+
+fn binary(action: u8, data: &[u8]) -> Result<()> {
+    decode_packet(action, data, Encoding::Binary)
+}
+
+fn is_invalid(res: Result<()>) -> bool {
+    match res {
+        Ok(_) => false,
+        Err(e) => e.kind() == ErrorKind::InvalidData,
+    }
+}
+
+fn rejected(action: u8, data: &[u8]) -> bool {
+    is_invalid(json(action, data))
+}
+
+fn request(names: &[String]) -> Vec<u8> {
+    let files: Vec<String> = names
+        .iter()
+        .map(|n| format!(r#"{{"name":"{}","size":1}}"#, n))
+        .collect();
+    format!("[{}]", files.join(",")).into_bytes()
+}
+
+#[test]
+fn valid_packets() {
+    assert!(json(SEND, &request(&["dir/a.txt".to_string()])).is_ok());
+    assert!(json(ACCEPT, b"").is_ok());
+    let start = br#"{"file_info":{"name":"a","size":1},"index":0,"total":1}"#;
+    assert!(json(START_FILE, start).is_ok());
+    assert!(json(CANCEL, br#"{"scope":"file","reason":"skip"}"#).is_ok());
+    assert!(json(CANCEL, br#"{"scope":"file","reason":"skip","index":3}"#).is_ok());
+}
+
+#[test]
+fn unknown_fields() {
+    assert!(rejected(SEND, br#"[{"name":"a","size":1,"mode":755}]"#));
+    assert!(rejected(
+        CANCEL,
+        br#"{"scope":"file","reason":"","by":"me"}"#
+    ));
+}
+
+#[test]
+fn data_of_empty_packets() {
+    assert!(rejected(ACCEPT, b"x"));
+}
+
+#[test]
+fn long_and_deep_names() {
+    assert!(rejected(SEND, &request(&["a".repeat(5000)])));
+    assert!(rejected(SEND, &request(&[vec!["d"; 100].join("/")])));
+    assert!(rejected(GET, br#"["a\u0000b"]"#));
+}
+
+#[test]
+fn too_many_entries() {
+    let names = vec![String::from("\"a\""); 5000].join(",");
+    assert!(rejected(GET, format!("[{}]", names).as_bytes()));
+}
+
+#[test]
+fn start_file_index() {
+    let start = br#"{"file_info":{"name":"a","size":1},"index":1,"total":1}"#;
+    assert!(rejected(START_FILE, start));
+}
+
+#[test]
+fn deep_nesting() {
+    let deep = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+    assert!(rejected(SEND, deep.as_bytes()));
+    assert!(rejected(SEND, &b"[".repeat(60_000)));
+}
+
+#[test]
+fn frames() {
+    let mut input = frame_bytes(ACCEPT, b"");
+    input.extend(frame_bytes(SEND, &request(&["a".to_string()])));
+    assert_eq!(read_frames(&input).unwrap(), 2);
+
+    // the body is shorter than announced
+    let mut truncated = frame_bytes(SEND, &request(&["a".to_string()]));
+    truncated.truncate(10);
+    assert_eq!(read_frames(&truncated).unwrap(), 0);
+
+    assert!(read_frames(&frame_bytes(200, b"")).is_err());
+}
+
+/// a manifest of protocol version 2 with names of 12 bytes, sent in full
+fn binary_request(count: usize) -> Vec<u8> {
+    let mut body = varint(count);
+    for i in 0..count {
+        body.extend([0, 0, 12]);
+        body.extend(format!("file-{:07}", i).as_bytes());
+        body.push(0);
+    }
+    body
+}
+
+fn varint(mut n: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+    bytes
+}
+
+#[test]
+fn binary_packets() {
+    // dir/a.txt of 3 bytes modified at 1, then dir/b.txt of 128 bytes sharing "dir/"
+    let mut send = vec![2, 1, 0, 9];
+    send.extend(b"dir/a.txt");
+    send.extend([3, 1, 0, 4, 5]);
+    send.extend(b"b.txt");
+    send.extend([0x80, 0x01]);
+    assert!(binary(SEND, &send).is_ok());
+    assert!(is_invalid(binary(SEND, &send[..send.len() - 1])));
+    assert!(is_invalid(binary(SEND, &[send.as_slice(), &[0]].concat())));
+
+    // the second name shares more than the length of the first one
+    let mut prefix = send.clone();
+    prefix[16] = 10;
+    assert!(is_invalid(binary(SEND, &prefix)));
+
+    let mut flags = send.clone();
+    flags[1] = 8;
+    assert!(is_invalid(binary(SEND, &flags)));
+
+    // a number with a useless last byte
+    assert!(is_invalid(binary(SEND, &[0x80, 0x00])));
+    assert!(is_invalid(binary(SEND, &varint((1 << 20) + 1))));
+    assert!(is_invalid(binary(START_FILE, b"")));
+    // an integrity failure found by the receiver
+    assert!(binary(CANCEL, &[2, 0]).is_ok());
+    assert!(is_invalid(binary(CANCEL, &[3, 0])));
+    // the index of the file a receiver cancels, after the reason
+    assert!(binary(CANCEL, &[0, 0, 3]).is_ok());
+    assert!(is_invalid(binary(CANCEL, &[0, 0, 3, 1])));
+
+    // 10 files, the first and the tenth already there
+    assert!(binary(HAVE, &[10, 0x01, 0x02]).is_ok());
+    assert!(json(HAVE, b"[true,false]").is_ok());
+    assert!(is_invalid(binary(HAVE, &[10, 0x01])));
+    // a bit set past the last file
+    assert!(is_invalid(binary(HAVE, &[10, 0x01, 0x04])));
+
+    // file 0 in blocks of 1024 bytes, one block: weak then strong checksum
+    let signature = [&[0, 0x80, 0x08, 1][..], &[7; 4 + 16]].concat();
+    assert!(binary(SIGNATURE, &signature).is_ok());
+    assert!(is_invalid(binary(
+        SIGNATURE,
+        &signature[..signature.len() - 1]
+    )));
+    assert!(is_invalid(binary(SIGNATURE, &[0, 0, 0])));
+    assert!(binary(COPY, &[3, 2]).is_ok());
+    assert!(is_invalid(binary(COPY, &[3, 0])));
+
+    // one chunk of 1024 bytes: its length then its hash
+    let chunks = [&[1, 0x80, 0x08][..], &[7; 32]].concat();
+    assert!(binary(CHUNKS, &chunks).is_ok());
+    assert!(is_invalid(binary(CHUNKS, &chunks[..chunks.len() - 1])));
+    assert!(is_invalid(binary(
+        CHUNKS,
+        &[&[1, 0][..], &[7; 32]].concat()
+    )));
+    assert!(is_invalid(binary(
+        CHUNKS,
+        &[&[1, 0x81, 0x80, 0x40][..], &[7; 32]].concat()
+    )));
+    assert!(binary(WANT, &[3, 0x05]).is_ok());
+    assert!(binary(DEDUP, b"").is_ok());
+    assert!(is_invalid(binary(DEDUP, &[0])));
+    assert!(binary(HOLE, &[0x80, 0x08, 0x80, 0x20]).is_ok());
+    assert!(json(HOLE, br#"{"offset":0,"len":4096}"#).is_ok());
+    assert!(is_invalid(binary(HOLE, &[0x80, 0x08, 0])));
+}
+
+#[test]
+fn continued_frames() {
+    let body = binary_request(5000);
+    assert!(body.len() > 65535);
+    let mut input = frame_bytes(HELLO, &[2, 1]);
+    input.extend(frame_bytes(SEND, &body[..65535]));
+    input.extend(frame_bytes(SEND, &body[65535..]));
+    assert_eq!(read_frames(&input).unwrap(), 1);
+
+    // another packet in the middle of the frames
+    let mut interleaved = frame_bytes(HELLO, &[2]);
+    interleaved.extend(frame_bytes(SEND, &body[..65535]));
+    interleaved.extend(frame_bytes(ACCEPT, b""));
+    assert!(read_frames(&interleaved).is_err());
+
+    // without Hello, a full frame is a whole packet of JSON
+    assert!(read_frames(&frame_bytes(SEND, &body[..65535])).is_err());
+}
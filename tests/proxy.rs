@@ -0,0 +1,194 @@
+use sendfile_cli::testing::TempDir;
+use sendfile_cli::transport::Proxy;
+use sendfile_cli::{Endpoint, Receiver, Sender};
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+/// a name only the proxies of these tests resolve, to the loopback address
+const HOST_NAME: &str = "receiver.test";
+
+/// copy the bytes of each connection to the other one until both end
+fn relay(client: TcpStream, target: TcpStream) -> io::Result<()> {
+    let (mut from_client, mut to_client) = (client.try_clone()?, client);
+    let (mut from_target, mut to_target) = (target.try_clone()?, target);
+    let forward = thread::spawn(move || {
+        let _ = io::copy(&mut from_client, &mut to_target);
+        let _ = to_target.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut from_target, &mut to_client);
+    let _ = to_client.shutdown(Shutdown::Write);
+    let _ = forward.join();
+    Ok(())
+}
+
+/// a SOCKS5 proxy taking `user:secret` for one connection, the host asked for goes to `hosts`
+fn socks5_proxy(hosts: mpsc::Sender<String>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || -> io::Result<()> {
+        let (mut client, _) = listener.accept()?;
+        let mut greeting = [0_u8; 3];
+        client.read_exact(&mut greeting)?;
+        assert_eq!(greeting, [5, 1, 2]);
+        client.write_all(&[5, 2])?;
+        let mut auth = [0_u8; 13];
+        client.read_exact(&mut auth)?;
+        assert_eq!(&auth, b"\x01\x04user\x06secret");
+        client.write_all(&[1, 0])?;
+        let mut request = [0_u8; 4];
+        client.read_exact(&mut request)?;
+        assert_eq!(request[..3], [5, 1, 0]);
+        let name = match request[3] {
+            1 => {
+                let mut ip = [0_u8; 4];
+                client.read_exact(&mut ip)?;
+                Ipv4Addr::from(ip).to_string()
+            }
+            3 => {
+                let mut len = [0_u8; 1];
+                client.read_exact(&mut len)?;
+                let mut name = vec![0_u8; usize::from(len[0])];
+                client.read_exact(&mut name)?;
+                String::from_utf8(name).unwrap()
+            }
+            atyp => panic!("unexpected address type {}", atyp),
+        };
+        let mut port = [0_u8; 2];
+        client.read_exact(&mut port)?;
+        let port = u16::from_be_bytes(port);
+        hosts.send(format!("{}:{}", name, port)).unwrap();
+        let ip = if name == HOST_NAME {
+            Ipv4Addr::LOCALHOST
+        } else {
+            name.parse().unwrap()
+        };
+        let target = TcpStream::connect((ip, port))?;
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+        relay(client, target)
+    });
+    addr
+}
+
+/// an HTTP proxy answering `status` to one CONNECT, whose head goes to `heads`; the tunnel is
+/// only opened for a 200
+fn http_proxy(heads: mpsc::Sender<String>, status: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || -> io::Result<()> {
+        let (mut client, _) = listener.accept()?;
+        let mut head = Vec::new();
+        let mut byte = [0_u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte)?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let target = head.split_whitespace().nth(1).unwrap().to_string();
+        heads.send(head).unwrap();
+        write!(client, "HTTP/1.1 {}\r\n\r\n", status)?;
+        if !status.starts_with("200") {
+            return Ok(());
+        }
+        let (name, port) = target.rsplit_once(':').unwrap();
+        let name = if name == HOST_NAME { "127.0.0.1" } else { name };
+        relay(client, TcpStream::connect((name, port.parse().unwrap()))?)
+    });
+    addr
+}
+
+/// send a file to a receiver through `proxy`, naming the receiver `HOST_NAME` if `named`;
+/// returns the port of the receiver
+fn send_through(proxy: Proxy, named: bool) -> u16 {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("a.txt");
+    fs::write(&path, b"hello").unwrap();
+    let out = dir.path().join("out");
+    fs::create_dir(&out).unwrap();
+
+    let receiver = Receiver::builder()
+        .endpoint(Endpoint::Tls("127.0.0.1:0".parse().unwrap()))
+        .output_dir(&out)
+        .build()
+        .unwrap();
+    let endpoint = receiver.endpoint().unwrap();
+    let port = endpoint.socket_addr().unwrap().port();
+    let fingerprint = receiver.fingerprint();
+    let receiving = thread::spawn(move || receiver.accept());
+
+    let endpoint = if named {
+        format!("tls://{}:{}", HOST_NAME, port).parse().unwrap()
+    } else {
+        endpoint
+    };
+    let sender = Sender::builder()
+        .endpoint(endpoint)
+        .file(&path)
+        .fingerprint(&fingerprint)
+        .proxy(proxy)
+        .build()
+        .unwrap();
+    sender.send().unwrap();
+    receiving.join().unwrap().unwrap();
+    assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"hello");
+    port
+}
+
+#[test]
+fn tls_goes_through_a_socks5_proxy() {
+    let (hosts, asked) = mpsc::channel();
+    let proxy: Proxy = format!("socks5://user:secret@{}", socks5_proxy(hosts))
+        .parse()
+        .unwrap();
+    let port = send_through(proxy, false);
+    assert_eq!(asked.recv().unwrap(), format!("127.0.0.1:{}", port));
+}
+
+#[test]
+fn socks5_proxy_resolves_host_names() {
+    let (hosts, asked) = mpsc::channel();
+    let proxy: Proxy = format!("socks5://user:secret@{}", socks5_proxy(hosts))
+        .parse()
+        .unwrap();
+    let port = send_through(proxy, true);
+    assert_eq!(asked.recv().unwrap(), format!("{}:{}", HOST_NAME, port));
+}
+
+#[test]
+fn tls_goes_through_an_http_proxy() {
+    let (heads, sent) = mpsc::channel();
+    let proxy: Proxy = format!(
+        "http://user:secret@{}",
+        http_proxy(heads, "200 Connection established")
+    )
+    .parse()
+    .unwrap();
+    let port = send_through(proxy, true);
+    let head = sent.recv().unwrap();
+    let target = format!("{}:{}", HOST_NAME, port);
+    assert!(head.starts_with(&format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target)));
+    // base64 of user:secret
+    assert!(head.contains("\r\nProxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+}
+
+#[test]
+fn basic_credentials_of_every_length() {
+    // user:password of 2, 3 and 4 bytes: the last group of 3 bytes is short by 1, full, and
+    // short by 2
+    for (credentials, token) in [("a:", "YTo="), ("ab:", "YWI6"), ("ab:c", "YWI6Yw==")] {
+        let (heads, sent) = mpsc::channel();
+        let addr = http_proxy(heads, "407 Proxy Authentication Required");
+        let proxy: Proxy = format!("http://{}@{}", credentials, addr).parse().unwrap();
+        let target = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let err = proxy.connect(target).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let authorization = format!("\r\nProxy-Authorization: Basic {}\r\n", token);
+        assert!(
+            sent.recv().unwrap().contains(&authorization),
+            "{}",
+            credentials
+        );
+    }
+}
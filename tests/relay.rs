@@ -0,0 +1,42 @@
+use sendfile_cli::relay::Relay;
+use sendfile_cli::testing::TempDir;
+use sendfile_cli::{Endpoint, Error, Receiver, Sender};
+use std::fs;
+use std::thread;
+
+#[test]
+fn peers_meet_at_the_relay() {
+    let relay = Relay::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let endpoint = Endpoint::Relay(relay.local_addr().unwrap(), String::from("lab-42"));
+    thread::spawn(move || relay.serve());
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("a.txt");
+    fs::write(&path, b"hello").unwrap();
+    let out = dir.path().join("out");
+    fs::create_dir(&out).unwrap();
+
+    let receiver = Receiver::builder()
+        .endpoint(endpoint.clone())
+        .output_dir(&out)
+        .build()
+        .unwrap();
+    let fingerprint = receiver.fingerprint();
+    let receiving = thread::spawn(move || receiver.accept());
+    let sender = Sender::builder()
+        .endpoint(endpoint)
+        .file(&path)
+        .fingerprint(&fingerprint)
+        .build()
+        .unwrap();
+    sender.send().unwrap();
+    receiving.join().unwrap().unwrap();
+    assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"hello");
+}
+
+#[test]
+fn relay_needs_a_fingerprint() {
+    let endpoint = Endpoint::Relay("127.0.0.1:7900".parse().unwrap(), String::from("lab-42"));
+    let res = Sender::builder().endpoint(endpoint).file("a.txt").build();
+    assert!(matches!(res, Err(Error::Config(_))));
+}
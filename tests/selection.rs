@@ -0,0 +1,133 @@
+use sendfile_cli::testing::TempDir;
+use sendfile_cli::{Endpoint, Receiver, Selection, Sender};
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+/// names of the files below `dir`, sorted
+fn file_names(dir: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if path.is_dir() {
+            names.extend(
+                file_names(&path)
+                    .into_iter()
+                    .map(|n| format!("{}/{}", name, n)),
+            );
+        } else {
+            names.push(name);
+        }
+    }
+    names.sort();
+    names
+}
+
+fn send_dir(dir: &Path, selection: Selection) -> Vec<String> {
+    let out = TempDir::new().unwrap();
+    let receiver = Receiver::builder()
+        .endpoint(Endpoint::Tls("127.0.0.1:0".parse().unwrap()))
+        .output_dir(out.path())
+        .build()
+        .unwrap();
+    let endpoint = receiver.endpoint().unwrap();
+    let fingerprint = receiver.fingerprint();
+    let receiving = thread::spawn(move || receiver.accept());
+
+    let sender = Sender::builder()
+        .endpoint(endpoint)
+        .file(dir)
+        .selection(selection)
+        .fingerprint(&fingerprint)
+        .build()
+        .unwrap();
+    sender.send().unwrap();
+    receiving.join().unwrap().unwrap();
+    file_names(out.path())
+}
+
+#[test]
+fn checkout_sent_without_what_git_ignores() {
+    let tmp = TempDir::new().unwrap();
+    let repo = tmp.path().join("repo");
+    for dir in [
+        "src/bin",
+        "target/debug",
+        ".git",
+        "web/node_modules/left-pad",
+    ] {
+        fs::create_dir_all(repo.join(dir)).unwrap();
+    }
+    for file in [
+        "Cargo.toml",
+        "src/lib.rs",
+        "src/bin/main.rs",
+        "src/build.log",
+        "src/keep.log",
+        "target/debug/main",
+        ".git/HEAD",
+        "web/index.js",
+        "web/node_modules/left-pad/index.js",
+    ] {
+        fs::write(repo.join(file), file).unwrap();
+    }
+    fs::write(repo.join(".gitignore"), "/target\n*.log\n").unwrap();
+    fs::write(repo.join("src/.gitignore"), "!keep.log\n").unwrap();
+    fs::write(repo.join("web/.ignore"), "node_modules/\n").unwrap();
+
+    let names = send_dir(&repo, Selection::default().gitignore(true));
+    assert_eq!(
+        names,
+        [
+            "repo/.gitignore",
+            "repo/Cargo.toml",
+            "repo/src/.gitignore",
+            "repo/src/bin/main.rs",
+            "repo/src/keep.log",
+            "repo/src/lib.rs",
+            "repo/web/.ignore",
+            "repo/web/index.js",
+        ]
+    );
+}
+
+#[test]
+fn patterns_depth_and_size_select_files() {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path().join("dir");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    fs::create_dir_all(dir.join("tmp")).unwrap();
+    fs::write(dir.join("top.rs"), b"fn main() {}").unwrap();
+    fs::write(dir.join("empty.rs"), b"").unwrap();
+    fs::write(dir.join("notes.txt"), b"notes").unwrap();
+    fs::write(dir.join("a/one.rs"), b"mod one;").unwrap();
+    fs::write(dir.join("a/b/two.rs"), b"mod two;").unwrap();
+    fs::write(dir.join("tmp/scratch.rs"), b"mod scratch;").unwrap();
+
+    let selection = Selection::default()
+        .include("*.rs")
+        .exclude("tmp/")
+        .max_depth(2)
+        .min_size(1);
+    assert_eq!(send_dir(&dir, selection), ["dir/a/one.rs", "dir/top.rs"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn links_to_directories_are_not_followed() {
+    use std::os::unix::fs::symlink;
+
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path().join("dir");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::create_dir_all(tmp.path().join("outside")).unwrap();
+    fs::write(dir.join("sub/a.txt"), b"a").unwrap();
+    fs::write(tmp.path().join("outside/b.txt"), b"b").unwrap();
+    symlink(dir.join("sub/a.txt"), dir.join("link.txt")).unwrap();
+    symlink(&dir, dir.join("sub/parent")).unwrap();
+    symlink(tmp.path().join("outside"), dir.join("outside")).unwrap();
+
+    let names = send_dir(&dir, Selection::default());
+    assert_eq!(names, ["dir/link.txt", "dir/sub/a.txt"]);
+}
@@ -0,0 +1,633 @@
+use sendfile_cli::testing::{duplex, frame_bytes, ClientOutcome, Fault, Harness, MemoryStream};
+use sendfile_cli::{Error, FileInfo, Sender};
+use std::fmt::Debug;
+use std::fs;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// SHA-256 of `hello`
+const HELLO_HASH: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+/// frames written by the sender of a single file small enough for one FileData
+const SEND: usize = 1;
+const START_FILE: usize = 2;
+
+/// frame written by the receiver after its answer to Hello
+const ACCEPT: usize = 1;
+
+fn error_kind<T: Debug>(res: &Result<T>) -> ErrorKind {
+    res.as_ref().expect_err("should fail").kind()
+}
+
+fn hello(harness: &Harness) -> Vec<PathBuf> {
+    vec![harness.add_file("a.txt", b"hello").unwrap()]
+}
+
+fn is_empty(harness: &Harness) -> bool {
+    fs::read_dir(harness.output_dir()).unwrap().next().is_none()
+}
+
+#[test]
+fn transfers_files_and_directories() {
+    let harness = Harness::new().unwrap();
+    let a = harness.add_file("a.txt", b"hello").unwrap();
+    let big = vec![7_u8; 200_000];
+    harness.add_file("dir/big.bin", &big).unwrap();
+    harness.add_file("dir/sub/empty", b"").unwrap();
+
+    let transfer = harness.transfer(&[a, harness.source_dir().join("dir")]);
+    assert_eq!(transfer.sent.unwrap(), ClientOutcome::Finished);
+    let received = transfer.received.unwrap();
+    let names: Vec<&str> = received.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["a.txt", "dir/big.bin", "dir/sub/empty"]);
+    assert_eq!(received[0].hash.as_deref(), Some(HELLO_HASH));
+
+    let out = harness.output_dir();
+    assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(out.join("dir/big.bin")).unwrap(), big);
+    assert_eq!(fs::read(out.join("dir/sub/empty")).unwrap(), b"");
+}
+
+#[test]
+fn connection_dropped_in_the_middle_of_a_file() {
+    let harness = Harness::new().unwrap();
+    let path = harness.add_file("big.bin", &vec![1_u8; 200_000]).unwrap();
+
+    let transfer = harness.transfer_with(&[path], Some(Fault::DropAt(100_000)), None);
+    assert!(transfer.sent.is_err());
+    assert_eq!(error_kind(&transfer.received), ErrorKind::UnexpectedEof);
+    assert!(!harness.output_dir().join("big.bin").exists());
+    let part = fs::metadata(harness.output_dir().join("big.bin.part")).unwrap();
+    assert!(part.len() < 100_000);
+}
+
+#[test]
+fn resumed_from_the_part_of_the_same_file_only() {
+    let harness = Harness::new().unwrap();
+    let content = noise(200_000, 3);
+    let path = harness.add_file("big.bin", &content).unwrap();
+    let part = harness.output_dir().join("big.bin.part");
+
+    // left by another transfer, of another file with this name
+    fs::write(&part, vec![9_u8; 100_000]).unwrap();
+    let transfer = harness.transfer_resumed(std::slice::from_ref(&path));
+    assert_eq!(transfer.sent.unwrap(), ClientOutcome::Finished);
+    assert_eq!(
+        fs::read(harness.output_dir().join("big.bin")).unwrap(),
+        content
+    );
+
+    fs::remove_file(harness.output_dir().join("big.bin")).unwrap();
+    let fault = Some(Fault::DropAt(100_000));
+    let transfer = harness.transfer_with(std::slice::from_ref(&path), fault, None);
+    assert!(transfer.received.is_err());
+    assert!(fs::metadata(&part).unwrap().len() > 0);
+    let transfer = harness.transfer_resumed(&[path]);
+    assert_eq!(transfer.sent.unwrap(), ClientOutcome::Finished);
+    assert_eq!(
+        fs::read(harness.output_dir().join("big.bin")).unwrap(),
+        content
+    );
+    assert!(!harness.output_dir().join("big.bin.part.info").exists());
+}
+
+#[test]
+fn truncated_request() {
+    let harness = Harness::new().unwrap();
+    let fault = Fault::TruncateFrame(SEND);
+    let transfer = harness.transfer_with(&hello(&harness), Some(fault), None);
+    assert!(transfer.sent.is_err());
+    assert_eq!(error_kind(&transfer.received), ErrorKind::UnexpectedEof);
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn truncated_start_file() {
+    let harness = Harness::new().unwrap();
+    let fault = Fault::TruncateFrame(START_FILE);
+    let transfer = harness.transfer_with(&hello(&harness), Some(fault), None);
+    assert!(transfer.sent.is_err());
+    assert_eq!(error_kind(&transfer.received), ErrorKind::UnexpectedEof);
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn truncated_answer() {
+    let harness = Harness::new().unwrap();
+    let fault = Fault::TruncateFrame(ACCEPT);
+    let transfer = harness.transfer_with(&hello(&harness), None, Some(fault));
+    assert_eq!(error_kind(&transfer.sent), ErrorKind::UnexpectedEof);
+    assert!(transfer.received.is_err());
+}
+
+#[test]
+fn malformed_request() {
+    let harness = Harness::new().unwrap();
+    let fault = Fault::ReplaceBody(SEND, b"[{not json}]".to_vec());
+    let transfer = harness.transfer_with(&hello(&harness), Some(fault), None);
+    assert!(transfer.sent.is_err());
+    assert_eq!(error_kind(&transfer.received), ErrorKind::InvalidData);
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn malformed_start_file() {
+    let harness = Harness::new().unwrap();
+    let fault = Fault::ReplaceBody(START_FILE, br#"{"index":"zero"}"#.to_vec());
+    // all its frames written, the sender still waits for the answer to its Finish
+    let transfer = harness.transfer_with(&hello(&harness), Some(fault), None);
+    assert!(transfer.sent.is_err());
+    assert_eq!(error_kind(&transfer.received), ErrorKind::InvalidData);
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn unknown_action() {
+    let harness = Harness::new().unwrap();
+    let (mut peer, end) = duplex();
+    peer.write_all(&frame_bytes(200, b"")).unwrap();
+    drop(peer);
+    let received = harness.receive(end);
+    assert_eq!(error_kind(&received), ErrorKind::InvalidData);
+    assert!(matches!(Error::from(received.unwrap_err()), Error::Io(_)));
+}
+
+/// send a request for a file of 10 bytes, then `data` as its content
+fn receive_ten_bytes(harness: &Harness, data: &[u8]) -> Result<Vec<FileInfo>> {
+    let (mut peer, end) = duplex();
+    thread::scope(|scope| {
+        let receiving = scope.spawn(|| harness.receive(end));
+
+        let file = r#"{"name":"a.txt","size":10}"#;
+        let request = format!("[{}]", file);
+        peer.write_all(&frame_bytes(0, request.as_bytes())).unwrap();
+        let mut answer = [0_u8; 3];
+        peer.read_exact(&mut answer).unwrap();
+        assert_eq!(answer, [1, 0, 0], "accept");
+        let start = format!(r#"{{"file_info":{},"index":0,"total":1}}"#, file);
+        peer.write_all(&frame_bytes(3, start.as_bytes())).unwrap();
+        // the receiver may hang up before the end
+        let _ = peer.write_all(&frame_bytes(4, data));
+        let _ = peer.write_all(&frame_bytes(5, b""));
+        receiving.join().unwrap()
+    })
+}
+
+#[test]
+fn fewer_bytes_than_announced() {
+    let harness = Harness::new().unwrap();
+    let received = receive_ten_bytes(&harness, b"abc");
+    assert_eq!(error_kind(&received), ErrorKind::InvalidData);
+    assert!(matches!(
+        Error::from(received.unwrap_err()),
+        Error::Integrity(_)
+    ));
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn sender_learns_about_integrity_failures() {
+    let harness = Harness::new().unwrap();
+    let paths = hello(&harness);
+    // one byte short of the 5 announced
+    let fault = Fault::ReplaceBody(START_FILE + 1, b"hell".to_vec());
+    let transfer = harness.transfer_with(&paths, Some(fault), None);
+    assert!(matches!(
+        Error::from(transfer.received.unwrap_err()),
+        Error::Integrity(_)
+    ));
+    assert!(matches!(
+        Error::from(transfer.sent.unwrap_err()),
+        Error::Integrity(_)
+    ));
+}
+
+#[test]
+fn more_bytes_than_announced() {
+    let harness = Harness::new().unwrap();
+    let received = receive_ten_bytes(&harness, &[0_u8; 1000]);
+    assert_eq!(error_kind(&received), ErrorKind::InvalidData);
+    let part = harness.output_dir().join("a.txt.part");
+    assert_eq!(fs::metadata(part).unwrap().len(), 0);
+    assert!(!harness.output_dir().join("a.txt").exists());
+}
+
+#[test]
+fn start_of_a_file_not_requested() {
+    let harness = Harness::new().unwrap();
+    let (mut peer, end) = duplex();
+    thread::scope(|scope| {
+        let receiving = scope.spawn(|| harness.receive(end));
+
+        let request = r#"[{"name":"a.txt","size":3}]"#;
+        peer.write_all(&frame_bytes(0, request.as_bytes())).unwrap();
+        let mut answer = [0_u8; 3];
+        peer.read_exact(&mut answer).unwrap();
+        let start = r#"{"file_info":{"name":"b.txt","size":3},"index":0,"total":1}"#;
+        peer.write_all(&frame_bytes(3, start.as_bytes())).unwrap();
+
+        assert_eq!(
+            error_kind(&receiving.join().unwrap()),
+            ErrorKind::InvalidData
+        );
+    });
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn offset_not_offered_is_refused() {
+    let harness = Harness::new().unwrap();
+    // a part of another version of the file, which ResumeAt does not offer
+    let part = harness.output_dir().join("a.txt.part");
+    fs::write(&part, b"abc").unwrap();
+    let (mut peer, end) = duplex();
+    thread::scope(|scope| {
+        let receiving = scope.spawn(|| harness.receive(end));
+
+        let file = r#"{"name":"a.txt","size":5,"mtime":1}"#;
+        let request = format!("[{}]", file);
+        peer.write_all(&frame_bytes(7, request.as_bytes())).unwrap();
+        let mut answer = [0_u8; 9];
+        peer.read_exact(&mut answer).unwrap();
+        assert_eq!(answer.to_vec(), frame_bytes(8, b"[null]"), "resume at");
+        let start = format!(r#"{{"file_info":{},"index":0,"total":1,"offset":3}}"#, file);
+        peer.write_all(&frame_bytes(3, start.as_bytes())).unwrap();
+        let _ = peer.write_all(&frame_bytes(4, b"lo"));
+        let _ = peer.write_all(&frame_bytes(5, b""));
+        drop(peer);
+
+        assert_eq!(
+            error_kind(&receiving.join().unwrap()),
+            ErrorKind::InvalidData
+        );
+    });
+    assert_eq!(fs::read(&part).unwrap(), b"abc");
+    assert!(!harness.output_dir().join("a.txt").exists());
+}
+
+/// start a sync announcing `manifest`, with an empty output directory on the other side
+fn start_sync(peer: &mut MemoryStream, manifest: &str) {
+    let request = format!(r#"{{"files":{},"policy":"newer"}}"#, manifest);
+    peer.write_all(&frame_bytes(12, request.as_bytes()))
+        .unwrap();
+    let mut listing = [0_u8; 5];
+    peer.read_exact(&mut listing).unwrap();
+    assert_eq!(&listing, b"\x0a\x02\x00[]", "empty listing");
+}
+
+#[test]
+fn sync_refuses_files_not_planned() {
+    let harness = Harness::new().unwrap();
+    let (mut peer, end) = duplex();
+    thread::scope(|scope| {
+        let syncing = scope.spawn(|| harness.sync(end));
+
+        start_sync(&mut peer, "[]");
+        let request = r#"[{"name":"a.txt","size":5}]"#;
+        peer.write_all(&frame_bytes(0, request.as_bytes())).unwrap();
+        let mut answer = [0_u8; 3];
+        peer.read_exact(&mut answer).unwrap();
+        assert_eq!(answer, [2, 0, 0], "reject");
+
+        let synced = syncing.join().unwrap();
+        assert_eq!(error_kind(&synced), ErrorKind::PermissionDenied);
+    });
+    assert!(is_empty(&harness));
+}
+
+#[test]
+fn sync_checks_the_hash_of_the_manifest() {
+    let harness = Harness::new().unwrap();
+    let (mut peer, end) = duplex();
+    thread::scope(|scope| {
+        let syncing = scope.spawn(|| harness.sync(end));
+
+        let file = format!(r#"{{"name":"a.txt","size":5,"hash":"{}"}}"#, HELLO_HASH);
+        start_sync(&mut peer, &format!("[{}]", file));
+        peer.write_all(&frame_bytes(0, format!("[{}]", file).as_bytes()))
+            .unwrap();
+        let mut answer = [0_u8; 3];
+        peer.read_exact(&mut answer).unwrap();
+        assert_eq!(answer, [1, 0, 0], "accept");
+        let start = format!(r#"{{"file_info":{},"index":0,"total":1}}"#, file);
+        peer.write_all(&frame_bytes(3, start.as_bytes())).unwrap();
+        peer.write_all(&frame_bytes(4, b"jello")).unwrap();
+        peer.write_all(&frame_bytes(5, b"")).unwrap();
+
+        let synced = syncing.join().unwrap();
+        assert!(matches!(
+            Error::from(synced.unwrap_err()),
+            Error::Integrity(_)
+        ));
+    });
+    assert!(!harness.output_dir().join("a.txt").exists());
+}
+
+#[test]
+fn manifest_too_large_for_a_packet() {
+    let harness = Harness::new().unwrap();
+    // far more than the 64 KiB of a JSON packet
+    for i in 0..2000 {
+        harness
+            .add_file(
+                &format!("dir/a-file-with-a-rather-long-name-{:04}.txt", i),
+                b"x",
+            )
+            .unwrap();
+    }
+    let dir = harness.source_dir().join("dir");
+    let (peer, end) = duplex();
+    let (done, sent) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(|| done.send(harness.send_legacy(end, &[dir])).unwrap());
+        // the receiver is still connected, waiting for a request
+        let sent = sent
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the sender should return");
+        assert_eq!(error_kind(&sent), ErrorKind::InvalidInput);
+        drop(peer);
+    });
+}
+
+#[test]
+fn large_manifest() {
+    let harness = Harness::new().unwrap();
+    for i in 0..5000 {
+        harness
+            .add_file(&format!("dir/sub/file-{:04}.txt", i), b"x")
+            .unwrap();
+    }
+    let transfer = harness.transfer(&[harness.source_dir().join("dir")]);
+    assert_eq!(transfer.sent.unwrap(), ClientOutcome::Finished);
+    assert_eq!(transfer.received.unwrap().len(), 5000);
+    assert!(harness.output_dir().join("dir/sub/file-4999.txt").exists());
+}
+
+#[test]
+fn sender_without_hello() {
+    let harness = Harness::new().unwrap();
+    let paths = hello(&harness);
+    let (sender_end, receiver_end) = duplex();
+    thread::scope(|scope| {
+        let receiving = scope.spawn(|| harness.receive(receiver_end));
+        let sent = harness.send_legacy(sender_end, &paths);
+        assert_eq!(sent.unwrap(), ClientOutcome::Finished);
+        assert_eq!(
+            receiving.join().unwrap().unwrap()[0].hash.as_deref(),
+            Some(HELLO_HASH)
+        );
+    });
+    assert_eq!(
+        fs::read(harness.output_dir().join("a.txt")).unwrap(),
+        b"hello"
+    );
+}
+
+#[test]
+fn receiver_without_hello() {
+    let harness = Harness::new().unwrap();
+    let paths = hello(&harness);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::scope(|scope| {
+        let receiving = scope.spawn(|| {
+            // the sender offers Hello, then asks again in JSON once the receiver hung up
+            let refused = harness.receive_legacy(listener.accept()?.0);
+            assert_eq!(error_kind(&refused), ErrorKind::InvalidData);
+            harness.receive_legacy(listener.accept()?.0)
+        });
+        let report = Sender::builder()
+            .endpoint(format!("tcp://{}", addr).parse().unwrap())
+            .files(&paths)
+            .build()
+            .unwrap()
+            .send()
+            .unwrap();
+        assert_eq!(report.attempts, 1);
+        assert_eq!(receiving.join().unwrap().unwrap().len(), 1);
+    });
+    assert_eq!(
+        fs::read(harness.output_dir().join("a.txt")).unwrap(),
+        b"hello"
+    );
+}
+
+#[test]
+fn files_already_received_are_skipped() {
+    let harness = Harness::new().unwrap();
+    let paths = hello(&harness);
+    harness.transfer(&paths).sent.unwrap();
+
+    let transfer = harness.transfer(&paths);
+    assert_eq!(transfer.sent.unwrap(), ClientOutcome::Finished);
+    assert!(transfer.received.unwrap().is_empty());
+    assert_eq!(
+        fs::read(harness.output_dir().join("a.txt")).unwrap(),
+        b"hello"
+    );
+}
+
+#[test]
+fn changed_files_are_sent_again() {
+    let harness = Harness::new().unwrap();
+    let mut paths = hello(&harness);
+    paths.push(harness.add_file("b.txt", b"b").unwrap());
+    harness.transfer(&paths).sent.unwrap();
+
+    harness.add_file("a.txt", b"hello, world").unwrap();
+    let transfer = harness.transfer(&paths);
+    assert_eq!(transfer.sent.unwrap(), ClientOutcome::Finished);
+    let received = transfer.received.unwrap();
+    let names: Vec<&str> = received.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["a.txt"]);
+    assert_eq!(
+        fs::read(harness.output_dir().join("a.txt")).unwrap(),
+        b"hello, world"
+    );
+}
+
+/// counts the bytes written to the stream
+struct Counting<S> {
+    inner: S,
+    written: usize,
+}
+
+impl<S: Read> Read for Counting<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Counting<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// send `paths` to the receiver of `harness`, returns the bytes the sender wrote
+fn send_counting(harness: &Harness, paths: &[PathBuf]) -> usize {
+    let (sender_end, receiver_end) = duplex();
+    let mut counting = Counting {
+        inner: sender_end,
+        written: 0,
+    };
+    thread::scope(|scope| {
+        let receiving = scope.spawn(|| harness.receive(receiver_end));
+        assert_eq!(
+            harness.send(&mut counting, paths).unwrap(),
+            ClientOutcome::Finished
+        );
+        assert_eq!(receiving.join().unwrap().unwrap().len(), paths.len());
+    });
+    counting.written
+}
+
+/// bytes that do not repeat, so that blocks are only found where they were
+fn noise(len: usize, seed: u32) -> Vec<u8> {
+    let mut x = seed;
+    (0..len)
+        .map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (x >> 16) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn modified_file_is_sent_as_a_delta() {
+    let harness = Harness::new().unwrap();
+    let mut content = noise(1 << 20, 1);
+    let paths = vec![harness.add_file("image.bin", &content).unwrap()];
+    harness.transfer(&paths).sent.unwrap();
+
+    // a few bytes changed, some inserted and the end cut
+    content[1000..1010].copy_from_slice(b"0123456789");
+    content.splice(500_000..500_000, b"inserted".iter().copied());
+    content.truncate(900_000);
+    harness.add_file("image.bin", &content).unwrap();
+
+    let written = send_counting(&harness, &paths);
+    assert!(written < 50_000, "{} bytes sent", written);
+    assert_eq!(
+        fs::read(harness.output_dir().join("image.bin")).unwrap(),
+        content
+    );
+}
+
+#[test]
+fn shared_chunks_are_not_sent_again() {
+    let harness = Harness::with_dedup().unwrap();
+    let layer = noise(1 << 20, 1);
+    let first = [noise(100_000, 2), layer.clone()].concat();
+    let paths = vec![harness.add_file("first.bin", &first).unwrap()];
+    harness.transfer(&paths).sent.unwrap();
+
+    // another file with the same section at another offset
+    let second = [noise(30_000, 3), layer, noise(200_000, 4)].concat();
+    let paths = vec![harness.add_file("second.bin", &second).unwrap()];
+    let written = send_counting(&harness, &paths);
+    assert!(written < 600_000, "{} bytes sent", written);
+    assert_eq!(
+        fs::read(harness.output_dir().join("second.bin")).unwrap(),
+        second
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn holes_are_not_sent() {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::MetadataExt;
+
+    let harness = Harness::new().unwrap();
+    let path = harness.add_file("disk.img", b"").unwrap();
+    let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(8 << 20)).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.set_len(32 << 20).unwrap();
+    drop(file);
+
+    let written = send_counting(&harness, std::slice::from_ref(&path));
+    assert!(written < 100_000, "{} bytes sent", written);
+    let received = harness.output_dir().join("disk.img");
+    assert_eq!(fs::read(&received).unwrap(), fs::read(&path).unwrap());
+    let meta = fs::metadata(&received).unwrap();
+    assert!(meta.blocks() * 512 < 1 << 20, "{} blocks", meta.blocks());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn files_copied_by_the_kernel() {
+    use sendfile_cli::{Event, Observer};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+
+    let harness = Harness::new().unwrap();
+    let big = noise(500_000, 5);
+    let paths = vec![
+        harness.add_file("a.txt", b"hello").unwrap(),
+        harness.add_file("big.bin", &big).unwrap(),
+        harness.add_file("empty", b"").unwrap(),
+    ];
+    let (sender_end, receiver_end) = UnixStream::pair().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let observer: Arc<dyn Observer> = Arc::new(move |event: &Event| {
+        if let Event::FileCompleted { file, .. } = event {
+            tx.lock().unwrap().send(file.hash.clone()).unwrap();
+        }
+    });
+    let received = thread::scope(|scope| {
+        let receiving = scope.spawn(|| harness.receive(receiver_end));
+        let sent = harness.send_zero_copy(sender_end, &paths, Some(observer));
+        assert_eq!(sent.unwrap(), ClientOutcome::Finished);
+        receiving.join().unwrap().unwrap()
+    });
+    assert_eq!(received[0].hash.as_deref(), Some(HELLO_HASH));
+    // the sender hashes the data the kernel copied
+    let hashes: Vec<Option<String>> = rx.try_iter().collect();
+    assert_eq!(hashes.len(), 3);
+    assert_eq!(hashes[0].as_deref(), Some(HELLO_HASH));
+    for (hash, file) in hashes.iter().zip(&received) {
+        assert!(hash.is_some());
+        assert_eq!(hash, &file.hash);
+    }
+    let out = harness.output_dir();
+    assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(out.join("big.bin")).unwrap(), big);
+    assert_eq!(fs::read(out.join("empty")).unwrap(), b"");
+}
+
+#[test]
+fn listing_too_large_for_json_is_rejected() {
+    const LIST: u8 = 9;
+    const REJECT: u8 = 2;
+    const FINISH: u8 = 6;
+
+    let harness = Harness::new().unwrap();
+    let long = "n".repeat(200);
+    for i in 0..400 {
+        harness.add_file(&format!("{}{}", long, i), b"x").unwrap();
+    }
+    let (mut client, server) = duplex();
+    thread::scope(|scope| {
+        let serving = scope.spawn(|| harness.share(server));
+        // a client of version 1, the listing does not fit in a frame
+        client.write_all(&frame_bytes(LIST, b"\"\"")).unwrap();
+        let mut header = [0_u8; 3];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header, [REJECT, 0, 0]);
+        client.write_all(&frame_bytes(FINISH, b"")).unwrap();
+        serving.join().unwrap().unwrap();
+    });
+}